@@ -0,0 +1,207 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde_with::chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_CACHE_TTL: u64 = 300;
+const RETRY_BASE_SECONDS: u64 = 5;
+const RETRY_MAX_SECONDS: u64 = 300;
+
+/// Redis connection once established, or when the next attempt is due after failed ones
+struct RedisState {
+  connection: Option<ConnectionManager>,
+  connecting: bool,
+  failures: u32,
+  retry_at: Option<Instant>,
+}
+
+static REDIS_STATE: Mutex<RedisState> = Mutex::new(RedisState { connection: None, connecting: false, failures: 0, retry_at: None });
+
+/// Caching is only enabled when REDIS_URL is set
+fn get_redis_url() -> Option<String> {
+  dotenv::var("REDIS_URL").ok().filter(|url| !url.trim().is_empty())
+}
+
+fn cache_ttl() -> u64 {
+  dotenv::var("CACHE_TTL")
+    .ok()
+    .and_then(|ttl| ttl.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_CACHE_TTL)
+}
+
+async fn init_connection() -> Option<ConnectionManager> {
+  let url = get_redis_url()?;
+  match redis::Client::open(url) {
+    Ok(client) => match ConnectionManager::new(client).await {
+      Ok(manager) => Some(manager),
+      Err(e) => {
+        println!("Redis cache unavailable: {}", e);
+        None
+      }
+    },
+    Err(e) => {
+      println!("Invalid REDIS_URL: {}", e);
+      None
+    }
+  }
+}
+
+/// Delay before reconnecting after consecutive failures, doubling up to a maximum
+fn retry_delay(failures: u32) -> Duration {
+  let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+  Duration::from_secs(RETRY_BASE_SECONDS.saturating_mul(factor).min(RETRY_MAX_SECONDS))
+}
+
+/// Only successful connections are kept, so caching resumes once Redis is reachable again.
+/// The connection manager reconnects by itself after it has been established.
+/// The lock is never held while connecting: requests made during an attempt skip the cache rather than wait for it.
+async fn get_connection() -> Option<ConnectionManager> {
+  get_redis_url()?;
+  {
+    let mut state = REDIS_STATE.lock().ok()?;
+    if let Some(conn) = &state.connection {
+      return Some(conn.clone());
+    }
+    if state.connecting || state.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+      return None;
+    }
+    state.connecting = true;
+  }
+  // spawned so the outcome is recorded even if the request is dropped meanwhile
+  tokio::spawn(connect()).await.ok().flatten()
+}
+
+async fn connect() -> Option<ConnectionManager> {
+  let connection = init_connection().await;
+  let mut state = REDIS_STATE.lock().ok()?;
+  state.connecting = false;
+  match connection {
+    Some(conn) => {
+      state.connection = Some(conn.clone());
+      state.failures = 0;
+      state.retry_at = None;
+      Some(conn)
+    },
+    None => {
+      state.failures += 1;
+      state.retry_at = Some(Instant::now() + retry_delay(state.failures));
+      None
+    }
+  }
+}
+
+fn version_key(dataset_id: &str) -> String {
+  format!("sheetapi:dataset:{}:version", dataset_id)
+}
+
+/// Current cache version of a dataset. Bumping the version orphans all cached responses, which then expire via their TTL.
+async fn dataset_version(conn: &mut ConnectionManager, dataset_id: &str) -> u64 {
+  conn.get::<_, Option<u64>>(version_key(dataset_id)).await.ok().flatten().unwrap_or(0)
+}
+
+/// Build a cache key for a dataset response from its current version and normalized query parameters
+pub async fn dataset_cache_key(dataset_id: &str, params_key: &str) -> Option<String> {
+  let mut conn = get_connection().await?;
+  let version = dataset_version(&mut conn, dataset_id).await;
+  Some(format!("sheetapi:dataset:{}:v{}:{}", dataset_id, version, params_key))
+}
+
+pub async fn get_cached(key: &str) -> Option<String> {
+  let mut conn = get_connection().await?;
+  conn.get::<_, Option<String>>(key).await.ok().flatten()
+}
+
+pub async fn set_cached(key: &str, value: &str) -> bool {
+  if let Some(mut conn) = get_connection().await {
+    return conn.set_ex::<_, _, ()>(key, value, cache_ttl()).await.is_ok();
+  }
+  false
+}
+
+/// Invalidate all cached responses for a dataset after its rows or schema change
+pub async fn invalidate_dataset(dataset_id: &str) -> bool {
+  if let Some(mut conn) = get_connection().await {
+    return conn.incr::<_, _, u64>(version_key(dataset_id), 1).await.is_ok();
+  }
+  false
+}
+
+/// Weak ETag derived from the resource, its last modification time and the normalized query.
+/// A SHA-256 digest keeps ETags stable across builds and between instances behind a load balancer.
+pub fn build_etag(resource: &str, modified: &DateTime<Utc>, params_key: &str) -> String {
+  let mut hasher = Sha256::new();
+  for part in [resource, &modified.timestamp_millis().to_string(), params_key] {
+    hasher.update(part.as_bytes());
+    hasher.update([0u8]);
+  }
+  let digest = hasher.finalize();
+  let hex = digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+  format!("W/\"{}\"", hex)
+}
+
+fn to_http_date(dt: &DateTime<Utc>) -> String {
+  dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// ETag and Last-Modified response headers
+pub fn validator_headers(etag: &str, modified: &DateTime<Utc>) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+  if let Ok(value) = HeaderValue::from_str(etag) {
+    headers.insert(header::ETAG, value);
+  }
+  if let Ok(value) = HeaderValue::from_str(&to_http_date(modified)) {
+    headers.insert(header::LAST_MODIFIED, value);
+  }
+  headers
+}
+
+/// Check If-None-Match, or If-Modified-Since when no ETags are sent, against the current validators
+pub fn is_not_modified(request_headers: &HeaderMap, etag: &str, modified: &DateTime<Utc>) -> bool {
+  if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+    let current = etag.trim_start_matches("W/");
+    return if_none_match
+      .split(',')
+      .map(|tag| tag.trim())
+      .any(|tag| tag == "*" || tag.trim_start_matches("W/") == current);
+  }
+  if let Some(since) = request_headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()) {
+    if let Ok(since_dt) = DateTime::parse_from_rfc2822(since) {
+      // HTTP dates have a resolution of one second
+      return modified.timestamp() <= since_dt.timestamp();
+    }
+  }
+  false
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_with::chrono::TimeZone;
+
+  #[test]
+  fn test_conditional_get_validators() {
+    let modified = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 15).unwrap();
+    let etag = build_etag("abc", &modified, "start=0&limit=100");
+    // the same on every build
+    assert_eq!(etag, "W/\"cc904ba04b79abbcb4e0f10e1fa28a3d\"");
+    assert_ne!(etag, build_etag("abc", &modified, "start=100&limit=100"));
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&etag.replace("W/", "")).unwrap());
+    assert!(is_not_modified(&headers, &etag, &modified));
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Wed, 01 May 2024 12:30:15 GMT"));
+    assert!(is_not_modified(&headers, &etag, &modified));
+    let later = Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 16).unwrap();
+    assert!(!is_not_modified(&headers, &etag, &later));
+    assert_eq!(to_http_date(&modified), "Wed, 01 May 2024 12:30:15 GMT");
+  }
+
+  #[test]
+  fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(5));
+    assert_eq!(retry_delay(3), Duration::from_secs(20));
+    assert_eq!(retry_delay(40), Duration::from_secs(300));
+  }
+}
@@ -0,0 +1,263 @@
+use arrow::array::{ArrayRef, BooleanArray, Date32Array, Float64Array, Int64Array, StringArray, TimestampMillisecondArray};
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use arrow::ipc::reader::{read_footer_length, FileReader};
+use arrow::ipc::{root_as_footer, root_as_message};
+use arrow::ipc::writer::FileWriter;
+use arrow::json::ArrayWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::Value;
+use serde_with::chrono::{DateTime, NaiveDate, NaiveDateTime};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::documents::JsonFile;
+use crate::schema::ColumnSchema;
+
+const READ_BATCH_SIZE: usize = 1024;
+
+/// Binary formats for dataset exports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+  Parquet,
+  Arrow,
+}
+
+impl ExportFormat {
+  pub fn from_key(key: &str) -> Option<Self> {
+    match key.trim().to_lowercase().as_str() {
+      "parquet" => Some(ExportFormat::Parquet),
+      "arrow" | "ipc" | "feather" => Some(ExportFormat::Arrow),
+      _ => None,
+    }
+  }
+
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      ExportFormat::Parquet => "application/vnd.apache.parquet",
+      ExportFormat::Arrow => "application/vnd.apache.arrow.file",
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ExportFormat::Parquet => "parquet",
+      ExportFormat::Arrow => "arrow",
+    }
+  }
+}
+
+pub fn is_columnar_file(file_path: &Path) -> bool {
+  let extension = file_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+  matches!(extension.as_str(), "parquet" | "arrow" | "feather" | "ipc")
+}
+
+fn batches_to_documents(batches: &[RecordBatch]) -> Result<Vec<Value>, String> {
+  let mut writer = ArrayWriter::new(Vec::new());
+  writer.write_batches(&batches.iter().collect::<Vec<&RecordBatch>>()).map_err(|e| e.to_string())?;
+  writer.finish().map_err(|e| e.to_string())?;
+  let bytes = writer.into_inner();
+  if bytes.is_empty() {
+    return Ok(vec![]);
+  }
+  serde_json::from_slice::<Vec<Value>>(&bytes).map_err(|e| e.to_string())
+}
+
+/// Rows of an Arrow IPC file, summed from the record batch headers listed in its footer without reading the batches
+fn count_ipc_rows(file: &mut File) -> Result<usize, String> {
+  let mut trailer = [0u8; 10];
+  file.seek(SeekFrom::End(-10)).and_then(|_| file.read_exact(&mut trailer)).map_err(|e| e.to_string())?;
+  let footer_len = read_footer_length(trailer).map_err(|e| e.to_string())?;
+  let mut footer_bytes = vec![0u8; footer_len];
+  file.seek(SeekFrom::End(-10 - footer_len as i64)).and_then(|_| file.read_exact(&mut footer_bytes)).map_err(|e| e.to_string())?;
+  let footer = root_as_footer(&footer_bytes).map_err(|e| e.to_string())?;
+  let mut num_rows = 0;
+  for block in footer.recordBatches().iter().flat_map(|blocks| blocks.iter()) {
+    let mut meta = vec![0u8; block.metaDataLength().max(0) as usize];
+    file.seek(SeekFrom::Start(block.offset() as u64)).and_then(|_| file.read_exact(&mut meta)).map_err(|e| e.to_string())?;
+    // the message length follows a continuation marker, absent in files written before Arrow 0.15
+    let start = if meta.starts_with(&[0xff; 4]) { 8 } else { 4 };
+    let length = meta.get(start - 4..start).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]).max(0) as usize).unwrap_or(0);
+    let message = meta.get(start..start + length).ok_or("Invalid Arrow IPC message")?;
+    let header = root_as_message(message).map_err(|e| e.to_string())?;
+    num_rows += header.header_as_record_batch().map(|batch| batch.length()).unwrap_or(0) as usize;
+  }
+  Ok(num_rows)
+}
+
+/// Read up to `limit` rows of a Parquet or Arrow IPC file as JSON rows. Struct columns become nested objects.
+pub fn read_columnar_file(file_path: &Path, limit: usize) -> Result<JsonFile, String> {
+  let file = File::open(file_path).map_err(|e| e.to_string())?;
+  let extension = file_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+  let (batches, num_rows) = if extension == "parquet" {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(|e| e.to_string())?;
+    let num_rows = builder.metadata().file_metadata().num_rows() as usize;
+    let reader = builder.with_batch_size(READ_BATCH_SIZE).with_limit(limit).build().map_err(|e| e.to_string())?;
+    (reader.collect::<Result<Vec<RecordBatch>, _>>().map_err(|e| e.to_string())?, num_rows)
+  } else {
+    let mut file = file;
+    let num_rows = count_ipc_rows(&mut file)?;
+    let reader = FileReader::try_new(file, None).map_err(|e| e.to_string())?;
+    // as with Parquet, no more batches are read than needed for the limit
+    let mut batches: Vec<RecordBatch> = vec![];
+    let mut num_read = 0;
+    for batch in reader {
+      if num_read >= limit {
+        break;
+      }
+      let batch = batch.map_err(|e| e.to_string())?;
+      num_read += batch.num_rows();
+      batches.push(batch);
+    }
+    (batches, num_rows)
+  };
+  let documents = batches_to_documents(&batches)?;
+  let file_name = file_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+  Ok(JsonFile::from_documents(&file_name, &extension, documents, num_rows, limit))
+}
+
+fn arrow_type(data_type: &str) -> DataType {
+  match data_type {
+    "int" => DataType::Int64,
+    "float" => DataType::Float64,
+    "bool" => DataType::Boolean,
+    "date" => DataType::Date32,
+    "datetime" => DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+    _ => DataType::Utf8,
+  }
+}
+
+/// Value of a column, which may be a dotted path into nested rows imported from JSON
+fn column_value<'a>(row: &'a Value, key: &str) -> &'a Value {
+  match row.get(key) {
+    Some(value) => value,
+    None => key.split('.').fold(row, |value, segment| &value[segment]),
+  }
+}
+
+fn to_f64(value: &Value) -> Option<f64> {
+  value.as_f64().or(value.as_str().and_then(|s| s.trim().parse::<f64>().ok()))
+}
+
+fn to_days(value: &Value) -> Option<i32> {
+  let text = value.as_str()?;
+  let date = NaiveDate::parse_from_str(text.get(..10)?, "%Y-%m-%d").ok()?;
+  Some((date - NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days() as i32)
+}
+
+fn to_millis(value: &Value) -> Option<i64> {
+  let text = value.as_str()?;
+  DateTime::parse_from_rfc3339(text)
+    .map(|dt| dt.timestamp_millis())
+    .or(NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").map(|dt| dt.and_utc().timestamp_millis()))
+    .ok()
+}
+
+fn build_array(column: &ColumnSchema, rows: &[Value]) -> ArrayRef {
+  let values = rows.iter().map(|row| column_value(row, &column.key));
+  match arrow_type(&column.data_type) {
+    DataType::Int64 => Arc::new(values.map(|v| v.as_i64().or(to_f64(v).map(|n| n as i64))).collect::<Int64Array>()),
+    DataType::Float64 => Arc::new(values.map(to_f64).collect::<Float64Array>()),
+    DataType::Boolean => Arc::new(values.map(|v| v.as_bool().or(v.as_str().and_then(|s| s.parse::<bool>().ok()))).collect::<BooleanArray>()),
+    DataType::Date32 => Arc::new(values.map(to_days).collect::<Date32Array>()),
+    DataType::Timestamp(_, _) => Arc::new(values.map(to_millis).collect::<TimestampMillisecondArray>().with_timezone("UTC")),
+    _ => Arc::new(values.map(|v| match v {
+      Value::Null => None,
+      Value::String(s) => Some(s.to_owned()),
+      other => Some(other.to_string()),
+    }).collect::<StringArray>()),
+  }
+}
+
+/// Record batch of dataset rows with column types from the stored schema.
+/// Values that cannot be converted to the column type are written as nulls.
+pub fn rows_to_batch(schema: &[ColumnSchema], rows: &[Value]) -> Result<RecordBatch, String> {
+  let fields = schema.iter().map(|column| Field::new(&column.key, arrow_type(&column.data_type), true)).collect::<Vec<Field>>();
+  let columns = schema.iter().map(|column| build_array(column, rows)).collect::<Vec<ArrayRef>>();
+  RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).map_err(|e| e.to_string())
+}
+
+pub fn export_rows(format: ExportFormat, schema: &[ColumnSchema], rows: &[Value]) -> Result<Vec<u8>, String> {
+  let batch = rows_to_batch(schema, rows)?;
+  let mut buffer: Vec<u8> = vec![];
+  match format {
+    ExportFormat::Parquet => {
+      let props = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+      let mut writer = ArrowWriter::try_new(&mut buffer, batch.schema(), Some(props)).map_err(|e| e.to_string())?;
+      writer.write(&batch).map_err(|e| e.to_string())?;
+      writer.close().map_err(|e| e.to_string())?;
+    }
+    ExportFormat::Arrow => {
+      let mut writer = FileWriter::try_new(&mut buffer, &batch.schema()).map_err(|e| e.to_string())?;
+      writer.write(&batch).map_err(|e| e.to_string())?;
+      writer.finish().map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(buffer)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::options::CastDataType;
+  use serde_json::json;
+
+  #[test]
+  fn test_export_and_import_parquet() {
+    let schema = vec![
+      ColumnSchema::new("sku", &CastDataType::String),
+      ColumnSchema::new("qty", &CastDataType::Integer),
+      ColumnSchema::new("price", &CastDataType::Float),
+      ColumnSchema::new("added", &CastDataType::Date),
+      ColumnSchema::new("address.city", &CastDataType::String),
+    ];
+    let rows = vec![
+      json!({ "sku": "A1", "qty": 3, "price": 2.5, "added": "2024-05-01T00:00:00.000Z", "address": { "city": "Leeds" } }),
+      json!({ "sku": "B2", "qty": "n/a", "price": 4, "added": null }),
+    ];
+    let batch = rows_to_batch(&schema, &rows).unwrap();
+    assert_eq!(batch.schema().field(1).data_type(), &DataType::Int64);
+    assert_eq!(batch.schema().field(3).data_type(), &DataType::Date32);
+    let bytes = export_rows(ExportFormat::Parquet, &schema, &rows).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stock.parquet");
+    std::fs::write(&path, bytes).unwrap();
+    let file = read_columnar_file(&path, 10).unwrap();
+    assert_eq!(file.result["num_rows"], json!(2));
+    assert_eq!(file.documents[0], json!({ "sku": "A1", "qty": 3, "price": 2.5, "added": "2024-05-01", "address.city": "Leeds" }));
+    // values that do not match the column type are nulls
+    assert_eq!(file.documents[1], json!({ "sku": "B2", "price": 4.0 }));
+    let arrow_bytes = export_rows(ExportFormat::Arrow, &schema, &rows).unwrap();
+    let arrow_path = dir.path().join("stock.arrow");
+    std::fs::write(&arrow_path, arrow_bytes).unwrap();
+    assert_eq!(read_columnar_file(&arrow_path, 10).unwrap().documents.len(), 2);
+  }
+
+  #[test]
+  fn test_arrow_preview_limit() {
+    let schema = vec![ColumnSchema::new("id", &CastDataType::Integer)];
+    let mut buffer: Vec<u8> = vec![];
+    let mut writer = FileWriter::try_new(&mut buffer, &rows_to_batch(&schema, &[]).unwrap().schema()).unwrap();
+    for start in 0..3 {
+      let rows = (start * 4..start * 4 + 4).map(|id| json!({ "id": id })).collect::<Vec<Value>>();
+      writer.write(&rows_to_batch(&schema, &rows).unwrap()).unwrap();
+    }
+    writer.finish().unwrap();
+    drop(writer);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ids.arrow");
+    std::fs::write(&path, buffer).unwrap();
+    let mut file = File::open(&path).unwrap();
+    assert_eq!(count_ipc_rows(&mut file).unwrap(), 12);
+    // the total comes from the footer while only the first batches are read
+    let preview = read_columnar_file(&path, 5).unwrap();
+    assert_eq!(preview.result["num_rows"], json!(12));
+    assert_eq!(preview.documents.len(), 5);
+    assert_eq!(preview.documents[4], json!({ "id": 4 }));
+  }
+}
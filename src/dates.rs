@@ -0,0 +1,252 @@
+use bson::{Bson, Document};
+use chrono_tz::Tz;
+use serde_with::chrono::{self, DateTime, Duration, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use std::str::FromStr;
+
+use crate::options::CastDataType;
+use crate::schema::ColumnSchema;
+
+// Excel's day zero, allowing for the fictitious 29 February 1900
+const EXCEL_EPOCH: (i32, u32, u32) = (1899, 12, 30);
+
+/// Translate user-friendly patterns such as `dd/mm/yyyy` or `yyyy-mm-dd hh:mm` into chrono format strings.
+/// Patterns already containing `%` tokens and the special `excel` format are returned unchanged.
+pub fn normalize_date_format(format: &str) -> String {
+  let fmt = format.trim();
+  if fmt.contains('%') || fmt.eq_ignore_ascii_case("excel") {
+    return fmt.to_string();
+  }
+  let chars = fmt.chars().collect::<Vec<char>>();
+  let mut output = String::new();
+  let mut index = 0;
+  while index < chars.len() {
+    let ch = chars[index];
+    let mut run = 1;
+    while index + run < chars.len() && chars[index + run].eq_ignore_ascii_case(&ch) {
+      run += 1;
+    }
+    let after_colon = index > 0 && chars[index - 1] == ':';
+    let token = match (ch.to_ascii_lowercase(), run) {
+      ('y', 4) => "%Y".to_string(),
+      ('y', 2) => "%y".to_string(),
+      ('m', 4) => "%B".to_string(),
+      ('m', 3) => "%b".to_string(),
+      ('m', 1..=2) if after_colon => "%M".to_string(),
+      ('m', 1..=2) => "%m".to_string(),
+      ('d', 1..=2) => "%d".to_string(),
+      ('h', 1..=2) => "%H".to_string(),
+      ('s', 1..=2) => "%S".to_string(),
+      _ => chars[index..index + run].iter().collect(),
+    };
+    output.push_str(&token);
+    index += run;
+  }
+  output
+}
+
+/// Parse a fixed timezone offset such as `+02:00`, `-0500`, `UTC` or `Z`
+pub fn parse_timezone_offset(tz: &str) -> Option<FixedOffset> {
+  let tz_str = tz.trim();
+  if tz_str.eq_ignore_ascii_case("utc") || tz_str.eq_ignore_ascii_case("z") || tz_str.eq_ignore_ascii_case("gmt") {
+    return FixedOffset::east_opt(0);
+  }
+  let (sign, rest) = match tz_str.chars().next() {
+    Some('+') => (1, &tz_str[1..]),
+    Some('-') => (-1, &tz_str[1..]),
+    _ => return None,
+  };
+  let digits = rest.replace(':', "");
+  let (hours, minutes) = match digits.len() {
+    1 | 2 => (digits.parse::<i32>().ok()?, 0),
+    4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+    _ => return None,
+  };
+  FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Timezone in which datetimes without one are read: a fixed offset, or an IANA name such as `Europe/London`
+/// whose offset follows daylight saving time on each date
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportTimezone {
+  Fixed(FixedOffset),
+  Named(Tz),
+}
+
+impl Default for ImportTimezone {
+  fn default() -> Self {
+    ImportTimezone::Fixed(FixedOffset::east_opt(0).unwrap())
+  }
+}
+
+impl ImportTimezone {
+  pub fn parse(tz: &str) -> Option<Self> {
+    match parse_timezone_offset(tz) {
+      Some(offset) => Some(ImportTimezone::Fixed(offset)),
+      None => Tz::from_str(tz.trim()).ok().map(ImportTimezone::Named),
+    }
+  }
+
+  /// Local times repeated when clocks go back are read as the first occurrence.
+  /// Those skipped when clocks go forward are read with the offset in force before the change.
+  pub fn to_utc(self, naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+    match self {
+      ImportTimezone::Fixed(offset) => offset.from_local_datetime(naive).single().map(|dt| dt.with_timezone(&Utc)),
+      ImportTimezone::Named(tz) => match tz.from_local_datetime(naive) {
+        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => Some(dt.with_timezone(&Utc)),
+        LocalResult::None => {
+          let before = tz.offset_from_utc_datetime(&(*naive - Duration::days(1)));
+          before.fix().from_local_datetime(naive).single().map(|dt| dt.with_timezone(&Utc))
+        },
+      },
+    }
+  }
+}
+
+/// Convert an Excel serial date (days since 1899-12-30 with the time as a fraction) to a naive datetime
+pub fn excel_serial_to_datetime(serial: f64) -> Option<NaiveDateTime> {
+  if !serial.is_finite() || serial < 0.0 {
+    return None;
+  }
+  let (y, m, d) = EXCEL_EPOCH;
+  let epoch = NaiveDate::from_ymd_opt(y, m, d)?.and_hms_opt(0, 0, 0)?;
+  let millis = (serial * 86_400_000.0).round() as i64;
+  epoch.checked_add_signed(Duration::milliseconds(millis))
+}
+
+fn parse_naive_datetime(text: &str, format: Option<&str>) -> Option<NaiveDateTime> {
+  let trimmed = text.trim();
+  if let Some(fmt) = format {
+    if let Ok(ndt) = NaiveDateTime::parse_from_str(trimmed, fmt) {
+      return Some(ndt);
+    }
+    if let Ok(nd) = NaiveDate::parse_from_str(trimmed, fmt) {
+      return nd.and_hms_opt(0, 0, 0);
+    }
+    return None;
+  }
+  for fmt in ["%Y-%m-%dT%H:%M:%S%.fZ", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M"] {
+    if let Ok(ndt) = NaiveDateTime::parse_from_str(trimmed, fmt) {
+      return Some(ndt);
+    }
+  }
+  if let Ok(nd) = NaiveDate::parse_from_str(trimmed, "%Y-%m-%d") {
+    return nd.and_hms_opt(0, 0, 0);
+  }
+  None
+}
+
+/// Parse a cell value for a date or datetime column.
+/// Pure dates are stored as midnight UTC regardless of the timezone, while datetimes without
+/// an explicit zone are read in the given timezone and converted to UTC.
+pub fn parse_date_value(value: &Bson, date_only: bool, format: Option<&str>, timezone: &ImportTimezone) -> Option<Bson> {
+  let is_excel = format.map(|f| f.eq_ignore_ascii_case("excel")).unwrap_or(false);
+  let naive = match value {
+    Bson::String(text) => {
+      if is_excel {
+        text.trim().parse::<f64>().ok().and_then(excel_serial_to_datetime)
+      } else {
+        parse_naive_datetime(text, format)
+      }
+    },
+    Bson::Double(num) => excel_serial_to_datetime(*num),
+    Bson::Int32(num) => excel_serial_to_datetime(*num as f64),
+    Bson::Int64(num) => excel_serial_to_datetime(*num as f64),
+    _ => None,
+  }?;
+  let utc = if date_only {
+    chrono::Utc.from_utc_datetime(&naive.date().and_hms_opt(0, 0, 0)?)
+  } else {
+    timezone.to_utc(&naive)?
+  };
+  Some(Bson::DateTime(utc.into()))
+}
+
+/// Convert date and datetime columns in a row according to the dataset schema.
+/// ISO datetime strings in other columns are still converted as before.
+pub fn convert_date_values(doc: &mut Document, schema: &[ColumnSchema], timezone: &ImportTimezone) {
+  convert_nested_date_values(doc, "", schema, timezone);
+}
+
+/// Values of nested JSON documents are matched to columns by their dotted key
+fn convert_nested_date_values(doc: &mut Document, prefix: &str, schema: &[ColumnSchema], timezone: &ImportTimezone) {
+  for (key, value) in doc.iter_mut() {
+    let path = if prefix.is_empty() { key.to_owned() } else { format!("{}.{}", prefix, key) };
+    if let Bson::Document(inner) = value {
+      if !inner.is_empty() {
+        convert_nested_date_values(inner, &path, schema, timezone);
+        continue;
+      }
+    }
+    let column = schema.iter().find(|c| c.key == path);
+    let dt = column.map(|c| c.cast_type()).unwrap_or(CastDataType::String);
+    if dt.is_datelike() {
+      let format = column.and_then(|c| c.date_format.clone());
+      if let Some(converted) = parse_date_value(value, !dt.is_datetime(), format.as_deref(), timezone) {
+        *value = converted;
+      }
+    } else if let Bson::String(date_str) = value {
+      if let Ok(naive_datetime) = NaiveDateTime::parse_from_str(date_str, "%Y-%m-%dT%H:%M:%S%.fZ") {
+        let datetime_utc = chrono::Utc.from_utc_datetime(&naive_datetime);
+        *value = Bson::DateTime(datetime_utc.into());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_normalize_date_format() {
+    assert_eq!(normalize_date_format("dd/mm/yyyy"), "%d/%m/%Y");
+    assert_eq!(normalize_date_format("yyyy-mm-dd hh:mm"), "%Y-%m-%d %H:%M");
+    assert_eq!(normalize_date_format("%d.%m.%Y"), "%d.%m.%Y");
+    assert_eq!(normalize_date_format("Excel"), "Excel");
+  }
+
+  #[test]
+  fn test_parse_date_values() {
+    let utc = ImportTimezone::parse("UTC").unwrap();
+    let cet = ImportTimezone::parse("+01:00").unwrap();
+    let dmy = parse_date_value(&Bson::String("25/12/2024".to_string()), true, Some("%d/%m/%Y"), &cet);
+    let expected = chrono::Utc.with_ymd_and_hms(2024, 12, 25, 0, 0, 0).unwrap();
+    assert_eq!(dmy, Some(Bson::DateTime(expected.into())));
+    let serial = parse_date_value(&Bson::Double(45651.5), false, Some("excel"), &utc);
+    let expected = chrono::Utc.with_ymd_and_hms(2024, 12, 25, 12, 0, 0).unwrap();
+    assert_eq!(serial, Some(Bson::DateTime(expected.into())));
+    let local = parse_date_value(&Bson::String("2024-12-25 09:30:00".to_string()), false, None, &cet);
+    let expected = chrono::Utc.with_ymd_and_hms(2024, 12, 25, 8, 30, 0).unwrap();
+    assert_eq!(local, Some(Bson::DateTime(expected.into())));
+    assert_eq!(parse_timezone_offset("-0530").map(|o| o.local_minus_utc()), Some(-19800));
+  }
+
+  #[test]
+  fn test_named_timezones() {
+    let london = ImportTimezone::parse("Europe/London").unwrap();
+    let winter = parse_date_value(&Bson::String("2024-01-15 09:30:00".to_string()), false, None, &london);
+    assert_eq!(winter, Some(Bson::DateTime(chrono::Utc.with_ymd_and_hms(2024, 1, 15, 9, 30, 0).unwrap().into())));
+    // British Summer Time
+    let summer = parse_date_value(&Bson::String("2024-07-15 09:30:00".to_string()), false, None, &london);
+    assert_eq!(summer, Some(Bson::DateTime(chrono::Utc.with_ymd_and_hms(2024, 7, 15, 8, 30, 0).unwrap().into())));
+    // 01:30 does not exist on 31 March 2024 and is read as GMT
+    let skipped = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap().and_hms_opt(1, 30, 0).unwrap();
+    assert_eq!(london.to_utc(&skipped), Some(chrono::Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap()));
+    // 01:30 occurs twice on 27 October 2024 and the first, in BST, is used
+    let repeated = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap().and_hms_opt(1, 30, 0).unwrap();
+    assert_eq!(london.to_utc(&repeated), Some(chrono::Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap()));
+    assert!(ImportTimezone::parse("Europe/Atlantis").is_none());
+  }
+
+  #[test]
+  fn test_convert_nested_date_values() {
+    let utc = ImportTimezone::default();
+    let mut column = ColumnSchema::new("order.placed", &CastDataType::Date);
+    column.date_format = Some("%d/%m/%Y".to_string());
+    let mut row = bson::doc! { "id": 1, "order": { "placed": "25/12/2024", "ref": "A1" } };
+    convert_date_values(&mut row, &[column], &utc);
+    let expected = chrono::Utc.with_ymd_and_hms(2024, 12, 25, 0, 0, 0).unwrap();
+    assert_eq!(row.get_document("order").unwrap().get("placed"), Some(&Bson::DateTime(expected.into())));
+    assert_eq!(row.get_document("order").unwrap().get_str("ref"), Ok("A1"));
+  }
+}
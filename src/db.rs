@@ -6,29 +6,34 @@ use mongodb::{
     Client, Collection,
 };
 use serde_json::Value;
-use serde_with::chrono::{self, NaiveDateTime, TimeZone};
+use serde_with::chrono;
 use spreadsheet_to_json::indexmap::IndexMap;
 use std::str::FromStr;
 use std::vec;
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{Mutex, OnceCell};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::sync::OnceCell;
 
-use crate::options::{DataSetMatcher, ReplaceMode};
+use async_trait::async_trait;
+
+use crate::cache::invalidate_dataset;
+use crate::dates::{convert_date_values, ImportTimezone};
+use crate::imports::{excess_skipped_runs, import_records, live_rows_criteria, retired_collections, skipped_run_record, ImportRecord};
+use crate::indexes::ColumnIndex;
+use crate::schema::{build_schema, ColumnSchema, MigrationOp, SchemaMigration};
+use crate::storage::Storage;
+use crate::workbooks::WorkbookRecord;
 
 const DEFAULT_MONGO_URI: &str = "mongodb://localhost:27017";
 const DEFAULT_MONGO_CONNECTION_TIMEOUT: u64 = 6000;
 const DEFAULT_MONGO_MIN_POOL_SIZE: u32 = 2;
 const DEFAULT_MONGO_MAX_POOL_SIZE: u32 = 64;
+pub const MIGRATION_BATCH_SIZE: i64 = 1000;
+const DEFAULT_DEDICATED_COLLECTION_THRESHOLD: usize = 100_000;
 
+pub const SHARED_ROWS_COLLECTION: &str = "data_rows";
 
-static DB_INSTANCE: OnceCell<DB> = OnceCell::const_new();
-
-pub async fn get_db_instance() -> &'static DB {
-    DB_INSTANCE.get_or_init(DB::new).await
-}
 
 pub struct DatabaseConfig {
     pub uri: String,
@@ -79,6 +84,8 @@ impl DatabaseConfig {
 #[derive(Clone)]
 pub struct DB {
     pub client: Arc<Mutex<Client>>,
+    // whether the deployment supports transactions, detected once
+    pub(crate) transactions: Arc<OnceCell<bool>>,
 }
 
 impl DB {
@@ -92,6 +99,7 @@ impl DB {
         let client = Client::with_options(client_options).unwrap();
         DB {
             client: Arc::new(Mutex::new(client)),
+            transactions: Arc::new(OnceCell::new()),
         }
     }
     
@@ -196,7 +204,7 @@ impl DB {
             let cursor_r = collection.find_one(doc!{ "_id": id }).await;
             if let Ok(doc_opt) = cursor_r {
                 if let Some(dset) = doc_opt {
-                    let mut criteria = doc! { "dataset_id": id };
+                    let mut criteria = live_rows_criteria(&dset);
                     if let Some(import_id) = import_id_opt {
                         if let Ok(imp_id) = ObjectId::from_str(&import_id) {
                             criteria.insert("import_id", imp_id);
@@ -209,7 +217,7 @@ impl DB {
                             }
                         }
                     }                    
-                    let (total,row_docs) = self.find_records_with_total("data_rows", limit, skip, Some(criteria), None, sort_criteria, true).await;
+                    let (total,row_docs) = self.find_records_with_total(&rows_collection_name(&dset), limit, skip, Some(criteria), None, sort_criteria, true).await;
                     let rows = row_docs.iter().filter(|r| r.contains_key("data")).map(|r| r.get("data").unwrap().as_document().unwrap().to_owned()).collect::<Vec<Document>>();
                     return Some(RowSet::new(&dset, &rows, total.unwrap_or(rows.len() as u64), limit, skip));
                 }
@@ -218,132 +226,6 @@ impl DB {
         None
     }
 
-    pub async fn update_record(
-        &self,
-        collection_name: &str,
-        filter_options: &Document,
-        values: &Document,
-        add: Option<(&str, &Document, Option<ObjectId>)>,
-    ) -> (bool, bool, Option<Bson>) {
-        let collection: Collection<Document> = self.get_collection(collection_name).await;
-
-        let cursor_r1 = collection.find_one(filter_options.to_owned()).await;
-        let record: Option<Document> = match cursor_r1 {
-            Ok(doc_opt) => doc_opt,
-            Err(_) => None,
-        };
-        if let Some(doc) = record {
-            let mut set_data = doc! { "options": values.to_owned() };
-            let mut add_to_set = false;
-
-            if let Some((key, doc, oid_opt)) = add {
-                if let Some(oid) = oid_opt {
-                    if let Some(inner_element) = doc.get(key) {
-                        if let Some(inner_vec) = inner_element.as_array() {
-                            let mut inner_items = inner_vec
-                                .iter()
-                                .map(|b| b.as_document().unwrap())
-                                .collect::<Vec<&Document>>();
-                            if let Some(i_index) = inner_vec
-                                .iter()
-                                .position(|x| x.as_object_id().unwrap() == oid)
-                            {
-                                inner_items[i_index] = doc;
-                                set_data.insert(key, inner_items);
-                            }
-                        }
-                    }
-                } else {
-                    add_to_set = true;
-                }
-            }
-            let mut update = doc! { "$set": set_data };
-            if add_to_set {
-                let import_row = doc! {
-                    "_id": ObjectId::new(),
-                    "dt": chrono::Utc::now(),
-                    "filename": "test",
-                    "sheet_index": 0
-                };
-                update.insert("$addToSet", doc! { "imports": import_row });
-            }
-            let cursor_r2 = collection
-                .update_one(filter_options.to_owned(), update)
-                .await;
-            if let Ok(cursor) = cursor_r2 {
-                if let Some(id) = cursor.upserted_id {
-                    return (true, true, Some(id));
-                } else {
-                    if let Some(ups_id) = doc.get("_id") {
-                        return (true, true, Some(ups_id.to_owned()));
-                    }
-                }
-                return (true, false, None);
-            }
-        }
-        (false, false, None)
-    }
-
-    pub async fn insert_record(
-        &self,
-        collection_name: &str,
-        values: &Document,
-    ) -> Option<Document> {
-        let collection: Collection<Document> = self.get_collection(collection_name).await;
-        let cursor_r = collection.insert_one(values.to_owned()).await;
-        if let Ok(cursor) = cursor_r {
-            if let Some(id) = cursor.inserted_id.as_object_id() {
-                let filter = doc! { "_id": id };
-                let row = collection.find_one(filter).await;
-                if let Ok(doc_opt) = row {
-                    return doc_opt;
-                }
-            }
-        }
-        None
-    }
-
-    pub async fn insert_many(
-        &self,
-        collection_name: &str,
-        rows: &[Document],
-        data_pk: Option<String>,
-        delete_key_ref: Option<(&str, ObjectId)>,
-    ) -> Option<HashMap<usize, Bson>> {
-        let collection: Collection<Document> = self.get_collection(collection_name).await;
-        // check each row for the data_pk field and update or insert as required
-        if let Some((fk, fk_id)) = delete_key_ref {
-            let del_result = delete_by_id(collection.clone(), fk, fk_id).await;
-            if let Some(deleted) = del_result {
-                if deleted > 0 {
-                    println!("Deleted {} rows.", deleted);
-                }
-            }
-        }
-        if let Some(pk) = data_pk {
-            let mut results = HashMap::new();
-            let mut counter = 0;
-            for row in rows {
-                let id: Option<Bson> = update_by_inner_id(collection.clone(), &pk, row.to_owned()).await;
-                if let Some(oid) = id {
-                    results.insert(counter, oid);
-                } else {
-                    if let Ok(insert_result) = collection.insert_one(row.to_owned()).await {
-                        results.insert(counter, insert_result.inserted_id);
-                    }
-                }
-                counter += 1;
-            }
-            return Some(results);
-        } else {
-            let cursor_r = collection.insert_many(rows).await;
-            if let Ok(cursor) = cursor_r {
-                return Some(cursor.inserted_ids);
-            }
-        }
-        None
-    }
-
     pub async fn fetch_aggregated_with_options(
         &self,
         collection_name: &str,
@@ -389,171 +271,245 @@ impl DB {
         self.fetch_record("imports", Some(filter)).await
     }
 
-    pub async fn update_import(
-        &self,
-        filter: &Document,
-        values: &mut Document,
-        import_id_opt: Option<ObjectId>,
-    ) -> Option<(ObjectId, ObjectId)> {
-        values.insert("updated_at", chrono::Utc::now());
-        let name = values.get_str("filename").unwrap_or_default();
-        let sheet_index = values.get_i32("sheet_index").unwrap_or(0);
-        let import = doc! {
-            "_id": ObjectId::new(),
-            "dt": chrono::Utc::now(),
-            "filename": name,
-            "sheet_index": sheet_index
-        };
-        let (_updated, _exists, id_opt) = self
-            .update_record(
-                "datasets",
-                &filter,
-                values,
-                Some(("imports", &import, import_id_opt)),
-            )
-            .await;
-        if let Some(id) = id_opt {
-            let oid = id.as_object_id().unwrap();
-            return Some((oid, id.as_object_id().unwrap()));
+    pub async fn fetch_dataset_schema(&self, dataset_id: &str) -> Option<Vec<ColumnSchema>> {
+        let collection: Collection<Document> = self.get_collection("datasets").await;
+        if let Ok(id) = ObjectId::from_str(dataset_id) {
+            if let Ok(Some(dset)) = collection.find_one(doc! { "_id": id }).await {
+                if let Ok(schema_items) = dset.get_array("schema") {
+                    if let Ok(schema) = bson::from_bson::<Vec<ColumnSchema>>(Bson::Array(schema_items.to_owned())) {
+                        return Some(schema);
+                    }
+                }
+                // datasets imported before schemas were persisted: infer from a sample of rows
+                let row_docs = self.find_records(&rows_collection_name(&dset), 100, 0, Some(live_rows_criteria(&dset)), None, None).await;
+                let rows = row_docs.iter().filter_map(|r| r.get_document("data").ok()).map(|d| bson_to_json(&Bson::Document(d.to_owned()))).collect::<Vec<Value>>();
+                return Some(build_schema(&json!({}), &rows, &[]));
+            }
         }
         None
     }
 
-    pub async fn save_rows(
+    /// Rewrite the `data.<field>` keys of all rows of a dataset in batches, then store the new schema and migration history
+    pub async fn migrate_dataset(
         &self,
         dataset_id: ObjectId,
-        import_id: ObjectId,
-        rows: &[Value],
-        data_pk: Option<String>,
-        replace_mode: ReplaceMode
-    ) -> usize {
-        let docs = rows
-            .iter()
-            .map(|row| {
-                let mut row_data = bson::to_document(row).unwrap();
-                convert_datetime_strings(&mut row_data);
-                doc! { "dataset_id": dataset_id, "import_id": import_id, "data": row_data }
-            })
-            .collect::<Vec<Document>>();
-        let delete_key_refs = match replace_mode {
-            ReplaceMode::ReplaceAll => Some(("dataset_id", dataset_id)),
-            ReplaceMode::ReplaceImport => Some(("import_id", import_id)),
-            _ => None,
+        migration: &SchemaMigration,
+        schema: &[ColumnSchema],
+    ) -> Option<u64> {
+        let op = migration.to_op().ok()?;
+        let field_path = format!("data.{}", migration.key);
+        let update: mongodb::options::UpdateModifications = match &op {
+            MigrationOp::Rename(to) => doc! { "$rename": { &field_path: format!("data.{}", to) } }.into(),
+            MigrationOp::Drop => doc! { "$unset": { &field_path: "" } }.into(),
+            MigrationOp::Retype(dt) => {
+                let target = match dt.to_key() {
+                    "int" => "long",
+                    "float" => "double",
+                    "bool" => "bool",
+                    "date" | "datetime" => "date",
+                    _ => "string",
+                };
+                // values that cannot be converted are left unchanged
+                let input = format!("${}", field_path);
+                vec![doc! { "$set": { &field_path: { "$convert": { "input": &input, "to": target, "onError": &input, "onNull": Bson::Null } } } }].into()
+            }
         };
-        if let Some(id) = self.insert_many("data_rows", &docs, data_pk, delete_key_refs).await {
-            return id.len();
-        }
-        0
-    }
-
-    pub async fn save_import(
-        &self,
-        options: &Value,
-        import_id: Option<String>,
-    ) -> Option<(ObjectId, ObjectId)> {
-        let mut options_doc = doc! {};
-        for (key, value) in options.as_object().unwrap() {
-            match key.as_str() {
-                "dataset_id" |  "import_id" | "filename" | "title" | "description" | "user_ref" => continue,
-                _ => {
-                    options_doc.insert(key, bson::to_bson(value).unwrap());
-                }
+        let collection_name = self.dataset_rows_collection(dataset_id).await;
+        let collection: Collection<Document> = self.get_collection(&collection_name).await;
+        let mut num_updated: u64 = 0;
+        let mut last_id: Option<ObjectId> = None;
+        loop {
+            let mut criteria = doc! { "dataset_id": dataset_id, &field_path: { "$exists": true } };
+            if let Some(prev_id) = last_id {
+                criteria.insert("_id", doc! { "$gt": prev_id });
+            }
+            let batch = self.find_records(&collection_name, MIGRATION_BATCH_SIZE as u64, 0, Some(criteria), Some(vec!["_id"]), Some(doc! { "_id": 1 })).await;
+            let ids = batch.iter().filter_map(extra_id_from_doc).collect::<Vec<ObjectId>>();
+            if ids.is_empty() {
+                break;
+            }
+            last_id = ids.last().cloned();
+            if let Ok(result) = collection.update_many(doc! { "_id": { "$in": &ids } }, update.clone()).await {
+                num_updated += result.modified_count;
+            }
+            if (ids.len() as i64) < MIGRATION_BATCH_SIZE {
+                break;
             }
         }
-        let dataset_id_opt = options["dataset_id"].as_str();
-        let fname = options["filename"].as_str().unwrap_or_default().to_owned();
-        let user_ref = options["user_ref"].as_str().unwrap_or_default().to_owned();
-        let title = options["title"].as_str().unwrap_or_default().to_owned();
-        let description = options["description"].as_str().unwrap_or_default().to_owned();
-        let s_index = options["sheet_index"].as_u64().unwrap_or(0) as u32;
-        let matcher = if let Some(dataset_id) = dataset_id_opt {
-            DataSetMatcher::from_id(dataset_id)
-        } else {
-            DataSetMatcher::from_name_index(&fname, s_index)
-        };
-        let import_id_opt = import_id.map(|id| ObjectId::from_str(&id).unwrap());
-
-        let import = doc! {
+        let record = doc! {
             "_id": ObjectId::new(),
             "dt": chrono::Utc::now(),
-            "filename": &fname,
-            "sheet_index": s_index
+            "op": &migration.op,
+            "key": &migration.key,
+            "to": migration.to.clone(),
+            "data_type": migration.data_type.clone(),
+            "rows": num_updated as i64
         };
-        let mut record_doc = doc! {
-            "user_ref": &user_ref,
-            "name": &fname,
-            "title": &title,
-            "description": &description,
-            "sheet_index": s_index,
-            "options": options_doc,
-            "imports": [import],
-            "created_at": chrono::Utc::now()
+        let datasets: Collection<Document> = self.get_collection("datasets").await;
+        let mut set_data = doc! { "schema": bson::to_bson(schema).unwrap_or(Bson::Array(vec![])), "updated_at": chrono::Utc::now() };
+        // archived rows were rewritten above, so the schemas kept with their imports follow suit for restores and publishes
+        let dset = datasets.find_one(doc! { "_id": dataset_id }).await.ok()??;
+        let mut array_filters: Vec<Document> = vec![];
+        for imp in import_records(&dset).iter() {
+            let migrated = imp.get("schema").and_then(|s| migration.apply_to_import(&bson_to_json(s)));
+            if let (Some(import_schema), Some(imp_id)) = (migrated.and_then(|s| bson::to_bson(&s).ok()), extra_id_from_doc(imp)) {
+                let name = format!("imp{}", array_filters.len());
+                set_data.insert(format!("imports.$[{}].schema", name), import_schema);
+                array_filters.push(doc! { format!("{}._id", name): imp_id });
+            }
+        }
+        let update = doc! {
+            "$set": set_data,
+            "$push": { "migrations": record }
         };
-        if let Some((id, import_id)) = self
-            .update_import(&matcher.to_criteria(), &mut record_doc, import_id_opt)
-            .await
-        {
-            return Some((id, import_id));
+        let mut action = datasets.update_one(doc! { "_id": dataset_id }, update);
+        if !array_filters.is_empty() {
+            action = action.array_filters(array_filters);
         }
-        if let Some(record) = self.insert_record("datasets", &record_doc).await {
-            let _id = if let Some(id_opt) = record.get("_id") {
-                id_opt.as_object_id()
-            } else {
-                return None;
-            };
-            if let Some(import_element) = record.get("imports") {
-                if let Some(imports) = import_element.as_array() {
-                    if let Some(last_import) = imports.last() {
-                        if let Some(imp_id) = last_import.as_document().and_then(|doc| doc.get("_id")) {
-                            return Some((_id.unwrap(), imp_id.as_object_id().unwrap()));
-                        }
-                    }
+        action.await.ok()?;
+        invalidate_dataset(&dataset_id.to_string()).await;
+        Some(num_updated)
+    }
+
+    /// Last modification time of a dataset, falling back to its creation time
+    pub async fn fetch_dataset_modified(&self, dataset_id: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        let id = ObjectId::from_str(dataset_id).ok()?;
+        let dset = self.find_records("datasets", 1, 0, Some(doc! { "_id": id }), Some(vec!["updated_at", "created_at"]), None).await.into_iter().next()?;
+        dset.get_datetime("updated_at").or(dset.get_datetime("created_at")).ok().map(|dt| dt.to_chrono())
+    }
+
+    /// Latest modification time and number of datasets matching the listing criteria
+    pub async fn datasets_modified(&self, filter_options: Option<Document>) -> (Option<chrono::DateTime<chrono::Utc>>, u64) {
+        let rows = self.fetch_aggregated("datasets", vec![
+            doc! { "$match": filter_options.unwrap_or_default() },
+            doc! {
+                "$group": {
+                    "_id": Bson::Null,
+                    "updated": { "$max": { "$ifNull": ["$updated_at", "$created_at"] } },
+                    "count": { "$sum": 1 }
                 }
             }
+        ]).await;
+        if let Some(row) = rows.first() {
+            let modified = row.get_datetime("updated").ok().map(|dt| dt.to_chrono());
+            let count = row.get_i32("count").map(|c| c as u64).unwrap_or(0);
+            return (modified, count);
         }
-        None
+        (None, 0)
     }
 
-    pub async fn save_import_with_rows(
-        &self,
-        options: &Value,
-        rows: &[Value],
-        import_id_opt: Option<String>,
-        append: bool,
-    ) -> Option<(String, String, usize)> {
-        let mut data_pk_opt: Option<String> = None;
-        if let Some(data_pk) = options.get("data_pk") {
-            if let Some(pk) = data_pk.as_str() {
-                data_pk_opt = Some(pk.to_owned());
+    pub async fn row_counts(&self, dsets: &[Document]) -> Vec<Document> {
+        let shared_criteria = dsets.iter().filter(|d| rows_collection_name(d) == SHARED_ROWS_COLLECTION).map(live_rows_criteria).collect::<Vec<Document>>();
+        let mut counts = if !shared_criteria.is_empty() {
+            self.fetch_aggregated(SHARED_ROWS_COLLECTION, vec![
+                doc! { "$match": { "$or": shared_criteria } },
+                doc! {
+                    "$group": {
+                        "_id": "$dataset_id",
+                        "count": { "$sum": 1 }
+                    }
+                }
+            ]).await
+        } else {
+            vec![]
+        };
+        for dset in dsets.iter().filter(|d| rows_collection_name(d) != SHARED_ROWS_COLLECTION) {
+            if let Some(id) = extra_id_from_doc(dset) {
+                let collection = self.get_collection(&rows_collection_name(dset)).await;
+                // dedicated collections may hold staged or retired batches
+                let count_r = if dset.contains_key("live_batches") {
+                    collection.count_documents(live_rows_criteria(dset)).await
+                } else {
+                    collection.estimated_document_count().await
+                };
+                if let Ok(count) = count_r {
+                    counts.push(doc! { "_id": id, "count": count as i32 });
+                }
             }
         }
-        let has_import_id = import_id_opt.is_some();
-        if let Some((id, import_id)) = self.save_import(options, import_id_opt).await {
-            let id_string = id.to_string();
-            let import_id_string = import_id.to_string();
-            let replace_mode = ReplaceMode::new(append, has_import_id);
-            let count = self.save_rows(id, import_id, rows, data_pk_opt, replace_mode).await;
-            return Some((id_string, import_id_string, count));
+        counts
+    }
+
+    /// Name of the collection holding a dataset's rows
+    pub async fn dataset_rows_collection(&self, dataset_id: ObjectId) -> String {
+        let dset_opt = self.find_records("datasets", 1, 0, Some(doc! { "_id": dataset_id }), Some(vec!["collection"]), None).await.into_iter().next();
+        dset_opt.map(|d| rows_collection_name(&d)).unwrap_or(SHARED_ROWS_COLLECTION.to_string())
+    }
+
+    pub async fn delete_dataset_rows(&self, dataset_id: ObjectId, collection_name: &str) -> Option<u64> {
+        let collection: Collection<Document> = self.get_collection(collection_name).await;
+        if collection_name == SHARED_ROWS_COLLECTION {
+            delete_by_id(collection, "dataset_id", dataset_id).await
+        } else {
+            let count = collection.estimated_document_count().await.ok();
+            collection.drop().await.ok()?;
+            count
         }
-        None
     }
 
-    pub async fn row_counts(&self, dataset_ids: &[ObjectId]) -> Vec<Document> {
-        self.fetch_aggregated("data_rows", vec![
-            doc! { "$match": { "dataset_id": { "$in": dataset_ids } } },
-            doc! {
-                "$group": {
-                    "_id": "$dataset_id", 
-                    "count": { "$sum": 1 } 
-                }
+    /// Delete a dataset with all its rows, whichever collection they live in
+    pub async fn delete_dataset(&self, dataset_id: &str) -> Option<u64> {
+        let id = ObjectId::from_str(dataset_id).ok()?;
+        let datasets: Collection<Document> = self.get_collection("datasets").await;
+        let dset = datasets.find_one(doc! { "_id": id }).await.ok()??;
+        let num_rows = self.delete_dataset_rows(id, &rows_collection_name(&dset)).await.unwrap_or(0);
+        for collection_name in retired_collections(&dset) {
+            self.delete_dataset_rows(id, &collection_name).await;
+        }
+        datasets.delete_one(doc! { "_id": id }).await.ok()?;
+        invalidate_dataset(dataset_id).await;
+        Some(num_rows)
+    }
+
+    pub async fn record_refresh(&self, dataset_id: &str, refresh: &Value) -> bool {
+        let Ok(id) = ObjectId::from_str(dataset_id) else {
+            return false;
+        };
+        let datasets: Collection<Document> = self.get_collection("datasets").await;
+        let refresh_bson = bson::to_bson(refresh).unwrap_or(Bson::Null);
+        // runs that imported rows are already listed with their import
+        let Some(record) = skipped_run_record(refresh) else {
+            return datasets.update_one(doc! { "_id": id }, doc! { "$set": { "refresh": refresh_bson } }).await.map(|r| r.matched_count > 0).unwrap_or(false);
+        };
+        let update = doc! { "$set": { "refresh": refresh_bson }, "$push": { "imports": record } };
+        let matched = datasets.update_one(doc! { "_id": id }, update).await.map(|r| r.matched_count > 0).unwrap_or(false);
+        if let Ok(Some(dset)) = datasets.find_one(doc! { "_id": id }).await {
+            let imports = import_records(&dset);
+            let excess = excess_skipped_runs(&imports, |r| r.get_str("status").ok()).into_iter().filter_map(|index| extra_id_from_doc(&imports[index])).collect::<Vec<ObjectId>>();
+            if !excess.is_empty() {
+                datasets.update_one(doc! { "_id": id }, doc! { "$pull": { "imports": { "_id": { "$in": excess } } } }).await.ok();
             }
-        ]).await
+        }
+        matched
+    }
+
+    pub async fn save_workbook(&self, workbook: &WorkbookRecord) -> bool {
+        let (Ok(id), Ok(mut workbook_doc)) = (ObjectId::from_str(&workbook.id), bson::to_document(workbook)) else {
+            return false;
+        };
+        workbook_doc.insert("_id", id);
+        let workbooks: Collection<Document> = self.get_collection("workbooks").await;
+        workbooks.replace_one(doc! { "_id": id }, workbook_doc).upsert(true).await.is_ok()
+    }
+
+    pub async fn fetch_workbook(&self, workbook_id: &str) -> Option<WorkbookRecord> {
+        let id = ObjectId::from_str(workbook_id).ok()?;
+        let workbooks: Collection<Document> = self.get_collection("workbooks").await;
+        let workbook_doc = workbooks.find_one(doc! { "_id": id }).await.ok()??;
+        WorkbookRecord::from_json(&bson_to_json(&Bson::Document(workbook_doc)))
+    }
+
+    pub async fn delete_workbook(&self, workbook_id: &str) -> bool {
+        let Ok(id) = ObjectId::from_str(workbook_id) else {
+            return false;
+        };
+        let workbooks: Collection<Document> = self.get_collection("workbooks").await;
+        delete_by_id(workbooks, "_id", id).await.unwrap_or(0) > 0
     }
 
     pub async fn get_datasets(&self, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> (Option<u64>, Vec<Value>) {
-        let (total, mut dsets) = self.find_records_with_total("datasets", limit, skip, filter_options, None, sort_criteria, true).await;
-        let dataset_ids = dsets.iter().map(|d| d.get("_id").unwrap().as_object_id().unwrap().to_owned()).collect::<Vec<ObjectId>>();
-        let counts = self.row_counts(&dataset_ids).await;
+        let (total, dsets) = self.find_records_with_total("datasets", limit, skip, filter_options, None, sort_criteria, true).await;
+        let counts = self.row_counts(&dsets).await;
         let mut datasets: Vec<Value> = vec![];
         for dset in dsets {
             let mut row = bson_to_json(&Bson::Document(dset.to_owned()));
@@ -580,6 +536,149 @@ impl DB {
 
 }
 
+#[async_trait]
+impl Storage for DB {
+    async fn init(&self) -> bool {
+        let transactions = self.supports_transactions().await;
+        println!("MongoDB transactions {}", if transactions { "available" } else { "unavailable, imports are swapped without them" });
+        // collections left behind by moves before a restart
+        let moved = self.find_records("datasets", 0, 0, Some(doc! { "retired_collections.0": { "$exists": true } }), Some(vec!["retired_collections"]), None).await;
+        for dset in moved.iter() {
+            if let Some(dataset_id) = extra_id_from_doc(dset) {
+                for collection_name in retired_collections(dset) {
+                    self.remove_retired_collection(dataset_id, &collection_name, vec![]);
+                }
+            }
+        }
+        self.ensure_core_indexes().await
+    }
+
+    async fn fetch_dataset(&self, dataset_id: &str, import_id_opt: Option<String>, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> Option<RowSet> {
+        DB::fetch_dataset(self, dataset_id, import_id_opt, filter_options, limit, skip, sort_criteria).await
+    }
+
+    async fn get_datasets(&self, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> (Option<u64>, Vec<Value>) {
+        DB::get_datasets(self, filter_options, limit, skip, sort_criteria).await
+    }
+
+    async fn save_import_with_rows(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Option<(String, String, usize)> {
+        DB::save_import_with_rows(self, options, rows, schema, import_id_opt, append).await
+    }
+
+    async fn delete_dataset(&self, dataset_id: &str) -> Option<u64> {
+        DB::delete_dataset(self, dataset_id).await
+    }
+
+    async fn record_refresh(&self, dataset_id: &str, refresh: &Value) -> bool {
+        DB::record_refresh(self, dataset_id, refresh).await
+    }
+
+    async fn list_imports(&self, dataset_id: &str) -> Option<Vec<ImportRecord>> {
+        DB::list_imports(self, dataset_id).await
+    }
+
+    async fn restore_import(&self, dataset_id: &str, import_id: &str) -> Option<bool> {
+        DB::restore_import(self, dataset_id, import_id).await
+    }
+
+    async fn fetch_import_rows(&self, dataset_id: &str, import_id: &str) -> Option<Vec<Value>> {
+        DB::fetch_import_rows(self, dataset_id, import_id).await
+    }
+
+    async fn fetch_dataset_schema(&self, dataset_id: &str) -> Option<Vec<ColumnSchema>> {
+        DB::fetch_dataset_schema(self, dataset_id).await
+    }
+
+    async fn migrate_dataset(&self, dataset_id: &str, migration: &SchemaMigration, schema: &[ColumnSchema]) -> Option<u64> {
+        let id = ObjectId::from_str(dataset_id).ok()?;
+        DB::migrate_dataset(self, id, migration, schema).await
+    }
+
+    async fn fetch_dataset_modified(&self, dataset_id: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        DB::fetch_dataset_modified(self, dataset_id).await
+    }
+
+    async fn datasets_modified(&self, filter_options: Option<Document>) -> (Option<chrono::DateTime<chrono::Utc>>, u64) {
+        DB::datasets_modified(self, filter_options).await
+    }
+
+    async fn declare_indexes(&self, dataset_id: &str, fields: &[String]) -> Vec<String> {
+        match ObjectId::from_str(dataset_id) {
+            Ok(id) => DB::declare_indexes(self, id, fields).await,
+            Err(_) => vec![],
+        }
+    }
+
+    async fn list_dataset_indexes(&self, dataset_id: &str) -> Option<Vec<ColumnIndex>> {
+        DB::list_dataset_indexes(self, dataset_id).await
+    }
+
+    async fn drop_dataset_index(&self, dataset_id: &str, field: &str) -> Option<bool> {
+        DB::drop_dataset_index(self, dataset_id, field).await
+    }
+
+    async fn save_workbook(&self, workbook: &WorkbookRecord) -> bool {
+        DB::save_workbook(self, workbook).await
+    }
+
+    async fn fetch_workbook(&self, workbook_id: &str) -> Option<WorkbookRecord> {
+        DB::fetch_workbook(self, workbook_id).await
+    }
+
+    async fn get_workbooks(&self, limit: u64, skip: u64) -> Vec<WorkbookRecord> {
+        let records = self.find_records("workbooks", limit, skip, None, None, Some(doc! { "updated_at": -1 })).await;
+        records.into_iter().filter_map(|record| WorkbookRecord::from_json(&bson_to_json(&Bson::Document(record)))).collect()
+    }
+
+    async fn delete_workbook(&self, workbook_id: &str) -> bool {
+        DB::delete_workbook(self, workbook_id).await
+    }
+}
+
+/// Import options stored on the dataset, without the identifying fields kept at the top level
+pub fn import_options_doc(options: &Value) -> Document {
+    let mut options_doc = doc! {};
+    if let Some(items) = options.as_object() {
+        for (key, value) in items {
+            match key.as_str() {
+                "dataset_id" |  "import_id" | "filename" | "title" | "description" | "user_ref" | "publish" | "trigger" => continue,
+                _ => {
+                    if let Ok(bson_value) = bson::to_bson(value) {
+                        options_doc.insert(key, bson_value);
+                    }
+                }
+            }
+        }
+    }
+    options_doc
+}
+
+pub fn import_timezone(options: &Value) -> ImportTimezone {
+    options["timezone"].as_str().and_then(ImportTimezone::parse).unwrap_or_default()
+}
+
+pub fn build_row_doc(dataset_id: ObjectId, import_id: ObjectId, row: &Value, schema: &[ColumnSchema], timezone: &ImportTimezone) -> Document {
+    let mut row_data = bson::to_document(row).unwrap_or_default();
+    convert_date_values(&mut row_data, schema, timezone);
+    doc! { "dataset_id": dataset_id, "import_id": import_id, "data": row_data }
+}
+
+pub fn dedicated_collection_threshold() -> usize {
+    match dotenv::var("DEDICATED_COLLECTION_THRESHOLD") {
+        Ok(value) => value.parse().unwrap_or(DEFAULT_DEDICATED_COLLECTION_THRESHOLD),
+        Err(_) => DEFAULT_DEDICATED_COLLECTION_THRESHOLD,
+    }
+}
+
+pub fn dedicated_collection_name(dataset_id: &ObjectId) -> String {
+    format!("rows_{}", dataset_id)
+}
+
+/// Datasets without a `collection` field store their rows in the shared collection
+pub fn rows_collection_name(dset: &Document) -> String {
+    dset.get_str("collection").unwrap_or(SHARED_ROWS_COLLECTION).to_string()
+}
+
 fn get_db_name() -> String {
     std::env::var("MONGO_NAME").expect("Failed to load `MONGO_DB_NAME` environment variable.")
 }
@@ -606,7 +705,7 @@ impl RowSet {
     }
 }
 
-fn bson_to_json(bson: &Bson) -> Value {
+pub fn bson_to_json(bson: &Bson) -> Value {
     match bson {
         Bson::ObjectId(oid) => json!(oid.to_string()),
         Bson::DateTime(dt) => {
@@ -637,37 +736,6 @@ where
     json_value.serialize(serializer)
 } */
 
-fn convert_datetime_strings(doc: &mut Document) {
-    let mut updates = IndexMap::new();
-
-    for (key, value) in doc.iter() {
-        if let Bson::String(date_str) = value {
-            if let Ok(naive_datetime) = NaiveDateTime::parse_from_str(date_str, "%Y-%m-%dT%H:%M:%S%.fZ") {
-                let datetime_utc = chrono::Utc.from_utc_datetime(&naive_datetime);
-                updates.insert(key.clone(), Bson::DateTime(datetime_utc.into()));
-            }
-        }
-    }
-
-    for (key, value) in updates {
-        doc.insert(key, value);
-    }
-}
-
-async fn update_by_inner_id(collection: Collection<Document>, inner_pk: &str, update: Document) -> Option<Bson> {
-    let update_doc = doc! { "$set": update.clone() };
-    let field_path = format!("data.{}", inner_pk);
-    if update.contains_key(inner_pk) {
-        let pk_val = update.get(inner_pk).unwrap();
-        let filter = doc! { field_path: pk_val };
-        let cursor_r = collection.update_one(filter, update_doc).await;
-        if let Ok(cursor) = cursor_r {
-           return cursor.upserted_id;
-        }
-    }
-    None
-}
-
 async fn count_docs(collection: Collection<Document>, filter_options: Option<Document>) -> Option<u64> {
     let filter_opts = if let Some(filter) = filter_options {
         filter
@@ -696,7 +764,7 @@ async fn delete_by_id(collection: Collection<Document>, field_name: &str, id: Ob
 }
 
 
-fn extra_id_from_doc(doc: &Document) -> Option<ObjectId> {
+pub fn extra_id_from_doc(doc: &Document) -> Option<ObjectId> {
     if let Some(id) = doc.get("_id") {
         if let Some(oid) = id.as_object_id() {
             return Some(oid.to_owned());
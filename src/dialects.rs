@@ -0,0 +1,380 @@
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SNIFF_LINES: usize = 20;
+const DELIMITER_CANDIDATES: [char; 4] = [',', ';', '\t', '|'];
+
+// Windows-1252 characters for bytes 0x80 to 0x9F. Unassigned bytes map to the same code point
+const WINDOWS_1252_HIGH: [u32; 32] = [
+  0x20AC, 0x81, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x8D, 0x017D, 0x8F,
+  0x90, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014, 0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x9D, 0x017E, 0x0178,
+];
+
+/// CSV and TSV settings requested with an import. Unset settings are detected on preview or take the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct DialectOptions {
+  pub delimiter: Option<String>,
+  pub quote: Option<String>,
+  pub escape: Option<String>,
+  pub encoding: Option<String>,
+  pub decimal_comma: Option<bool>,
+  pub thousands: Option<String>,
+}
+
+impl DialectOptions {
+  pub fn is_empty(&self) -> bool {
+    self.delimiter.is_none() && self.quote.is_none() && self.escape.is_none() && self.encoding.is_none() && self.decimal_comma.is_none() && self.thousands.is_none()
+  }
+}
+
+/// Resolved CSV dialect. Without an escape character quotes are escaped by doubling them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CsvDialect {
+  pub delimiter: char,
+  pub quote: char,
+  pub escape: Option<char>,
+  pub encoding: String,
+  pub decimal_comma: bool,
+  pub thousands: Option<char>,
+}
+
+impl Default for CsvDialect {
+  fn default() -> Self {
+    CsvDialect { delimiter: ',', quote: '"', escape: None, encoding: "utf-8".to_string(), decimal_comma: false, thousands: None }
+  }
+}
+
+fn parse_char_option(value: &str, name: &str) -> Result<Option<char>, String> {
+  let named = match value.trim().to_lowercase().as_str() {
+    "" | "none" => return Ok(None),
+    "tab" | "\\t" => '\t',
+    "comma" => ',',
+    "semicolon" => ';',
+    "pipe" => '|',
+    "space" => ' ',
+    "dot" | "period" => '.',
+    "apostrophe" | "single" => '\'',
+    "double" => '"',
+    "backslash" => '\\',
+    _ => {
+      let mut chars = value.chars();
+      match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => return Err(format!("Invalid {} {}, expected a single character", name, value)),
+      }
+    }
+  };
+  Ok(Some(named))
+}
+
+/// Canonical name of a supported text encoding
+fn encoding_name(value: &str) -> Result<String, String> {
+  let name = match value.trim().to_lowercase().replace('_', "-").as_str() {
+    "utf-8" | "utf8" => "utf-8",
+    "utf-16" | "utf16" | "utf-16le" => "utf-16le",
+    "utf-16be" => "utf-16be",
+    "windows-1252" | "cp1252" | "latin1" | "latin-1" | "iso-8859-1" => "windows-1252",
+    _ => return Err(format!("Unsupported encoding {}", value)),
+  };
+  Ok(name.to_string())
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> Result<String, String> {
+  let units = bytes
+    .chunks_exact(2)
+    .map(|pair| if big_endian { u16::from_be_bytes([pair[0], pair[1]]) } else { u16::from_le_bytes([pair[0], pair[1]]) })
+    .collect::<Vec<u16>>();
+  String::from_utf16(&units).map_err(|_| "The file is not valid UTF-16".to_string())
+}
+
+fn decode_windows_1252(bytes: &[u8]) -> String {
+  bytes
+    .iter()
+    .map(|b| match b {
+      0x80..=0x9F => char::from_u32(WINDOWS_1252_HIGH[(b - 0x80) as usize]).unwrap_or('\u{FFFD}'),
+      _ => *b as char,
+    })
+    .collect()
+}
+
+/// Decode file contents, honouring a byte order mark. Without an encoding, text that is not valid UTF-8 is read as Windows-1252.
+/// Returns the text and the encoding used.
+pub fn decode_text(bytes: &[u8], encoding: Option<&str>) -> Result<(String, String), String> {
+  let requested = encoding.map(encoding_name).transpose()?;
+  let (bom_encoding, body) = match bytes {
+    [0xEF, 0xBB, 0xBF, rest @ ..] => (Some("utf-8"), rest),
+    [0xFF, 0xFE, rest @ ..] => (Some("utf-16le"), rest),
+    [0xFE, 0xFF, rest @ ..] => (Some("utf-16be"), rest),
+    _ => (None, bytes),
+  };
+  let name = match (bom_encoding, requested) {
+    (Some(bom), _) => bom.to_string(),
+    (None, Some(name)) => name,
+    (None, None) => match std::str::from_utf8(body) {
+      Ok(_) => "utf-8".to_string(),
+      Err(_) => "windows-1252".to_string(),
+    },
+  };
+  let text = match name.as_str() {
+    "utf-16le" => decode_utf16(body, false)?,
+    "utf-16be" => decode_utf16(body, true)?,
+    "windows-1252" => decode_windows_1252(body),
+    _ => String::from_utf8(body.to_vec()).map_err(|_| "The file is not valid UTF-8".to_string())?,
+  };
+  Ok((text, name))
+}
+
+/// Number of delimiters in a line outside quoted values
+fn count_delimiters(line: &str, delimiter: char, quote: char) -> usize {
+  let mut in_quotes = false;
+  let mut count = 0;
+  for c in line.chars() {
+    if c == quote {
+      in_quotes = !in_quotes;
+    } else if c == delimiter && !in_quotes {
+      count += 1;
+    }
+  }
+  count
+}
+
+/// Pick the delimiter found the same number of times on the most lines of a sample
+fn sniff_delimiter(text: &str) -> char {
+  let lines = text.lines().filter(|line| !line.trim().is_empty()).take(SNIFF_LINES).collect::<Vec<&str>>();
+  let mut best = (',', 0usize);
+  for candidate in DELIMITER_CANDIDATES {
+    let counts = lines.iter().map(|line| count_delimiters(line, candidate, '"')).collect::<Vec<usize>>();
+    let Some(first) = counts.first().copied().filter(|n| *n > 0) else {
+      continue;
+    };
+    let consistent = counts.iter().filter(|n| **n == first).count();
+    if consistent > best.1 {
+      best = (candidate, consistent);
+    }
+  }
+  best.0
+}
+
+fn is_digits(s: &str) -> bool {
+  !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Digits, optionally grouped by a thousands separator with groups of three after the first
+fn is_grouped_integer(s: &str, thousands: Option<char>) -> bool {
+  match thousands {
+    Some(sep) if s.contains(sep) => {
+      let groups = s.split(sep).collect::<Vec<&str>>();
+      groups[0].len() <= 3 && is_digits(groups[0]) && groups[1..].iter().all(|g| g.len() == 3 && is_digits(g))
+    }
+    _ => is_digits(s),
+  }
+}
+
+/// Plain decimal form of a number written with a decimal comma and/or thousands separators, e.g. 1.234,5 as 1234.5.
+/// Returns None for other values.
+pub fn normalize_number(value: &str, decimal_comma: bool, thousands: Option<char>) -> Option<String> {
+  let trimmed = value.trim();
+  let (sign, body) = match trimmed.strip_prefix('-') {
+    Some(rest) => ("-", rest),
+    None => ("", trimmed.strip_prefix('+').unwrap_or(trimmed)),
+  };
+  let decimal = if decimal_comma { ',' } else { '.' };
+  let (int_part, frac) = match body.split_once(decimal) {
+    Some((int_part, frac)) if is_digits(frac) => (int_part, Some(frac)),
+    Some(_) => return None,
+    None => (body, None),
+  };
+  if !is_grouped_integer(int_part, thousands) {
+    return None;
+  }
+  let digits = match thousands {
+    Some(sep) => int_part.replace(sep, ""),
+    None => int_part.to_string(),
+  };
+  let normalized = match frac {
+    Some(frac) => format!("{}{}.{}", sign, digits, frac),
+    None => format!("{}{}", sign, digits),
+  };
+  if normalized == trimmed {
+    None
+  } else {
+    Some(normalized)
+  }
+}
+
+/// Detect a decimal comma, with dots as thousands separators, in the values of a sample
+fn sniff_number_format(records: &[Vec<String>]) -> (bool, Option<char>) {
+  let values = records.iter().take(SNIFF_LINES).flatten().map(|v| v.trim()).collect::<Vec<&str>>();
+  let decimal_comma = values.iter().any(|v| v.contains(',') && normalize_number(v, true, Some('.')).is_some());
+  if !decimal_comma {
+    return (false, None);
+  }
+  let thousands = values.iter().any(|v| v.contains('.') && normalize_number(v, true, Some('.')).is_some());
+  (true, if thousands { Some('.') } else { None })
+}
+
+/// Split text into records of fields according to the dialect. Blank lines are skipped.
+pub fn parse_records(text: &str, dialect: &CsvDialect) -> Vec<Vec<String>> {
+  let mut records: Vec<Vec<String>> = vec![];
+  let mut record: Vec<String> = vec![];
+  let mut field = String::new();
+  let mut in_quotes = false;
+  let mut chars = text.chars().peekable();
+  while let Some(c) = chars.next() {
+    if in_quotes {
+      if Some(c) == dialect.escape && chars.peek().is_some() {
+        field.push(chars.next().unwrap_or_default());
+      } else if c == dialect.quote {
+        if dialect.escape.is_none() && chars.peek() == Some(&dialect.quote) {
+          field.push(dialect.quote);
+          chars.next();
+        } else {
+          in_quotes = false;
+        }
+      } else {
+        field.push(c);
+      }
+    } else if c == dialect.quote && field.is_empty() {
+      in_quotes = true;
+    } else if c == dialect.delimiter {
+      record.push(std::mem::take(&mut field));
+    } else if c == '\n' || c == '\r' {
+      if c == '\r' && chars.peek() == Some(&'\n') {
+        chars.next();
+      }
+      record.push(std::mem::take(&mut field));
+      if !(record.len() == 1 && record[0].is_empty()) {
+        records.push(std::mem::take(&mut record));
+      } else {
+        record.clear();
+      }
+    } else {
+      field.push(c);
+    }
+  }
+  if !field.is_empty() || !record.is_empty() {
+    record.push(field);
+    records.push(record);
+  }
+  records
+}
+
+fn write_field(field: &str) -> String {
+  if field.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+/// Resolve the dialect of a delimited file from the requested options, detecting the others when `sniff` is set
+pub fn resolve_dialect(options: &DialectOptions, text: &str, encoding: &str, is_tsv: bool, sniff: bool) -> Result<CsvDialect, String> {
+  let mut dialect = CsvDialect { encoding: encoding.to_string(), ..Default::default() };
+  dialect.delimiter = match options.delimiter.as_deref().map(|d| parse_char_option(d, "delimiter")).transpose()?.flatten() {
+    Some(delimiter) => delimiter,
+    None if sniff => sniff_delimiter(text),
+    None if is_tsv => '\t',
+    None => ',',
+  };
+  if let Some(quote) = options.quote.as_deref().map(|q| parse_char_option(q, "quote")).transpose()?.flatten() {
+    dialect.quote = quote;
+  }
+  if let Some(escape) = options.escape.as_deref() {
+    dialect.escape = match escape.trim().to_lowercase().as_str() {
+      "double" | "doubled" | "" | "none" => None,
+      _ => parse_char_option(escape, "escape")?,
+    };
+  }
+  let (sniffed_comma, sniffed_thousands) = if sniff && options.decimal_comma.is_none() && options.thousands.is_none() {
+    sniff_number_format(&parse_records(text, &dialect))
+  } else {
+    (false, None)
+  };
+  dialect.decimal_comma = options.decimal_comma.unwrap_or(sniffed_comma);
+  dialect.thousands = match options.thousands.as_deref() {
+    Some(thousands) => parse_char_option(thousands, "thousands separator")?,
+    None => sniffed_thousands,
+  };
+  if dialect.thousands == Some(dialect.delimiter) || (dialect.decimal_comma && dialect.thousands == Some(',')) {
+    return Err("The thousands separator must differ from the delimiter and decimal separator".to_string());
+  }
+  Ok(dialect)
+}
+
+pub fn is_delimited_file(file_path: &Path) -> bool {
+  let extension = file_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+  extension == "csv" || extension == "tsv"
+}
+
+/// Rewrite a CSV or TSV file as UTF-8 with commas, double quotes and plain decimal numbers for the parser.
+/// Returns the path of the rewritten file and the dialect read.
+pub fn normalize_delimited_file(file_path: &Path, options: &DialectOptions, sniff: bool) -> Result<(PathBuf, CsvDialect), String> {
+  let bytes = fs::read(file_path).map_err(|e| e.to_string())?;
+  let (text, encoding) = decode_text(&bytes, options.encoding.as_deref())?;
+  let is_tsv = file_path.extension().map(|e| e.eq_ignore_ascii_case("tsv")).unwrap_or(false);
+  let dialect = resolve_dialect(options, &text, &encoding, is_tsv, sniff)?;
+  let normalize_numbers = dialect.decimal_comma || dialect.thousands.is_some();
+  let mut output = String::with_capacity(text.len());
+  for record in parse_records(&text, &dialect) {
+    let fields = record
+      .iter()
+      .map(|field| {
+        let value = match normalize_numbers {
+          true => normalize_number(field, dialect.decimal_comma, dialect.thousands).unwrap_or(field.to_owned()),
+          false => field.to_owned(),
+        };
+        write_field(&value)
+      })
+      .collect::<Vec<String>>();
+    output.push_str(&fields.join(","));
+    output.push('\n');
+  }
+  let stem = file_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+  let normalized_path = file_path.with_file_name(format!("{}--utf8.csv", stem));
+  fs::write(&normalized_path, output).map_err(|e| e.to_string())?;
+  Ok((normalized_path, dialect))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_decode_text() {
+    let utf16 = [0xFF, 0xFE, b'a', 0, b';', 0, 0xE9, 0];
+    assert_eq!(decode_text(&utf16, None), Ok(("a;é".to_string(), "utf-16le".to_string())));
+    // 0x80 is the euro sign and 0xE9 é in Windows-1252, neither valid UTF-8
+    assert_eq!(decode_text(&[0x80, b'5', b',', 0xE9], None), Ok(("€5,é".to_string(), "windows-1252".to_string())));
+    assert_eq!(decode_text(&[0xEF, 0xBB, 0xBF, b'x'], Some("cp1252")).unwrap().0, "x");
+    assert!(decode_text(b"x", Some("ebcdic")).is_err());
+  }
+
+  #[test]
+  fn test_normalize_number() {
+    assert_eq!(normalize_number("1.234,56", true, Some('.')), Some("1234.56".to_string()));
+    assert_eq!(normalize_number("-0,5", true, None), Some("-0.5".to_string()));
+    assert_eq!(normalize_number("12 345", false, Some(' ')), Some("12345".to_string()));
+    assert_eq!(normalize_number("1.23", true, Some('.')), None);
+    assert_eq!(normalize_number("42", true, Some('.')), None);
+    assert_eq!(normalize_number("Rue 1,2", true, None), None);
+  }
+
+  #[test]
+  fn test_sniff_and_parse_records() {
+    let text = "name;price;note\r\nWidget;1.234,50;\"a; b\"\n\nGadget;7,25;\"say \"\"hi\"\"\"\n";
+    let dialect = resolve_dialect(&DialectOptions::default(), text, "utf-8", false, true).unwrap();
+    assert_eq!((dialect.delimiter, dialect.decimal_comma, dialect.thousands), (';', true, Some('.')));
+    let records = parse_records(text, &dialect);
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1], vec!["Widget", "1.234,50", "a; b"]);
+    assert_eq!(records[2][2], "say \"hi\"");
+    let escaped = CsvDialect { delimiter: '|', escape: Some('\\'), ..Default::default() };
+    assert_eq!(parse_records("a|\"x \\\" y\"", &escaped), vec![vec!["a".to_string(), "x \" y".to_string()]]);
+    // explicit options win over detection
+    let options = DialectOptions { delimiter: Some("pipe".to_string()), decimal_comma: Some(false), ..Default::default() };
+    let dialect = resolve_dialect(&options, text, "utf-8", false, true).unwrap();
+    assert_eq!((dialect.delimiter, dialect.decimal_comma), ('|', false));
+    assert_eq!(write_field("a, \"b\""), "\"a, \"\"b\"\"\"");
+  }
+}
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Value of a field before and after an import. Missing fields are null.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldChange {
+  pub before: Value,
+  pub after: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowChange {
+  pub key: Value,
+  pub fields: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportDiff {
+  pub added: Vec<Value>,
+  pub removed: Vec<Value>,
+  pub changed: Vec<RowChange>,
+  pub unchanged: usize,
+}
+
+fn key_string(row: &Value, data_pk: &str) -> Option<String> {
+  match &row[data_pk] {
+    Value::Null => None,
+    value => Some(value.to_string()),
+  }
+}
+
+/// Per-field changes between two versions of a row, in the order of the new row's fields
+fn field_changes(before: &Value, after: &Value) -> Map<String, Value> {
+  let mut changes = Map::new();
+  let empty = Map::new();
+  let (before_map, after_map) = (before.as_object().unwrap_or(&empty), after.as_object().unwrap_or(&empty));
+  let removed_keys = before_map.keys().filter(|k| !after_map.contains_key(*k));
+  for key in after_map.keys().chain(removed_keys) {
+    let (prev, next) = (&before[key], &after[key]);
+    if prev != next {
+      let change = FieldChange { before: prev.clone(), after: next.clone() };
+      changes.insert(key.to_owned(), serde_json::to_value(change).unwrap_or(Value::Null));
+    }
+  }
+  changes
+}
+
+/// Compare the rows of two import versions matched on the dataset's primary key column.
+/// Rows without a key value are ignored.
+pub fn diff_rows(from: &[Value], to: &[Value], data_pk: &str) -> ImportDiff {
+  let previous = from.iter().filter_map(|row| key_string(row, data_pk).map(|k| (k, row))).collect::<HashMap<String, &Value>>();
+  let mut seen: HashMap<String, bool> = HashMap::new();
+  let mut diff = ImportDiff { added: vec![], removed: vec![], changed: vec![], unchanged: 0 };
+  for row in to {
+    let Some(key) = key_string(row, data_pk) else {
+      continue;
+    };
+    if seen.insert(key.clone(), true).is_some() {
+      continue;
+    }
+    match previous.get(&key) {
+      Some(prev) => {
+        let fields = field_changes(prev, row);
+        if fields.is_empty() {
+          diff.unchanged += 1;
+        } else {
+          diff.changed.push(RowChange { key: row[data_pk].clone(), fields });
+        }
+      },
+      None => diff.added.push(row.clone()),
+    }
+  }
+  for row in from {
+    if let Some(key) = key_string(row, data_pk) {
+      if seen.insert(key, true).is_none() {
+        diff.removed.push(row.clone());
+      }
+    }
+  }
+  diff
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_diff_rows() {
+    let from = vec![
+      json!({ "sku": "A1", "name": "Widget", "qty": 3 }),
+      json!({ "sku": "B2", "name": "Gadget", "qty": 7 }),
+      json!({ "sku": "C3", "name": "Gizmo", "qty": 1, "colour": "red" }),
+    ];
+    let to = vec![
+      json!({ "sku": "A1", "name": "Widget", "qty": 3 }),
+      json!({ "sku": "C3", "name": "Gizmo", "qty": 2 }),
+      json!({ "sku": "D4", "name": "Doohickey", "qty": 5 }),
+    ];
+    let diff = diff_rows(&from, &to, "sku");
+    assert_eq!(diff.unchanged, 1);
+    assert_eq!(diff.added, vec![to[2].clone()]);
+    assert_eq!(diff.removed, vec![from[1].clone()]);
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].key, json!("C3"));
+    assert_eq!(diff.changed[0].fields["qty"], json!({ "before": 1, "after": 2 }));
+    // a column dropped by the new import shows as null afterwards
+    assert_eq!(diff.changed[0].fields["colour"], json!({ "before": "red", "after": null }));
+  }
+}
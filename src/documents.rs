@@ -0,0 +1,155 @@
+use bson::{Bson, Document};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Rows read from a JSON, JSON Lines or columnar file. `rows` have nested objects flattened to dotted keys
+/// for the schema and previews, while `documents` keep their structure for storage, so dotted keys
+/// remain valid query paths.
+pub struct JsonFile {
+  pub result: Value,
+  pub rows: Vec<Value>,
+  pub documents: Vec<Value>,
+}
+
+pub fn is_json_file(file_path: &Path) -> bool {
+  let extension = file_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+  matches!(extension.as_str(), "json" | "jsonl" | "ndjson")
+}
+
+fn flatten_into(prefix: &str, value: &Value, flat: &mut Map<String, Value>) {
+  match value {
+    Value::Object(items) if !items.is_empty() => {
+      for (key, item) in items {
+        let path = if prefix.is_empty() { key.to_owned() } else { format!("{}.{}", prefix, key) };
+        flatten_into(&path, item, flat);
+      }
+    }
+    _ => {
+      flat.insert(prefix.to_string(), value.clone());
+    }
+  }
+}
+
+/// Flatten nested objects to dotted keys, e.g. `{ "address": { "city": "Leeds" } }` to `{ "address.city": "Leeds" }`.
+/// Arrays are kept as values.
+pub fn flatten_object(value: &Value) -> Value {
+  let mut flat = Map::new();
+  flatten_into("", value, &mut flat);
+  Value::Object(flat)
+}
+
+/// Remove the value at a dotted key from a stored row, following nested documents
+pub fn take_path(doc: &mut Document, path: &str) -> Option<Bson> {
+  if let Some(value) = doc.remove(path) {
+    return Some(value);
+  }
+  let (head, rest) = path.split_once('.')?;
+  match doc.get_mut(head) {
+    Some(Bson::Document(inner)) => take_path(inner, rest),
+    _ => None,
+  }
+}
+
+/// Set the value at a dotted key of a stored row, adding nested documents as needed
+pub fn insert_path(doc: &mut Document, path: &str, value: Bson) {
+  match path.split_once('.') {
+    Some((head, rest)) => {
+      if !matches!(doc.get(head), Some(Bson::Document(_))) {
+        doc.insert(head, Document::new());
+      }
+      if let Some(Bson::Document(inner)) = doc.get_mut(head) {
+        insert_path(inner, rest, value);
+      }
+    },
+    None => {
+      doc.insert(path, value);
+    }
+  }
+}
+
+/// Objects of a JSON array, a single JSON object or the lines of a JSON Lines file
+fn parse_documents(text: &str, lines: bool) -> Result<Vec<Value>, String> {
+  let documents = if lines {
+    let mut items: Vec<Value> = vec![];
+    for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+      items.push(serde_json::from_str(line).map_err(|e| format!("Invalid JSON on line {}: {}", index + 1, e))?);
+    }
+    items
+  } else {
+    match serde_json::from_str::<Value>(text.trim_start_matches('\u{feff}')).map_err(|e| format!("Invalid JSON: {}", e))? {
+      Value::Array(items) => items,
+      item => vec![item],
+    }
+  };
+  if documents.iter().any(|item| !item.is_object()) {
+    return Err("JSON files must contain objects, one per row".to_string());
+  }
+  Ok(documents)
+}
+
+impl JsonFile {
+  /// Up to `limit` documents of `num_rows`, with a result summary in the shape returned by the spreadsheet parser
+  pub fn from_documents(file_name: &str, extension: &str, mut documents: Vec<Value>, num_rows: usize, limit: usize) -> Self {
+    documents.truncate(limit);
+    let rows = documents.iter().map(flatten_object).collect::<Vec<Value>>();
+    let mut keys: Vec<String> = vec![];
+    for row in rows.iter() {
+      for key in row.as_object().map(|obj| obj.keys()).into_iter().flatten() {
+        if !keys.contains(key) {
+          keys.push(key.to_owned());
+        }
+      }
+    }
+    let result = json!({
+      "filename": file_name,
+      "extension": extension,
+      "sheets": [],
+      "keys": keys,
+      "fields": keys,
+      "num_rows": num_rows,
+      "data": rows
+    });
+    JsonFile { result, rows, documents }
+  }
+}
+
+/// Read up to `limit` rows of a JSON or JSON Lines file
+pub fn read_json_file(file_path: &Path, limit: usize) -> Result<JsonFile, String> {
+  let text = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+  let extension = file_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+  let documents = parse_documents(&text, extension != "json")?;
+  let num_rows = documents.len();
+  let file_name = file_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+  Ok(JsonFile::from_documents(&file_name, &extension, documents, num_rows, limit))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_flatten_object() {
+    let doc = json!({ "id": 1, "address": { "city": "Leeds", "geo": { "lat": 53.8 } }, "tags": ["a", "b"], "meta": {} });
+    assert_eq!(flatten_object(&doc), json!({ "id": 1, "address.city": "Leeds", "address.geo.lat": 53.8, "tags": ["a", "b"], "meta": {} }));
+  }
+
+  #[test]
+  fn test_row_paths() {
+    let mut row = bson::doc! { "id": 1, "address": { "city": "Leeds", "zip": "LS1" } };
+    assert_eq!(take_path(&mut row, "address.city"), Some(Bson::String("Leeds".to_string())));
+    assert_eq!(take_path(&mut row, "address.city"), None);
+    insert_path(&mut row, "location.town", Bson::String("Leeds".to_string()));
+    assert_eq!(row, bson::doc! { "id": 1, "address": { "zip": "LS1" }, "location": { "town": "Leeds" } });
+  }
+
+  #[test]
+  fn test_parse_documents() {
+    let lines = "{\"id\": 1}\n\n{\"id\": 2, \"name\": \"B\"}\n";
+    assert_eq!(parse_documents(lines, true).unwrap().len(), 2);
+    assert_eq!(parse_documents("[{\"id\": 1}, {\"id\": 2}]", false).unwrap()[1], json!({ "id": 2 }));
+    assert_eq!(parse_documents("{\"id\": 1}", false).unwrap(), vec![json!({ "id": 1 })]);
+    assert!(parse_documents("[1, 2]", false).is_err());
+    assert!(parse_documents("{\"id\": 1}\n{oops", true).unwrap_err().starts_with("Invalid JSON on line 2"));
+  }
+}
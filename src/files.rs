@@ -1,4 +1,4 @@
-use std::{fs::{self, File}, os::unix::fs::MetadataExt};
+use std::{fs::{self, File}, io::Write, os::unix::fs::MetadataExt};
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use serde::{Deserialize, Serialize};
@@ -43,6 +43,10 @@ pub async fn match_available_path_name(filename: &str) -> Option<FileInfo> {
   
   pub fn build_filename(file: &FieldData<NamedTempFile>) -> String {
     let file_name = file.metadata.file_name.clone().unwrap();
+    build_timestamped_filename(&file_name)
+  }
+
+  pub fn build_timestamped_filename(file_name: &str) -> String {
     let (start, end) = file_name.to_start_end(".");
     let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() % 1_000_000;
     format!("{}--{}.{}", start.to_kebab_case(), timestamp, end)
@@ -101,6 +105,142 @@ pub async fn match_available_path_name(filename: &str) -> Option<FileInfo> {
 
 
 
+#[derive(Debug)]
+pub enum DownloadError {
+  InvalidUrl,
+  // the validators sent with the request still match
+  NotModified,
+  TooLarge(usize),
+  Failed(String),
+}
+
+impl DownloadError {
+  pub fn message(&self) -> String {
+    match self {
+      DownloadError::InvalidUrl => "Only http and https URLs can be imported".to_string(),
+      DownloadError::NotModified => "The remote file has not changed".to_string(),
+      DownloadError::TooLarge(max) => format!("The remote file exceeds the maximum upload size of {} bytes", max),
+      DownloadError::Failed(msg) => format!("Failed to download the remote file: {}", msg),
+    }
+  }
+}
+
+const DEFAULT_URL_IMPORT_TIMEOUT_SECONDS: u64 = 60;
+
+fn url_import_timeout() -> Duration {
+  let secs = dotenv::var("URL_IMPORT_TIMEOUT")
+    .ok()
+    .and_then(|secs| secs.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_URL_IMPORT_TIMEOUT_SECONDS);
+  Duration::from_secs(secs)
+}
+
+/// Extension implied by a content type, e.g. for published Google Sheets links ending in /pub?output=csv
+fn extension_from_content_type(content_type: &str) -> Option<&'static str> {
+  let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+  match mime.as_str() {
+    "text/csv" => Some("csv"),
+    "text/tab-separated-values" => Some("tsv"),
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => Some("xlsx"),
+    "application/vnd.ms-excel.sheet.binary.macroenabled.12" => Some("xlsb"),
+    "application/vnd.ms-excel" => Some("xls"),
+    "application/vnd.oasis.opendocument.spreadsheet" => Some("ods"),
+    "application/json" => Some("json"),
+    "application/x-ndjson" | "application/jsonl" => Some("jsonl"),
+    "application/vnd.apache.parquet" => Some("parquet"),
+    "application/vnd.apache.arrow.file" => Some("arrow"),
+    _ => None,
+  }
+}
+
+/// Name of a remote file from the Content-Disposition header or the last segment of the URL path.
+/// The content type or an `output` query parameter supply the extension when the name has none.
+pub fn remote_file_name(url: &reqwest::Url, content_disposition: Option<&str>, content_type: Option<&str>) -> String {
+  let disposition_name = content_disposition.and_then(|cd| {
+    cd.split(';')
+      .filter_map(|part| part.trim().strip_prefix("filename="))
+      .next()
+      .map(|name| name.trim_matches('"').to_string())
+  });
+  let path_name = url.path_segments().and_then(|mut segments| segments.rfind(|s| !s.is_empty())).map(|s| s.to_string());
+  let name = disposition_name.or(path_name).unwrap_or("import".to_string());
+  if name.contains('.') {
+    return name;
+  }
+  let output_format = url.query_pairs().find(|(k, _)| k == "output" || k == "format").map(|(_, v)| v.to_lowercase());
+  let extension = content_type.and_then(extension_from_content_type).map(|ext| ext.to_string()).or(output_format).unwrap_or("csv".to_string());
+  format!("{}.{}", name, extension)
+}
+
+/// Remote file saved to the temporary directory with the validators for the next conditional request
+pub struct RemoteFile {
+  pub name: String,
+  // timestamped name in the temporary directory, as assigned to uploads
+  pub file_name: String,
+  pub path: PathBuf,
+  pub size: usize,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+}
+
+/// Download an http(s) URL to the temporary directory, stopping once the size exceeds `max_size`.
+/// With an ETag or Last-Modified value from a previous download the request is conditional.
+pub async fn download_remote_file(url: &str, max_size: usize, etag: Option<&str>, last_modified: Option<&str>) -> Result<RemoteFile, DownloadError> {
+  let parsed = reqwest::Url::parse(url).map_err(|_| DownloadError::InvalidUrl)?;
+  if !["http", "https"].contains(&parsed.scheme()) {
+    return Err(DownloadError::InvalidUrl);
+  }
+  let client = reqwest::Client::builder().timeout(url_import_timeout()).build().map_err(|e| DownloadError::Failed(e.to_string()))?;
+  let mut request = client.get(parsed.clone());
+  if let Some(tag) = etag {
+    request = request.header(reqwest::header::IF_NONE_MATCH, tag);
+  }
+  if let Some(modified) = last_modified {
+    request = request.header(reqwest::header::IF_MODIFIED_SINCE, modified);
+  }
+  let mut response = request.send().await.map_err(|e| DownloadError::Failed(e.to_string()))?;
+  if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+    return Err(DownloadError::NotModified);
+  }
+  if !response.status().is_success() {
+    return Err(DownloadError::Failed(format!("the server responded with {}", response.status())));
+  }
+  if response.content_length().map(|len| len as usize > max_size).unwrap_or(false) {
+    return Err(DownloadError::TooLarge(max_size));
+  }
+  let header_value = |name: reqwest::header::HeaderName| response.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+  let content_disposition = header_value(reqwest::header::CONTENT_DISPOSITION);
+  let content_type = header_value(reqwest::header::CONTENT_TYPE);
+  let (etag, last_modified) = (header_value(reqwest::header::ETAG), header_value(reqwest::header::LAST_MODIFIED));
+  let remote_name = remote_file_name(&parsed, content_disposition.as_deref(), content_type.as_deref());
+  let file_name = build_timestamped_filename(&remote_name);
+  let (tmp_directory, sub_directory) = get_tmp_and_sub_directories();
+  let file_path = ensure_directory_and_construct_path(&tmp_directory, &sub_directory, &file_name).map_err(|e| DownloadError::Failed(e.to_string()))?;
+  let mut dest_file = File::create(&file_path).map_err(|e| DownloadError::Failed(e.to_string()))?;
+  let mut num_bytes: usize = 0;
+  loop {
+    let chunk = match response.chunk().await {
+      Ok(Some(chunk)) => chunk,
+      Ok(None) => break,
+      Err(e) => {
+        remove_uploaded_file(&file_path);
+        return Err(DownloadError::Failed(e.to_string()));
+      }
+    };
+    num_bytes += chunk.len();
+    // the Content-Length header may be missing or wrong
+    if num_bytes > max_size {
+      remove_uploaded_file(&file_path);
+      return Err(DownloadError::TooLarge(max_size));
+    }
+    if let Err(e) = dest_file.write_all(&chunk) {
+      remove_uploaded_file(&file_path);
+      return Err(DownloadError::Failed(e.to_string()));
+    }
+  }
+  Ok(RemoteFile { name: remote_name, file_name, path: file_path, size: num_bytes, etag, last_modified })
+}
+
 fn tmp_file_delete_after_seconds() -> u64 {
     dotenv::var("DELETE_TMP_FILES_AFTER_SECONDS")
         .unwrap_or_else(|_| String::from("600"))
@@ -151,4 +291,49 @@ fn tmp_file_delete_after_seconds() -> u64 {
         }
     }
     Ok((num_deleted, num_files))
-  }
\ No newline at end of file
+  }
+#[cfg(test)]
+mod test {
+  use super::*;
+  use axum::{http::{header, HeaderMap, StatusCode}, response::IntoResponse, routing::get, Router};
+
+  #[test]
+  fn test_remote_file_name() {
+    let url = reqwest::Url::parse("https://docs.google.com/spreadsheets/d/e/2PACX-1v/pub?output=csv").unwrap();
+    assert_eq!(remote_file_name(&url, None, Some("text/csv; charset=utf-8")), "pub.csv");
+    assert_eq!(remote_file_name(&url, None, None), "pub.csv");
+    assert_eq!(remote_file_name(&url, Some("attachment; filename=\"Price List.xlsx\""), None), "Price List.xlsx");
+    let url = reqwest::Url::parse("https://example.com/files/stock.ods").unwrap();
+    assert_eq!(remote_file_name(&url, None, Some("application/octet-stream")), "stock.ods");
+  }
+
+  #[tokio::test]
+  async fn test_download_remote_file() {
+    let app = Router::new()
+      .route("/sheets/pub", get(|headers: HeaderMap| async move {
+        if headers.get(header::IF_NONE_MATCH).is_some_and(|tag| tag == "\"v1\"") {
+          return StatusCode::NOT_MODIFIED.into_response();
+        }
+        ([(header::CONTENT_TYPE, "text/csv"), (header::ETAG, "\"v1\"")], "sku,qty\nA1,3\nB2,7\n").into_response()
+      }))
+      .route("/large.csv", get(|| async { "x".repeat(2048) }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      axum::serve(listener, app).await.unwrap();
+    });
+    let url = format!("http://{}/sheets/pub", addr);
+    let file = download_remote_file(&url, 1024, None, None).await.unwrap();
+    assert_eq!((file.name.as_str(), file.size), ("pub.csv", 18));
+    assert_eq!(fs::read_to_string(&file.path).unwrap(), "sku,qty\nA1,3\nB2,7\n");
+    // /process finds the file under its stored name
+    assert!(file.file_name.starts_with("pub--") && file.path.ends_with(&file.file_name));
+    remove_uploaded_file(&file.path);
+    // a matching ETag is answered with 304
+    let result = download_remote_file(&url, 1024, file.etag.as_deref(), None).await;
+    assert!(matches!(result, Err(DownloadError::NotModified)));
+    let result = download_remote_file(&format!("http://{}/large.csv", addr), 1024, None, None).await;
+    assert!(matches!(result, Err(DownloadError::TooLarge(1024))));
+    assert!(matches!(download_remote_file("ftp://example.com/stock.csv", 1024, None, None).await, Err(DownloadError::InvalidUrl)));
+  }
+}
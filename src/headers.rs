@@ -0,0 +1,196 @@
+use serde_json::{json, Value};
+use spreadsheet_to_json::{heck::ToSnakeCase, simple_string_patterns::IsNumeric};
+
+use crate::schema::extract_keys;
+
+/// Header rows are addressed with a single byte by the parser
+pub const MAX_HEADER_ROW: usize = 255;
+
+fn cell_label(value: &Value) -> String {
+  match value {
+    Value::Null => String::new(),
+    Value::String(s) => s.trim().to_string(),
+    other => other.to_string(),
+  }
+}
+
+/// Labels of each header level by column. Blank cells in all but the last level belong to a merged
+/// cell spanning from the left, so they repeat the previous label.
+fn fill_merged_spans(levels: &mut [Vec<String>]) {
+  let last = levels.len().saturating_sub(1);
+  for level in levels.iter_mut().take(last) {
+    let mut previous = String::new();
+    for label in level.iter_mut() {
+      if label.is_empty() {
+        *label = previous.clone();
+      } else {
+        previous = label.clone();
+      }
+    }
+  }
+}
+
+/// Key for a column of a sheet without headers: `col_a`, `col_b` ... `col_aa` by column letter,
+/// or `c01`, `c02` with the `numbers` key style
+pub fn generated_key(index: usize, key_style: &str) -> String {
+  match key_style {
+    "numbers" | "number" | "numeric" => format!("c{:02}", index + 1),
+    _ => {
+      let mut letters = String::new();
+      let mut n = index + 1;
+      while n > 0 {
+        let rem = (n - 1) % 26;
+        letters.insert(0, (b'a' + rem as u8) as char);
+        n = (n - 1) / 26;
+      }
+      format!("col_{}", letters)
+    }
+  }
+}
+
+fn header_cell_value(label: &str) -> Value {
+  if label.is_empty() {
+    Value::Null
+  } else if label.is_numeric() {
+    label.trim().parse::<f64>().map(|n| if n.fract() == 0.0 && n.abs() < 1e15 { json!(n as i64) } else { json!(n) }).unwrap_or(json!(label))
+  } else {
+    json!(label)
+  }
+}
+
+/// Re-key the rows of a sheet without a header row and restore the first row, which the parser read as headers.
+/// Supplied keys name the leading columns and the rest are generated, counted from `first_col`.
+pub fn restore_header_row(result: &mut Value, rows: &mut Vec<Value>, keys: &[String], first_col: usize, key_style: &str) {
+  let original_keys = extract_keys(result, rows);
+  let labels = match result["headers"].as_array() {
+    Some(items) if items.len() == original_keys.len() => items.iter().map(cell_label).collect::<Vec<String>>(),
+    _ => original_keys.clone(),
+  };
+  let new_keys = (0..original_keys.len())
+    .map(|index| keys.get(index).filter(|k| !k.is_empty()).cloned().unwrap_or(generated_key(first_col + index, key_style)))
+    .collect::<Vec<String>>();
+  let mut first_row = serde_json::Map::new();
+  for (key, label) in new_keys.iter().zip(labels.iter()) {
+    first_row.insert(key.to_owned(), header_cell_value(label));
+  }
+  let mut renamed_rows = vec![Value::Object(first_row)];
+  for row in rows.iter() {
+    let mut renamed = serde_json::Map::new();
+    for (key, new_key) in original_keys.iter().zip(new_keys.iter()) {
+      renamed.insert(new_key.to_owned(), row[key].clone());
+    }
+    renamed_rows.push(Value::Object(renamed));
+  }
+  *rows = renamed_rows;
+  result["keys"] = json!(new_keys);
+  result["fields"] = json!(new_keys);
+  result["headers"] = json!(new_keys);
+}
+
+/// Join the header levels of each column, e.g. "Q1 / Revenue", and derive unique snake_case keys.
+/// Columns without any label keep their original key.
+fn combined_headers(levels: &[Vec<String>], original_keys: &[String]) -> Vec<(String, String)> {
+  let mut headers: Vec<(String, String)> = vec![];
+  for (index, original) in original_keys.iter().enumerate() {
+    let mut parts: Vec<&str> = vec![];
+    for level in levels {
+      if let Some(label) = level.get(index).filter(|l| !l.is_empty()) {
+        // a vertically merged cell repeats its label on the next level
+        if parts.last() != Some(&label.as_str()) {
+          parts.push(label);
+        }
+      }
+    }
+    let label = parts.join(" / ");
+    let base = match label.to_snake_case() {
+      key if key.is_empty() => original.to_owned(),
+      key => key,
+    };
+    let mut key = base.clone();
+    let mut suffix = 2;
+    while headers.iter().any(|(k, _)| *k == key) {
+      key = format!("{}_{}", base, suffix);
+      suffix += 1;
+    }
+    headers.push((key, label));
+  }
+  headers
+}
+
+/// Merge `header_rows` rows starting at the parsed header row into one header per column.
+/// The parser reads the rows below the first header row as data, so they are removed from `rows`
+/// and the remaining rows are re-keyed.
+pub fn combine_header_rows(result: &mut Value, rows: &mut Vec<Value>, header_rows: usize) {
+  if header_rows < 2 {
+    return;
+  }
+  let keys = extract_keys(result, rows);
+  let first_level = match result["headers"].as_array() {
+    Some(items) if items.len() == keys.len() => items.iter().map(cell_label).collect::<Vec<String>>(),
+    _ => keys.clone(),
+  };
+  let extra = (header_rows - 1).min(rows.len());
+  let mut levels = vec![first_level];
+  for row in rows.iter().take(extra) {
+    levels.push(keys.iter().map(|key| cell_label(&row[key])).collect());
+  }
+  fill_merged_spans(&mut levels);
+  let headers = combined_headers(&levels, &keys);
+  let data = rows.split_off(extra);
+  *rows = data
+    .into_iter()
+    .map(|row| {
+      let mut renamed = serde_json::Map::new();
+      for (key, (new_key, _)) in keys.iter().zip(headers.iter()) {
+        renamed.insert(new_key.to_owned(), row[key].clone());
+      }
+      Value::Object(renamed)
+    })
+    .collect();
+  let new_keys = headers.iter().map(|(key, _)| key.to_owned()).collect::<Vec<String>>();
+  result["keys"] = json!(new_keys);
+  result["fields"] = json!(new_keys);
+  result["headers"] = json!(headers.iter().map(|(_, label)| label.to_owned()).collect::<Vec<String>>());
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_combine_header_rows() {
+    // "2024" is merged across both quarters of revenue, "Region" across both header rows
+    let mut result = json!({ "fields": ["region", "2024", "column_c"], "headers": ["Region", "2024", ""] });
+    let mut rows = vec![
+      json!({ "region": "Region", "2024": "Q1 Revenue", "column_c": "Q2 Revenue" }),
+      json!({ "region": "North", "2024": 120, "column_c": 140 }),
+      json!({ "region": "South", "2024": 90, "column_c": 95 }),
+    ];
+    combine_header_rows(&mut result, &mut rows, 2);
+    assert_eq!(result["headers"], json!(["Region", "2024 / Q1 Revenue", "2024 / Q2 Revenue"]));
+    assert_eq!(result["fields"], json!(["region", "2024_q1_revenue", "2024_q2_revenue"]));
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0], json!({ "region": "North", "2024_q1_revenue": 120, "2024_q2_revenue": 140 }));
+  }
+
+  #[test]
+  fn test_restore_header_row() {
+    assert_eq!((generated_key(0, "letters"), generated_key(27, "letters"), generated_key(8, "numbers")), ("col_a".to_string(), "col_ab".to_string(), "c09".to_string()));
+    let mut result = json!({ "fields": ["a_1", "widget", "3"], "headers": ["A1", "Widget", "3"] });
+    let mut rows = vec![json!({ "a_1": "B2", "widget": "Gadget", "3": 7 })];
+    restore_header_row(&mut result, &mut rows, &["sku".to_string()], 1, "letters");
+    assert_eq!(result["fields"], json!(["sku", "col_c", "col_d"]));
+    assert_eq!(rows, vec![
+      json!({ "sku": "A1", "col_c": "Widget", "col_d": 3 }),
+      json!({ "sku": "B2", "col_c": "Gadget", "col_d": 7 }),
+    ]);
+  }
+
+  #[test]
+  fn test_combined_header_keys_are_unique() {
+    let mut levels = vec![vec![String::new(), "Total".to_string(), "Total".to_string()], vec![String::new(), String::new(), String::new()]];
+    fill_merged_spans(&mut levels);
+    let headers = combined_headers(&levels, &["a".to_string(), "b".to_string(), "c".to_string()]);
+    assert_eq!(headers.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>(), vec!["a", "total", "total_2"]);
+  }
+}
@@ -0,0 +1,801 @@
+use bson::{doc, oid::ObjectId, Bson, Document};
+use futures::stream::StreamExt;
+use mongodb::{ClientSession, Collection};
+use serde::Serialize;
+use serde_json::Value;
+use serde_with::chrono;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::cache::invalidate_dataset;
+use crate::db::{
+  bson_to_json, build_row_doc, dedicated_collection_name, dedicated_collection_threshold, extra_id_from_doc, import_options_doc,
+  import_timezone, rows_collection_name, DB, MIGRATION_BATCH_SIZE, SHARED_ROWS_COLLECTION,
+};
+use crate::options::{DataSetMatcher, ReplaceMode};
+use crate::schema::ColumnSchema;
+
+/// Rows written by an import are tagged with a batch id. Readers only see rows of the batches listed in the
+/// dataset's `live_batches`, so new rows are staged first and published with a single update of the dataset.
+pub fn live_rows_criteria(dset: &Document) -> Document {
+  let mut criteria = doc! {};
+  if let Some(id) = extra_id_from_doc(dset) {
+    criteria.insert("dataset_id", id);
+  }
+  // datasets imported before batches were introduced have no live_batches and all their rows are live
+  if dset.contains_key("live_batches") {
+    criteria.insert("batch_id", doc! { "$in": batch_ids(&live_batches(dset)) });
+  }
+  criteria
+}
+
+/// Live batches of a dataset, each with its `_id` and `import_id`
+pub fn live_batches(dset: &Document) -> Vec<Document> {
+  dset
+    .get_array("live_batches")
+    .map(|items| items.iter().filter_map(|b| b.as_document().cloned()).collect())
+    .unwrap_or_default()
+}
+
+/// Collections a dataset's rows moved out of, whose rows are removed once readers that resolved them before the move are done
+pub fn retired_collections(dset: &Document) -> Vec<String> {
+  dset
+    .get_array("retired_collections")
+    .map(|items| items.iter().filter_map(|c| c.as_str().map(|name| name.to_string())).collect())
+    .unwrap_or_default()
+}
+
+const DEFAULT_IMPORT_HISTORY_LIMIT: usize = 5;
+const RETIRED_BATCH_GRACE_SECONDS: u64 = 30;
+
+/// Number of previous imports whose rows are kept as restorable versions
+pub fn import_history_limit() -> usize {
+  dotenv::var("IMPORT_HISTORY_LIMIT")
+    .ok()
+    .and_then(|limit| limit.parse::<usize>().ok())
+    .unwrap_or(DEFAULT_IMPORT_HISTORY_LIMIT)
+}
+
+/// Imports made with `publish: false` are stored as drafts and leave the live rows unchanged
+pub fn is_draft(options: &Value) -> bool {
+  options["publish"].as_bool() == Some(false)
+}
+
+/// Origin of an import recorded with it: the source URL and what triggered it, e.g. a scheduled refresh
+pub fn import_source(options: &Value) -> Document {
+  let mut source = doc! {};
+  for key in ["source_url", "trigger"] {
+    if let Some(value) = options[key].as_str() {
+      source.insert(key, value);
+    }
+  }
+  source
+}
+
+const MAX_SKIPPED_RUNS: usize = 50;
+
+/// Scheduled runs that found the source unchanged or failed are listed in the history without rows
+pub fn is_skipped_run(status: Option<&str>) -> bool {
+  matches!(status, Some("unchanged") | Some("failed"))
+}
+
+/// History entry of a scheduled run that did not import rows. Runs that import are recorded by the import they make.
+pub fn skipped_run_record(refresh: &Value) -> Option<Document> {
+  let status = refresh["status"].as_str().filter(|status| is_skipped_run(Some(status)))?;
+  let mut record = doc! { "_id": ObjectId::new(), "dt": chrono::Utc::now(), "trigger": "schedule", "status": status };
+  for key in ["source_url", "message"] {
+    if let Some(value) = refresh[key].as_str() {
+      record.insert(key, value);
+    }
+  }
+  Some(record)
+}
+
+/// Positions of the oldest skipped runs beyond the number kept in the history
+pub fn excess_skipped_runs<T>(imports: &[T], status: impl Fn(&T) -> Option<&str>) -> Vec<usize> {
+  let skipped = imports.iter().enumerate().filter(|(_, record)| is_skipped_run(status(record))).map(|(index, _)| index).collect::<Vec<usize>>();
+  let num_excess = skipped.len().saturating_sub(MAX_SKIPPED_RUNS);
+  skipped.into_iter().take(num_excess).collect()
+}
+
+/// Import listed in a dataset's history
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRecord {
+  pub import_id: String,
+  pub dt: Option<String>,
+  pub filename: String,
+  pub sheet_index: u32,
+  // rows written by the import
+  pub rows: Option<u64>,
+  // rows of the dataset once the import was applied
+  pub total_rows: Option<u64>,
+  pub current: bool,
+  pub restorable: bool,
+  // not yet published
+  pub draft: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub source_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub trigger: Option<String>,
+  // outcome of a scheduled run: imported, unchanged or failed
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub status: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>,
+  // live import when the draft was created
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub base_import: Option<String>,
+}
+
+impl ImportRecord {
+  pub fn from_json(record: &Value, current_import: Option<&str>, restorable: bool) -> Self {
+    let import_id = record["_id"].as_str().unwrap_or_default().to_string();
+    ImportRecord {
+      current: current_import == Some(import_id.as_str()),
+      import_id,
+      dt: record["dt"].as_str().map(|dt| dt.to_string()),
+      filename: record["filename"].as_str().unwrap_or_default().to_string(),
+      sheet_index: record["sheet_index"].as_u64().unwrap_or(0) as u32,
+      rows: record["rows"].as_u64(),
+      total_rows: record["total_rows"].as_u64(),
+      restorable,
+      draft: record["draft"].as_bool().unwrap_or(false),
+      source_url: record["source_url"].as_str().map(|url| url.to_string()),
+      trigger: record["trigger"].as_str().map(|trigger| trigger.to_string()),
+      status: match (record["status"].as_str(), record["trigger"].as_str()) {
+        (Some(status), _) => Some(status.to_string()),
+        (None, Some("schedule")) => Some("imported".to_string()),
+        _ => None,
+      },
+      message: record["message"].as_str().map(|message| message.to_string()),
+      base_import: record["base_import"].as_str().map(|id| id.to_string()),
+    }
+  }
+
+  /// Whether the entry is a scheduled run that imported no rows
+  pub fn is_skipped_run(&self) -> bool {
+    is_skipped_run(self.status.as_deref())
+  }
+}
+
+/// A draft is stale when another import was made live after it was created, as publishing it would roll that import back
+pub fn is_stale_draft(imports: &[ImportRecord], draft_id: &str) -> bool {
+  let current = imports.iter().find(|imp| imp.current).map(|imp| imp.import_id.as_str());
+  match imports.iter().find(|imp| imp.import_id == draft_id && imp.draft) {
+    Some(draft) => current != Some(draft_id) && draft.base_import.as_deref() != current,
+    None => false,
+  }
+}
+
+/// Import records of a dataset in the order they were first made
+pub fn import_records(dset: &Document) -> Vec<Document> {
+  dset
+    .get_array("imports")
+    .map(|items| items.iter().filter_map(|imp| imp.as_document().cloned()).collect())
+    .unwrap_or_default()
+}
+
+/// Import whose rows are currently live. Datasets without a `current_import` were last changed by their latest import.
+pub fn current_import_id(dset: &Value) -> Option<String> {
+  match dset["current_import"].as_str() {
+    Some(id) => Some(id.to_string()),
+    None => dset["imports"]
+      .as_array()
+      .and_then(|items| items.iter().rev().find(|imp| !is_skipped_run(imp["status"].as_str())))
+      .and_then(|imp| imp["_id"].as_str())
+      .map(|id| id.to_string()),
+  }
+}
+
+/// Import ids whose row versions are retained: the latest imports within the history limit and the current import
+pub fn retained_import_ids(import_ids: &[String], current_import: &str, limit: usize) -> Vec<String> {
+  let skip = import_ids.len().saturating_sub(limit + 1);
+  let mut retained = import_ids.iter().skip(skip).cloned().collect::<Vec<String>>();
+  if !retained.iter().any(|id| id == current_import) {
+    retained.push(current_import.to_string());
+  }
+  retained
+}
+
+fn batch_ids(batches: &[Document]) -> Vec<ObjectId> {
+  batches.iter().filter_map(extra_id_from_doc).collect()
+}
+
+/// Batches referenced by the live rows or by the versions kept with import records
+fn referenced_batches(live: &[Document], imports: &[Document]) -> HashSet<ObjectId> {
+  let mut ids = batch_ids(live).into_iter().collect::<HashSet<ObjectId>>();
+  for record in imports {
+    if let Ok(batches) = record.get_array("batches") {
+      ids.extend(batches.iter().filter_map(|b| b.as_document().and_then(extra_id_from_doc)));
+    }
+  }
+  ids
+}
+
+/// Batches no longer referenced after an import, split into the previously live ones and those only kept as versions.
+/// Readers may still hold the previous live batches, so they are only removed once the swap has committed.
+fn unreferenced_batches(previous: &[Document], previous_imports: &[Document], live: &[Document], imports: &[Document]) -> (Vec<ObjectId>, Vec<ObjectId>) {
+  let referenced = referenced_batches(previous, previous_imports);
+  let kept = referenced_batches(live, imports);
+  let previous_ids = batch_ids(previous);
+  referenced.difference(&kept).cloned().partition(|id| previous_ids.contains(id))
+}
+
+/// Split the current live batches into those retired by an import and those kept alongside it
+fn split_batches(previous: Vec<Document>, replace_mode: &ReplaceMode, import_id: ObjectId) -> (Vec<Document>, Vec<Document>) {
+  match replace_mode {
+    ReplaceMode::ReplaceAll => (previous, vec![]),
+    ReplaceMode::ReplaceImport => previous.into_iter().partition(|b| b.get_object_id("import_id").ok() == Some(import_id)),
+    ReplaceMode::Append => (vec![], previous),
+  }
+}
+
+/// Keep only the last row for each primary key value within an import
+fn dedupe_by_key(docs: Vec<Document>, data_pk: &str) -> Vec<Document> {
+  let mut seen: HashSet<String> = HashSet::new();
+  let mut unique = docs
+    .into_iter()
+    .rev()
+    .filter(|d| match d.get_document("data").ok().and_then(|data| data.get(data_pk)) {
+      Some(value) => seen.insert(value.to_string()),
+      None => true,
+    })
+    .collect::<Vec<Document>>();
+  unique.reverse();
+  unique
+}
+
+/// Large datasets, or those imported with `storage=dedicated`, get their own collection.
+/// The collection only changes when all rows are replaced.
+fn select_rows_collection(dataset_id: &ObjectId, current: &str, options: &Value, num_rows: usize, replace_mode: &ReplaceMode) -> String {
+  if !matches!(replace_mode, ReplaceMode::ReplaceAll) {
+    return current.to_string();
+  }
+  let threshold = dedicated_collection_threshold();
+  let dedicated = match options["storage"].as_str().unwrap_or_default() {
+    "dedicated" => true,
+    "shared" => false,
+    _ => threshold > 0 && num_rows >= threshold,
+  };
+  if dedicated {
+    dedicated_collection_name(dataset_id)
+  } else {
+    SHARED_ROWS_COLLECTION.to_string()
+  }
+}
+
+enum DatasetWrite {
+  Insert(Document),
+  Update(Document, Document),
+}
+
+/// Writes that publish a staged batch: the dataset update, then the removal of rows no longer referenced
+struct ImportSwap {
+  write: DatasetWrite,
+  cleanup: Vec<(String, Document)>,
+}
+
+impl DB {
+  /// Transactions require a replica set or a sharded cluster
+  async fn detect_transactions(&self) -> Option<bool> {
+    let client = self.client.lock().await.clone();
+    let reply = client.database("admin").run_command(doc! { "hello": 1 }).await.ok()?;
+    Some(reply.contains_key("setName") || reply.get_str("msg") == Ok("isdbgrid"))
+  }
+
+  /// Transaction support is detected at startup and kept for the life of the process.
+  /// If the server cannot be reached then, the next import tries again.
+  pub(crate) async fn supports_transactions(&self) -> bool {
+    if let Some(supported) = self.transactions.get() {
+      return *supported;
+    }
+    match self.detect_transactions().await {
+      Some(supported) => {
+        self.transactions.set(supported).ok();
+        supported
+      },
+      None => false,
+    }
+  }
+
+  /// Tag rows of a legacy dataset with one batch per import so they stay live once `live_batches` is set.
+  /// Readers do not filter by batch until then, so this is invisible to them.
+  async fn adopt_legacy_rows(&self, dset: &Document, collection_name: &str) -> Vec<Document> {
+    if dset.contains_key("live_batches") {
+      return live_batches(dset);
+    }
+    let Some(dataset_id) = extra_id_from_doc(dset) else {
+      return vec![];
+    };
+    let collection: Collection<Document> = self.get_collection(collection_name).await;
+    let criteria = doc! { "dataset_id": dataset_id, "batch_id": { "$exists": false } };
+    let import_ids = collection.distinct("import_id", criteria.clone()).await.unwrap_or_default();
+    let mut batches: Vec<Document> = vec![];
+    for import_id in import_ids {
+      let batch_id = ObjectId::new();
+      let mut filter = criteria.clone();
+      filter.insert("import_id", import_id.clone());
+      if collection.update_many(filter, doc! { "$set": { "batch_id": batch_id } }).await.is_ok() {
+        batches.push(doc! { "_id": batch_id, "import_id": import_id });
+      }
+    }
+    batches
+  }
+
+  /// Remove batches that were live before an import once readers that fetched the dataset before the swap are done
+  fn remove_retired_batches(&self, collection_name: &str, dataset_id: ObjectId, batch_ids: Vec<ObjectId>) {
+    let db = self.clone();
+    let collection_name = collection_name.to_string();
+    tokio::spawn(async move {
+      sleep(Duration::from_secs(RETIRED_BATCH_GRACE_SECONDS)).await;
+      let collection: Collection<Document> = db.get_collection(&collection_name).await;
+      collection.delete_many(doc! { "dataset_id": dataset_id, "batch_id": { "$in": batch_ids } }).await.ok();
+    });
+  }
+
+  /// Remove the rows left in a collection the dataset moved out of after the same grace period as retired batches
+  pub(crate) fn remove_retired_collection(&self, dataset_id: ObjectId, collection_name: &str, batch_ids: Vec<ObjectId>) {
+    let db = self.clone();
+    let collection_name = collection_name.to_string();
+    tokio::spawn(async move {
+      sleep(Duration::from_secs(RETIRED_BATCH_GRACE_SECONDS)).await;
+      db.purge_retired_collection(dataset_id, &collection_name, &batch_ids).await;
+    });
+  }
+
+  /// If the dataset has since moved back to the collection, only the given batches, which no longer belong to any version, are deleted
+  async fn purge_retired_collection(&self, dataset_id: ObjectId, collection_name: &str, batch_ids: &[ObjectId]) {
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let dset = datasets.find_one(doc! { "_id": dataset_id }).await.ok().flatten();
+    if dset.as_ref().map(rows_collection_name).as_deref() == Some(collection_name) {
+      if !batch_ids.is_empty() {
+        let collection: Collection<Document> = self.get_collection(collection_name).await;
+        collection.delete_many(doc! { "dataset_id": dataset_id, "batch_id": { "$in": batch_ids } }).await.ok();
+      }
+    } else {
+      self.delete_dataset_rows(dataset_id, collection_name).await;
+    }
+    if dset.is_some() {
+      datasets.update_one(doc! { "_id": dataset_id }, doc! { "$pull": { "retired_collections": collection_name } }).await.ok();
+    }
+  }
+
+  async fn discard_batch(&self, collection_name: &str, batch_id: ObjectId) {
+    let collection: Collection<Document> = self.get_collection(collection_name).await;
+    collection.delete_many(doc! { "batch_id": batch_id }).await.ok();
+  }
+
+  async fn apply_swap(&self, swap: &ImportSwap, mut session: Option<&mut ClientSession>) -> mongodb::error::Result<bool> {
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    match &swap.write {
+      DatasetWrite::Insert(record) => {
+        match session.as_deref_mut() {
+          Some(s) => datasets.insert_one(record).session(s).await?,
+          None => datasets.insert_one(record).await?,
+        };
+      },
+      DatasetWrite::Update(filter, update) => {
+        let result = match session.as_deref_mut() {
+          Some(s) => datasets.update_one(filter.clone(), update.clone()).session(s).await?,
+          None => datasets.update_one(filter.clone(), update.clone()).await?,
+        };
+        // another import published first
+        if result.matched_count < 1 {
+          return Ok(false);
+        }
+      },
+    }
+    for (collection_name, filter) in swap.cleanup.iter() {
+      let collection: Collection<Document> = self.get_collection(collection_name).await;
+      match session.as_deref_mut() {
+        Some(s) => collection.delete_many(filter.clone()).session(s).await?,
+        None => collection.delete_many(filter.clone()).await?,
+      };
+    }
+    Ok(true)
+  }
+
+  async fn swap_in_transaction(&self, swap: &ImportSwap) -> mongodb::error::Result<bool> {
+    let client = self.client.lock().await.clone();
+    let mut session = client.start_session().await?;
+    session.start_transaction().await?;
+    match self.apply_swap(swap, Some(&mut session)).await {
+      Ok(true) => {
+        session.commit_transaction().await?;
+        Ok(true)
+      },
+      result => {
+        session.abort_transaction().await.ok();
+        result
+      }
+    }
+  }
+
+  /// Copy the rows of live batches not superseded by an incoming key into a new batch.
+  /// Earlier batches are left intact so the versions they belong to can still be restored.
+  async fn copy_rows_to_batch(
+    &self,
+    collection_name: &str,
+    dataset_id: ObjectId,
+    batches: &[Document],
+    data_pk: &str,
+    keys: &[Bson],
+    batch_id: ObjectId,
+  ) -> mongodb::error::Result<u64> {
+    let collection: Collection<Document> = self.get_collection(collection_name).await;
+    let criteria = doc! { "dataset_id": dataset_id, "batch_id": { "$in": batch_ids(batches) }, format!("data.{}", data_pk): { "$nin": keys } };
+    let mut cursor = collection.find(criteria).await?;
+    let mut chunk: Vec<Document> = vec![];
+    let mut num_copied: u64 = 0;
+    while let Some(row) = cursor.next().await {
+      let mut row = row?;
+      row.insert("_id", ObjectId::new());
+      row.insert("batch_id", batch_id);
+      chunk.push(row);
+      if chunk.len() as i64 >= MIGRATION_BATCH_SIZE {
+        collection.insert_many(&chunk).await?;
+        num_copied += chunk.len() as u64;
+        chunk.clear();
+      }
+    }
+    if !chunk.is_empty() {
+      collection.insert_many(&chunk).await?;
+      num_copied += chunk.len() as u64;
+    }
+    Ok(num_copied)
+  }
+
+  /// Stage the rows of an import, then publish them by updating the dataset, inside a transaction when the
+  /// deployment supports them. Readers never see a partially replaced dataset and a failed import leaves the
+  /// previous rows in place.
+  pub async fn save_import_with_rows(
+    &self,
+    options: &Value,
+    rows: &[Value],
+    schema: &[ColumnSchema],
+    import_id_opt: Option<String>,
+    append: bool,
+  ) -> Option<(String, String, usize)> {
+    let fname = options["filename"].as_str().unwrap_or_default().to_owned();
+    let s_index = options["sheet_index"].as_u64().unwrap_or(0) as u32;
+    let matcher = match options["dataset_id"].as_str() {
+      Some(dataset_id) => DataSetMatcher::from_id(dataset_id),
+      None => DataSetMatcher::from_name_index(&fname, s_index),
+    };
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let existing = datasets.find_one(matcher.to_criteria()).await.ok().flatten();
+    let import_ref = import_id_opt.and_then(|id| ObjectId::from_str(&id).ok()).filter(|id| {
+      existing
+        .as_ref()
+        .and_then(|d| d.get_array("imports").ok())
+        .map(|imports| imports.iter().any(|imp| imp.as_document().and_then(extra_id_from_doc) == Some(*id)))
+        .unwrap_or(false)
+    });
+    let replace_mode = ReplaceMode::new(append, import_ref.is_some());
+    let dataset_id = existing.as_ref().and_then(extra_id_from_doc).unwrap_or_else(ObjectId::new);
+    let import_id = import_ref.unwrap_or_default();
+    let batch_id = ObjectId::new();
+    let current_collection = existing.as_ref().map(rows_collection_name).unwrap_or(SHARED_ROWS_COLLECTION.to_string());
+    let draft = is_draft(options);
+    // drafts are staged alongside the live rows, so they stay in the current collection
+    let collection_name = match draft {
+      true => current_collection.clone(),
+      false => select_rows_collection(&dataset_id, &current_collection, options, rows.len(), &replace_mode),
+    };
+    let previous = match &existing {
+      Some(dset) => self.adopt_legacy_rows(dset, &current_collection).await,
+      None => vec![],
+    };
+
+    // stage the new rows, invisible to readers until their batch is live
+    let timezone = import_timezone(options);
+    let data_pk = options["data_pk"].as_str().map(|pk| pk.to_owned());
+    let mut docs = rows
+      .iter()
+      .map(|row| {
+        let mut row_doc = build_row_doc(dataset_id, import_id, row, schema, &timezone);
+        row_doc.insert("batch_id", batch_id);
+        row_doc
+      })
+      .collect::<Vec<Document>>();
+    if let Some(pk) = &data_pk {
+      docs = dedupe_by_key(docs, pk);
+    }
+    if collection_name != current_collection {
+      self.ensure_rows_indexes(&collection_name).await;
+      // recreate declared column indexes in the new collection
+      let declared = existing.as_ref().and_then(|d| d.get_array("indexes").ok()).cloned().unwrap_or_default();
+      for field in declared.iter().filter_map(|f| f.as_str()) {
+        self.create_column_index(&collection_name, field).await;
+      }
+    }
+    let had_batches = existing.as_ref().map(|d| d.contains_key("live_batches")).unwrap_or(false);
+    let (_, mut live) = split_batches(previous.clone(), &replace_mode, import_id);
+    let mut num_rows = docs.len() as i64;
+    // keyed imports replace rows sharing a key, so the rows they keep are copied into the new batch
+    if let (Some(pk), true) = (&data_pk, !live.is_empty()) {
+      let keys = docs.iter().filter_map(|d| d.get_document("data").ok().and_then(|data| data.get(pk)).cloned()).collect::<Vec<Bson>>();
+      match self.copy_rows_to_batch(&collection_name, dataset_id, &live, pk, &keys, batch_id).await {
+        Ok(num_copied) => num_rows += num_copied as i64,
+        Err(e) => {
+          println!("Failed to stage import rows: {}", e);
+          self.discard_batch(&collection_name, batch_id).await;
+          return None;
+        }
+      }
+      live.clear();
+    }
+    if !docs.is_empty() {
+      let collection: Collection<Document> = self.get_collection(&collection_name).await;
+      if let Err(e) = collection.insert_many(&docs).await {
+        println!("Failed to stage import rows: {}", e);
+        self.discard_batch(&collection_name, batch_id).await;
+        return None;
+      }
+    }
+
+    // publish the batch, keeping the versions of recent imports
+    live.push(doc! { "_id": batch_id, "import_id": import_id, "rows": num_rows });
+    let now = chrono::Utc::now();
+    let schema_bson = bson::to_bson(schema).unwrap_or(Bson::Array(vec![]));
+    let total_rows: i64 = live.iter().map(|b| b.get_i64("rows").unwrap_or(0)).sum();
+    let mut import_record = doc! {
+      "_id": import_id,
+      "dt": now,
+      "filename": &fname,
+      "sheet_index": s_index,
+      "rows": docs.len() as i64,
+      "total_rows": total_rows,
+      "schema": &schema_bson,
+      "batches": &live,
+    };
+    let previous_imports = existing.as_ref().map(import_records).unwrap_or_default();
+    let previous_current = existing.as_ref().and_then(|d| current_import_id(&bson_to_json(&Bson::Document(d.clone()))));
+    import_record.extend(import_source(options));
+    if draft {
+      import_record.insert("draft", true);
+      import_record.insert("options", import_options_doc(options));
+      if let Some(base) = &previous_current {
+        import_record.insert("base_import", base);
+      }
+    }
+    let mut imports = previous_imports.clone();
+    match imports.iter().position(|r| extra_id_from_doc(r) == Some(import_id)) {
+      Some(index) => imports[index] = import_record,
+      None => imports.push(import_record),
+    }
+    let current_import = match (draft, &previous_current) {
+      (true, Some(id)) => id.to_owned(),
+      _ => import_id.to_hex(),
+    };
+    let import_ids = imports
+      .iter()
+      .filter(|r| !is_skipped_run(r.get_str("status").ok()))
+      .filter_map(extra_id_from_doc)
+      .map(|id| id.to_hex())
+      .collect::<Vec<String>>();
+    let retained = retained_import_ids(&import_ids, &current_import, import_history_limit());
+    for record in imports.iter_mut() {
+      let id = extra_id_from_doc(record).map(|id| id.to_hex()).unwrap_or_default();
+      // versions are dropped beyond the history limit or when their rows lived in another collection
+      if !retained.contains(&id) || (collection_name != current_collection && id != import_id.to_hex()) {
+        record.remove("batches");
+      }
+    }
+    let mut set_data = doc! {
+      "options": import_options_doc(options),
+      "schema": schema_bson,
+      "live_batches": &live,
+      "collection": &collection_name,
+      "imports": &imports,
+      "current_import": import_id,
+      "updated_at": now,
+    };
+    if draft {
+      // only the import record is added, the live rows, schema and options change when it is published
+      set_data = doc! { "imports": &imports, "collection": &collection_name, "live_batches": &previous };
+      if existing.is_none() {
+        set_data.insert("options", import_options_doc(options));
+      }
+      if let Some(current) = previous_current.as_ref().and_then(|id| ObjectId::from_str(id).ok()) {
+        set_data.insert("current_import", current);
+      }
+    }
+    let write = if existing.is_some() {
+      // the update only applies if no other import published in the meantime
+      let mut filter = doc! { "_id": dataset_id };
+      if had_batches {
+        filter.insert("live_batches", previous.clone());
+      } else {
+        filter.insert("live_batches", doc! { "$exists": false });
+      }
+      let mut update = doc! { "$set": set_data };
+      if collection_name != current_collection {
+        // the previous collection is emptied later, as readers may have resolved it before this update
+        update.insert("$push", doc! { "retired_collections": &current_collection });
+      }
+      DatasetWrite::Update(filter, update)
+    } else {
+      let mut record = doc! {
+        "_id": dataset_id,
+        "user_ref": options["user_ref"].as_str().unwrap_or_default(),
+        "name": &fname,
+        "title": options["title"].as_str().unwrap_or_default(),
+        "description": options["description"].as_str().unwrap_or_default(),
+        "sheet_index": s_index,
+        "created_at": now,
+      };
+      record.extend(set_data);
+      DatasetWrite::Insert(record)
+    };
+    let mut cleanup: Vec<(String, Document)> = vec![];
+    let mut retired: Vec<ObjectId> = vec![];
+    if collection_name == current_collection {
+      let (retired_live, purged) = unreferenced_batches(&previous, &previous_imports, if draft { &previous } else { &live }, &imports);
+      retired = retired_live;
+      if !purged.is_empty() {
+        cleanup.push((current_collection.clone(), doc! { "dataset_id": dataset_id, "batch_id": { "$in": purged } }));
+      }
+    }
+    let swap = ImportSwap { write, cleanup };
+    let swapped = if self.supports_transactions().await {
+      self.swap_in_transaction(&swap).await
+    } else {
+      self.apply_swap(&swap, None).await
+    };
+    match swapped {
+      Ok(true) => {},
+      Ok(false) => {
+        println!("Import rejected as dataset {} was updated concurrently", dataset_id);
+        self.discard_batch(&collection_name, batch_id).await;
+        return None;
+      },
+      Err(e) => {
+        println!("Failed to publish import: {}", e);
+        self.discard_batch(&collection_name, batch_id).await;
+        return None;
+      }
+    }
+    if !retired.is_empty() {
+      self.remove_retired_batches(&current_collection, dataset_id, retired);
+    }
+    if collection_name != current_collection && existing.is_some() {
+      let moved = referenced_batches(&previous, &previous_imports).into_iter().collect::<Vec<ObjectId>>();
+      self.remove_retired_collection(dataset_id, &current_collection, moved);
+    }
+    if let Some(index_cols) = options["index_cols"].as_array() {
+      let fields = index_cols.iter().filter_map(|f| f.as_str().map(|s| s.to_string())).collect::<Vec<String>>();
+      self.declare_indexes(dataset_id, &fields).await;
+    }
+    invalidate_dataset(&dataset_id.to_string()).await;
+    Some((dataset_id.to_string(), import_id.to_string(), docs.len()))
+  }
+
+  pub async fn list_imports(&self, dataset_id: &str) -> Option<Vec<ImportRecord>> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let dset = datasets.find_one(doc! { "_id": id }).await.ok()??;
+    let current = current_import_id(&bson_to_json(&Bson::Document(dset.clone())));
+    Some(import_records(&dset).iter().map(|record| {
+      ImportRecord::from_json(&bson_to_json(&Bson::Document(record.clone())), current.as_deref(), record.contains_key("batches"))
+    }).collect())
+  }
+
+  /// Make the rows of an earlier import live again. Returns false when its version is no longer kept.
+  pub async fn restore_import(&self, dataset_id: &str, import_id: &str) -> Option<bool> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let imp_id = ObjectId::from_str(import_id).ok()?;
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let dset = datasets.find_one(doc! { "_id": id }).await.ok()??;
+    let record = import_records(&dset).into_iter().find(|r| extra_id_from_doc(r) == Some(imp_id))?;
+    let (Ok(batches), Ok(live)) = (record.get_array("batches"), dset.get_array("live_batches")) else {
+      return Some(false);
+    };
+    let mut set_data = doc! { "live_batches": batches.clone(), "current_import": imp_id, "updated_at": chrono::Utc::now() };
+    if let Ok(schema) = record.get_array("schema") {
+      set_data.insert("schema", schema.clone());
+    }
+    if let Ok(options) = record.get_document("options") {
+      set_data.insert("options", options.clone());
+    }
+    // a single update, so readers switch from one version to the other. Restoring a draft publishes it.
+    let filter = doc! { "_id": id, "live_batches": live.clone(), "imports._id": imp_id };
+    let update = doc! { "$set": set_data, "$unset": { "imports.$.draft": "", "imports.$.options": "", "imports.$.base_import": "" } };
+    let result = datasets.update_one(filter, update).await.ok()?;
+    if result.matched_count < 1 {
+      return Some(false);
+    }
+    invalidate_dataset(dataset_id).await;
+    Some(true)
+  }
+
+  pub async fn fetch_import_rows(&self, dataset_id: &str, import_id: &str) -> Option<Vec<Value>> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let imp_id = ObjectId::from_str(import_id).ok()?;
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let dset = datasets.find_one(doc! { "_id": id }).await.ok()??;
+    let record = import_records(&dset).into_iter().find(|r| extra_id_from_doc(r) == Some(imp_id))?;
+    let batches = record.get_array("batches").ok()?.iter().filter_map(|b| b.as_document().cloned()).collect::<Vec<Document>>();
+    let collection: Collection<Document> = self.get_collection(&rows_collection_name(&dset)).await;
+    let criteria = doc! { "dataset_id": id, "batch_id": { "$in": batch_ids(&batches) } };
+    let mut cursor = collection.find(criteria).sort(doc! { "_id": 1 }).await.ok()?;
+    let mut rows: Vec<Value> = vec![];
+    while let Some(Ok(row)) = cursor.next().await {
+      if let Ok(data) = row.get_document("data") {
+        rows.push(bson_to_json(&Bson::Document(data.to_owned())));
+      }
+    }
+    Some(rows)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_live_rows_criteria() {
+    let dataset_id = ObjectId::new();
+    let legacy = doc! { "_id": dataset_id, "name": "stock.xlsx" };
+    assert_eq!(live_rows_criteria(&legacy), doc! { "dataset_id": dataset_id });
+    let batch_id = ObjectId::new();
+    let current = doc! { "_id": dataset_id, "live_batches": [{ "_id": batch_id, "import_id": ObjectId::new() }] };
+    assert_eq!(live_rows_criteria(&current), doc! { "dataset_id": dataset_id, "batch_id": { "$in": [batch_id] } });    assert!(retired_collections(&current).is_empty());
+    let moved = doc! { "_id": dataset_id, "collection": "data_rows", "retired_collections": [format!("rows_{}", dataset_id)] };
+    assert_eq!(retired_collections(&moved), vec![dedicated_collection_name(&dataset_id)]);
+  }
+
+  #[test]
+  fn test_split_batches_and_dedupe() {
+    let (first, second) = (ObjectId::new(), ObjectId::new());
+    let previous = vec![doc! { "_id": ObjectId::new(), "import_id": first }, doc! { "_id": ObjectId::new(), "import_id": second }];
+    let (retired, kept) = split_batches(previous.clone(), &ReplaceMode::ReplaceImport, second);
+    assert_eq!((retired.len(), kept.len()), (1, 1));
+    assert_eq!(kept[0].get_object_id("import_id").unwrap(), first);
+    assert_eq!(split_batches(previous.clone(), &ReplaceMode::ReplaceAll, second).0.len(), 2);
+    assert_eq!(split_batches(previous, &ReplaceMode::Append, second).1.len(), 2);
+    // without history the previous live batch is retired rather than purged with the swap
+    let (old_batch, live_batch, new_batch) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+    let previous_imports = vec![doc! { "_id": first, "batches": [{ "_id": old_batch }] }];
+    let (retired, purged) = unreferenced_batches(&[doc! { "_id": live_batch }], &previous_imports, &[doc! { "_id": new_batch }], &[]);
+    assert_eq!((retired, purged), (vec![live_batch], vec![old_batch]));
+    let docs = vec![
+      doc! { "data": { "sku": "A1", "qty": 1 } },
+      doc! { "data": { "sku": "B2", "qty": 2 } },
+      doc! { "data": { "sku": "A1", "qty": 3 } },
+    ];
+    let unique = dedupe_by_key(docs, "sku");
+    assert_eq!(unique.len(), 2);
+    assert_eq!(unique[1], doc! { "data": { "sku": "A1", "qty": 3 } });
+  }
+
+  #[test]
+  fn test_retained_import_ids() {
+    let ids = ["a", "b", "c", "d"].iter().map(|id| id.to_string()).collect::<Vec<String>>();
+    assert_eq!(retained_import_ids(&ids, "d", 2), vec!["b", "c", "d"]);
+    // a restored earlier import stays restorable
+    assert_eq!(retained_import_ids(&ids, "a", 1), vec!["c", "d", "a"]);
+    assert_eq!(retained_import_ids(&ids, "d", 0), vec!["d"]);
+    let record = json!({ "_id": "d", "dt": "2024-05-01T10:00:00.000Z", "filename": "stock.xlsx", "sheet_index": 0, "rows": 12, "total_rows": 40 });
+    let item = ImportRecord::from_json(&record, Some("d"), true);
+    assert!(item.current && item.restorable);
+    assert_eq!((item.rows, item.total_rows), (Some(12), Some(40)));
+  }
+
+  #[test]
+  fn test_skipped_runs() {
+    let refresh = json!({ "status": "failed", "message": "Failed to download the remote file", "source_url": "https://example.com/stock.csv" });
+    let record = skipped_run_record(&refresh).unwrap();
+    assert_eq!((record.get_str("trigger"), record.get_str("status")), (Ok("schedule"), Ok("failed")));
+    assert!(skipped_run_record(&json!({ "status": "imported" })).is_none());
+    let mut statuses = vec![Some("unchanged"); MAX_SKIPPED_RUNS + 2];
+    statuses.insert(1, None);
+    assert_eq!(excess_skipped_runs(&statuses, |status| *status), vec![0, 2]);
+    // the latest import is current, not the runs after it
+    let dset = json!({ "imports": [{ "_id": "a" }, { "_id": "b", "status": "unchanged" }] });
+    assert_eq!(current_import_id(&dset), Some("a".to_string()));
+  }
+}
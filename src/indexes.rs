@@ -0,0 +1,126 @@
+use bson::{doc, oid::ObjectId, Document};
+use mongodb::{options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::db::{rows_collection_name, SHARED_ROWS_COLLECTION, DB};
+
+/// Index state of a column declared on a dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnIndex {
+  pub field: String,
+  pub name: String,
+  pub exists: bool,
+}
+
+/// Column indexes are compound with `dataset_id`. In the shared `data_rows` collection they serve all datasets
+/// declaring the same field, which keeps the number of indexes well below MongoDB's limit of 64 per collection.
+pub fn column_index_name(field: &str) -> String {
+  format!("dataset_id_1_data.{}_1", field)
+}
+
+pub fn is_valid_field(field: &str) -> bool {
+  !field.is_empty() && !field.starts_with('$') && field.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+impl DB {
+  /// Ensure the indexes every dataset query relies on in the shared rows collection
+  pub async fn ensure_core_indexes(&self) -> bool {
+    self.ensure_rows_indexes(SHARED_ROWS_COLLECTION).await
+  }
+
+  pub async fn ensure_rows_indexes(&self, collection_name: &str) -> bool {
+    let collection: Collection<Document> = self.get_collection(collection_name).await;
+    let models = vec![
+      IndexModel::builder().keys(doc! { "dataset_id": 1 }).build(),
+      IndexModel::builder().keys(doc! { "import_id": 1 }).build(),
+      IndexModel::builder().keys(doc! { "batch_id": 1 }).build(),
+    ];
+    match collection.create_indexes(models).await {
+      Ok(_) => true,
+      Err(e) => {
+        println!("Failed to create core indexes: {}", e);
+        false
+      }
+    }
+  }
+
+  pub async fn create_column_index(&self, collection_name: &str, field: &str) -> bool {
+    let collection: Collection<Document> = self.get_collection(collection_name).await;
+    let options = IndexOptions::builder().name(column_index_name(field)).build();
+    let model = IndexModel::builder()
+      .keys(doc! { "dataset_id": 1, format!("data.{}", field): 1 })
+      .options(options)
+      .build();
+    collection.create_index(model).await.is_ok()
+  }
+
+  /// Declare indexed columns on a dataset and create any missing indexes
+  pub async fn declare_indexes(&self, dataset_id: ObjectId, fields: &[String]) -> Vec<String> {
+    let valid_fields = fields.iter().map(|f| f.trim().to_string()).filter(|f| is_valid_field(f)).collect::<Vec<String>>();
+    let collection_name = self.dataset_rows_collection(dataset_id).await;
+    let mut created: Vec<String> = vec![];
+    for field in valid_fields.iter() {
+      if self.create_column_index(&collection_name, field).await {
+        created.push(field.to_owned());
+      }
+    }
+    if !created.is_empty() {
+      let datasets: Collection<Document> = self.get_collection("datasets").await;
+      let update = doc! { "$addToSet": { "indexes": { "$each": &created } } };
+      datasets.update_one(doc! { "_id": dataset_id }, update).await.ok();
+    }
+    created
+  }
+
+  /// Columns declared as indexed on a dataset with the state of their indexes
+  pub async fn list_dataset_indexes(&self, dataset_id: &str) -> Option<Vec<ColumnIndex>> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let dset = self.find_records("datasets", 1, 0, Some(doc! { "_id": id }), Some(vec!["indexes", "collection"]), None).await.into_iter().next()?;
+    let collection: Collection<Document> = self.get_collection(&rows_collection_name(&dset)).await;
+    let names = collection.list_index_names().await.unwrap_or_default();
+    let fields = dset.get_array("indexes").map(|items| items.iter().filter_map(|f| f.as_str().map(|s| s.to_string())).collect::<Vec<String>>()).unwrap_or_default();
+    Some(fields.iter().map(|field| {
+      let name = column_index_name(field);
+      ColumnIndex {
+        field: field.to_owned(),
+        exists: names.contains(&name),
+        name,
+      }
+    }).collect())
+  }
+
+  /// Remove a declared index from a dataset. In the shared collection the index itself is only dropped
+  /// when no other dataset declares it.
+  pub async fn drop_dataset_index(&self, dataset_id: &str, field: &str) -> Option<bool> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let datasets: Collection<Document> = self.get_collection("datasets").await;
+    let result = datasets.update_one(doc! { "_id": id, "indexes": field }, doc! { "$pull": { "indexes": field } }).await.ok()?;
+    if result.matched_count < 1 {
+      return None;
+    }
+    let collection_name = self.dataset_rows_collection(id).await;
+    if collection_name == SHARED_ROWS_COLLECTION {
+      let shared_criteria = doc! { "indexes": field, "$or": [{ "collection": { "$exists": false } }, { "collection": SHARED_ROWS_COLLECTION }] };
+      if datasets.count_documents(shared_criteria).await.unwrap_or(0) > 0 {
+        return Some(false);
+      }
+    }
+    let collection: Collection<Document> = self.get_collection(&collection_name).await;
+    Some(collection.drop_index(column_index_name(field)).await.is_ok())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_index_field_validation() {
+    assert!(is_valid_field("unit_price"));
+    assert!(is_valid_field("address.city"));
+    assert!(!is_valid_field("$where"));
+    assert!(!is_valid_field("name, id"));
+    assert_eq!(column_index_name("sku"), "dataset_id_1_data.sku_1");
+  }
+}
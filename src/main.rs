@@ -1,16 +1,35 @@
+#![recursion_limit = "256"]
+
 use axum::{
     extract::DefaultBodyLimit,
     http::Method,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
 Router,
 };
 use options::get_max_body_size;
+use refresh::run_scheduler;
+use storage::load_storage_instance;
 use tower_http::cors::{Any, CorsLayer};
 
+mod cache;
+mod columnar;
+mod dates;
 mod db;
+mod dialects;
+mod documents;
+mod diffs;
 mod files;
+mod headers;
+mod imports;
+mod indexes;
+mod memory;
 mod options;
+mod postgres;
+mod refresh;
 mod routes;
+mod schema;
+mod storage;
+mod workbooks;
 
 use routes::*;
 
@@ -23,15 +42,38 @@ async fn main() -> Result<(), std::io::Error> {
         Method::PUT,
         Method::DELETE,
     ]);
+    let storage = match load_storage_instance().await {
+        Ok(storage) => storage,
+        Err(message) => {
+            println!("Failed to start: {}", message);
+            return Err(std::io::Error::other(message));
+        }
+    };
+    tokio::spawn(async move {
+        storage.init().await;
+    });
+    tokio::spawn(run_scheduler());
     let max_body_size = get_max_body_size();
     let app = Router::new()
         .route("/", get(welcome))
         .route("/upload", post(upload_asset))
+        .route("/import-url", post(import_url))
         .route("/process", put(process_asset))
         .route("/check-file/:file_name", get(check_file))
+        .route("/inspect/:file_name", get(inspect_file))
         .route("/dataset/:id", get(get_dataset))
-        .route("/datasets/:id", get(get_dataset))
+        .route("/datasets/:id", get(get_dataset).delete(delete_dataset))
+        .route("/datasets/:id/schema", get(get_dataset_schema))
+        .route("/datasets/:id/schema/migrations", post(migrate_dataset_schema))
+        .route("/datasets/:id/indexes", get(list_dataset_indexes).post(add_dataset_indexes))
+        .route("/datasets/:id/indexes/:field", delete(drop_dataset_index))
+        .route("/datasets/:id/imports", get(list_dataset_imports))
+        .route("/datasets/:id/imports/:import_id/restore", post(restore_dataset_import))
+        .route("/datasets/:id/imports/:import_id/publish", post(publish_dataset_import))
+        .route("/datasets/:id/diff", get(diff_dataset_imports))
         .route("/datasets", get(list_datasets))
+        .route("/workbooks", get(list_workbooks))
+        .route("/workbooks/:id", get(get_workbook).delete(delete_workbook))
         // The default axum body size limit is 2MiB, so we increase it to 1GiB.
         .layer(DefaultBodyLimit::max(max_body_size))
         .layer(cors)
@@ -0,0 +1,785 @@
+use async_trait::async_trait;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use regex::RegexBuilder;
+use serde_json::{json, Value};
+use serde_with::chrono::{self, DateTime, Utc};
+use std::cmp::Ordering;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::cache::invalidate_dataset;
+use crate::db::{bson_to_json, build_row_doc, extra_id_from_doc, import_options_doc, import_timezone, RowSet};
+use crate::imports::{
+  current_import_id, excess_skipped_runs, import_history_limit, import_records, import_source, is_draft, is_skipped_run, retained_import_ids, skipped_run_record,
+  ImportRecord,
+};
+use crate::indexes::{column_index_name, is_valid_field, ColumnIndex};
+use crate::options::{DataSetMatcher, ReplaceMode};
+use crate::schema::{build_schema, ColumnSchema, SchemaMigration};
+use crate::storage::Storage;
+use crate::workbooks::WorkbookRecord;
+
+/// Rows of a dataset as they were after an import
+struct Snapshot {
+  dataset_id: ObjectId,
+  import_id: ObjectId,
+  rows: Vec<Document>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+  datasets: Vec<Document>,
+  rows: Vec<Document>,
+  snapshots: Vec<Snapshot>,
+  workbooks: Vec<WorkbookRecord>,
+}
+
+/// Non-persistent backend for local development and tests. Datasets and rows are kept
+/// as BSON documents, so the same criteria documents apply as with MongoDB.
+pub struct MemoryStorage {
+  data: RwLock<MemoryData>,
+}
+
+impl MemoryStorage {
+  pub fn new() -> Self {
+    Self { data: RwLock::new(MemoryData::default()) }
+  }
+}
+
+/// Resolve a dotted path, collecting values from arrays of subdocuments as MongoDB does
+fn lookup_values<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+  let (head, rest) = match path.split_once('.') {
+    Some((head, rest)) => (head, Some(rest)),
+    None => (path, None),
+  };
+  match (doc.get(head), rest) {
+    (None, _) => vec![],
+    (Some(value), None) => vec![value],
+    (Some(Bson::Document(inner)), Some(rest)) => lookup_values(inner, rest),
+    (Some(Bson::Array(items)), Some(rest)) => items
+      .iter()
+      .filter_map(|item| item.as_document())
+      .flat_map(|inner| lookup_values(inner, rest))
+      .collect(),
+    _ => vec![],
+  }
+}
+
+fn to_f64(value: &Bson) -> Option<f64> {
+  match value {
+    Bson::Double(n) => Some(*n),
+    Bson::Int32(n) => Some(*n as f64),
+    Bson::Int64(n) => Some(*n as f64),
+    _ => None,
+  }
+}
+
+/// Compare values of compatible types. Numbers of any width compare with each other.
+pub fn compare_bson(a: &Bson, b: &Bson) -> Option<Ordering> {
+  if let (Some(x), Some(y)) = (to_f64(a), to_f64(b)) {
+    return x.partial_cmp(&y);
+  }
+  match (a, b) {
+    (Bson::String(x), Bson::String(y)) => Some(x.cmp(y)),
+    (Bson::DateTime(x), Bson::DateTime(y)) => Some(x.cmp(y)),
+    (Bson::Boolean(x), Bson::Boolean(y)) => Some(x.cmp(y)),
+    (Bson::ObjectId(x), Bson::ObjectId(y)) => Some(x.cmp(y)),
+    (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+    _ => None,
+  }
+}
+
+fn bson_equals(a: &Bson, b: &Bson) -> bool {
+  compare_bson(a, b) == Some(Ordering::Equal)
+}
+
+fn value_matches_regex(value: &Bson, pattern: &str, options: &str) -> bool {
+  let regex = RegexBuilder::new(pattern).case_insensitive(options.contains('i')).build();
+  match (regex, value) {
+    (Ok(re), Bson::String(text)) => re.is_match(text),
+    _ => false,
+  }
+}
+
+fn matches_operator(values: &[&Bson], op: &str, arg: &Bson, condition: &Document) -> bool {
+  match op {
+    "$eq" => values.iter().any(|v| bson_equals(v, arg)),
+    "$ne" => !values.iter().any(|v| bson_equals(v, arg)),
+    "$gt" => values.iter().any(|v| compare_bson(v, arg) == Some(Ordering::Greater)),
+    "$gte" => values.iter().any(|v| matches!(compare_bson(v, arg), Some(Ordering::Greater | Ordering::Equal))),
+    "$lt" => values.iter().any(|v| compare_bson(v, arg) == Some(Ordering::Less)),
+    "$lte" => values.iter().any(|v| matches!(compare_bson(v, arg), Some(Ordering::Less | Ordering::Equal))),
+    "$in" | "$nin" => {
+      let options = arg.as_array().cloned().unwrap_or_default();
+      let found = values.iter().any(|v| options.iter().any(|o| bson_equals(v, o)));
+      found == (op == "$in")
+    },
+    "$regex" => {
+      let pattern = arg.as_str().unwrap_or_default();
+      let options = condition.get_str("$options").unwrap_or_default();
+      values.iter().any(|v| value_matches_regex(v, pattern, options))
+    },
+    "$options" => true,
+    "$exists" => values.is_empty() != arg.as_bool().unwrap_or(true),
+    "$not" => match arg.as_document() {
+      Some(inner) => !matches_condition(values, inner),
+      None => true,
+    },
+    _ => false,
+  }
+}
+
+fn matches_condition(values: &[&Bson], condition: &Document) -> bool {
+  condition.iter().all(|(op, arg)| matches_operator(values, op, arg, condition))
+}
+
+/// Evaluate the subset of MongoDB query syntax generated by the filter and search parameters
+pub fn matches_criteria(doc: &Document, criteria: &Document) -> bool {
+  criteria.iter().all(|(key, expected)| match key.as_str() {
+    "$or" => expected.as_array().map(|items| items.iter().filter_map(|c| c.as_document()).any(|c| matches_criteria(doc, c))).unwrap_or(false),
+    "$and" => expected.as_array().map(|items| items.iter().filter_map(|c| c.as_document()).all(|c| matches_criteria(doc, c))).unwrap_or(false),
+    _ => {
+      let values = lookup_values(doc, key);
+      match expected {
+        Bson::Document(condition) if condition.keys().all(|k| k.starts_with('$')) => matches_condition(&values, condition),
+        _ => values.iter().any(|v| bson_equals(v, expected) || v.as_array().map(|items| items.iter().any(|i| bson_equals(i, expected))).unwrap_or(false)),
+      }
+    }
+  })
+}
+
+fn sort_documents(docs: &mut [Document], sort_criteria: &Document) {
+  docs.sort_by(|a, b| {
+    for (key, dir) in sort_criteria.iter() {
+      let descending = to_f64(dir).map(|d| d < 0.0).unwrap_or(false);
+      let ord = match (lookup_values(a, key).first(), lookup_values(b, key).first()) {
+        (Some(x), Some(y)) => compare_bson(x, y).unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => Ordering::Equal,
+      };
+      if ord != Ordering::Equal {
+        return if descending { ord.reverse() } else { ord };
+      }
+    }
+    Ordering::Equal
+  });
+}
+
+fn paginate(docs: Vec<Document>, limit: u64, skip: u64) -> Vec<Document> {
+  docs.into_iter().skip(skip as usize).take(limit as usize).collect()
+}
+
+impl MemoryStorage {
+  fn find_dataset(&self, dataset_id: &str) -> Option<Document> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let data = self.data.read().ok()?;
+    data.datasets.iter().find(|d| extra_id_from_doc(d) == Some(id)).cloned()
+  }
+
+  fn update_dataset<F: FnOnce(&mut Document)>(&self, dataset_id: ObjectId, update: F) -> bool {
+    if let Ok(mut data) = self.data.write() {
+      if let Some(dset) = data.datasets.iter_mut().find(|d| extra_id_from_doc(d) == Some(dataset_id)) {
+        update(dset);
+        return true;
+      }
+    }
+    false
+  }
+
+  fn save_import_sync(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Option<(ObjectId, ObjectId, usize)> {
+    let fname = options["filename"].as_str().unwrap_or_default().to_owned();
+    let s_index = options["sheet_index"].as_u64().unwrap_or(0) as u32;
+    let matcher = match options["dataset_id"].as_str() {
+      Some(dataset_id) => DataSetMatcher::from_id(dataset_id),
+      None => DataSetMatcher::from_name_index(&fname, s_index),
+    };
+    let criteria = matcher.to_criteria();
+    let now = chrono::Utc::now();
+    let mut data = self.data.write().ok()?;
+    let dataset_id = match data.datasets.iter().find(|d| matches_criteria(d, &criteria)).and_then(extra_id_from_doc) {
+      Some(id) => id,
+      None => {
+        let dataset_id = ObjectId::new();
+        data.datasets.push(doc! {
+          "_id": dataset_id,
+          "user_ref": options["user_ref"].as_str().unwrap_or_default(),
+          "name": &fname,
+          "title": options["title"].as_str().unwrap_or_default(),
+          "description": options["description"].as_str().unwrap_or_default(),
+          "sheet_index": s_index,
+          "imports": [],
+          "created_at": now
+        });
+        dataset_id
+      }
+    };
+    let dset_index = data.datasets.iter().position(|d| extra_id_from_doc(d) == Some(dataset_id))?;
+    let mut imports = import_records(&data.datasets[dset_index]);
+    let import_ref = import_id_opt.and_then(|id| ObjectId::from_str(&id).ok()).filter(|id| imports.iter().any(|r| extra_id_from_doc(r) == Some(*id)));
+    let replace_mode = ReplaceMode::new(append, import_ref.is_some());
+    let import_id = import_ref.unwrap_or_default();
+    let draft = is_draft(options);
+    // the import is applied to a copy of the dataset's rows, which replaces them unless it is a draft
+    let mut dataset_rows = data.rows.iter().filter(|r| r.get_object_id("dataset_id").ok() == Some(dataset_id)).cloned().collect::<Vec<Document>>();
+    match replace_mode {
+      ReplaceMode::ReplaceAll => dataset_rows.clear(),
+      ReplaceMode::ReplaceImport => dataset_rows.retain(|r| r.get_object_id("import_id").ok() != Some(import_id)),
+      ReplaceMode::Append => {}
+    }
+    let timezone = import_timezone(options);
+    let data_pk = options["data_pk"].as_str().map(|pk| pk.to_owned());
+    for row in rows {
+      let row_doc = build_row_doc(dataset_id, import_id, row, schema, &timezone);
+      let pk_value = data_pk.as_ref().and_then(|pk| row_doc.get_document("data").ok().and_then(|d| d.get(pk)).cloned());
+      let existing_row = pk_value.and_then(|pk_val| {
+        let pk = data_pk.clone().unwrap_or_default();
+        dataset_rows.iter().position(|r| r.get_document("data").ok().and_then(|d| d.get(&pk)).map(|v| bson_equals(v, &pk_val)).unwrap_or(false))
+      });
+      match existing_row {
+        Some(index) => {
+          let row_id = dataset_rows[index].get("_id").cloned().unwrap_or(Bson::ObjectId(ObjectId::new()));
+          let mut replacement = row_doc;
+          replacement.insert("_id", row_id);
+          dataset_rows[index] = replacement;
+        },
+        None => {
+          let mut new_row = doc! { "_id": ObjectId::new() };
+          new_row.extend(row_doc);
+          dataset_rows.push(new_row);
+        }
+      }
+    }
+    let schema_bson = bson::to_bson(schema).unwrap_or(Bson::Array(vec![]));
+    let mut import_record = doc! {
+      "_id": import_id,
+      "dt": now,
+      "filename": &fname,
+      "sheet_index": s_index,
+      "rows": rows.len() as i64,
+      "total_rows": dataset_rows.len() as i64,
+      "schema": &schema_bson,
+    };
+    let previous_current = current_import_id(&bson_to_json(&Bson::Document(data.datasets[dset_index].clone())));
+    import_record.extend(import_source(options));
+    if draft {
+      import_record.insert("draft", true);
+      import_record.insert("options", import_options_doc(options));
+      if let Some(base) = &previous_current {
+        import_record.insert("base_import", base);
+      }
+    }
+    match imports.iter().position(|r| extra_id_from_doc(r) == Some(import_id)) {
+      Some(index) => imports[index] = import_record,
+      None => imports.push(import_record),
+    }
+    let current_import = match (draft, &previous_current) {
+      (true, Some(id)) => id.to_owned(),
+      _ => import_id.to_hex(),
+    };
+    // keep a copy of the rows as the version of this import. Drafts need one to be published.
+    let import_ids = imports
+      .iter()
+      .filter(|r| !is_skipped_run(r.get_str("status").ok()))
+      .filter_map(extra_id_from_doc)
+      .map(|id| id.to_hex())
+      .collect::<Vec<String>>();
+    let retained = retained_import_ids(&import_ids, &current_import, import_history_limit());
+    data.snapshots.retain(|snap| snap.dataset_id != dataset_id || (snap.import_id != import_id && retained.contains(&snap.import_id.to_hex())));
+    if import_history_limit() > 0 || draft {
+      data.snapshots.push(Snapshot { dataset_id, import_id, rows: dataset_rows.clone() });
+    }
+    if !draft {
+      data.rows.retain(|r| r.get_object_id("dataset_id").ok() != Some(dataset_id));
+      data.rows.extend(dataset_rows);
+    }
+    let dset = &mut data.datasets[dset_index];
+    dset.insert("imports", imports);
+    if draft {
+      // the live rows, schema and options change when the draft is published
+      if let Some(current) = previous_current.as_ref().and_then(|id| ObjectId::from_str(id).ok()) {
+        dset.insert("current_import", current);
+      }
+      if !dset.contains_key("options") {
+        dset.insert("options", import_options_doc(options));
+      }
+      return Some((dataset_id, import_id, rows.len()));
+    }
+    dset.insert("current_import", import_id);
+    dset.insert("options", import_options_doc(options));
+    dset.insert("schema", schema_bson);
+    dset.insert("updated_at", now);
+    Some((dataset_id, import_id, rows.len()))
+  }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+  async fn init(&self) -> bool {
+    true
+  }
+
+  async fn fetch_dataset(&self, dataset_id: &str, import_id_opt: Option<String>, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> Option<RowSet> {
+    let dset = self.find_dataset(dataset_id)?;
+    let id = extra_id_from_doc(&dset)?;
+    let mut criteria = filter_options.unwrap_or_default();
+    criteria.insert("dataset_id", id);
+    if let Some(imp_id) = import_id_opt.and_then(|imp| ObjectId::from_str(&imp).ok()) {
+      criteria.insert("import_id", imp_id);
+    }
+    let mut matched = {
+      let data = self.data.read().ok()?;
+      data.rows.iter().filter(|r| matches_criteria(r, &criteria)).cloned().collect::<Vec<Document>>()
+    };
+    if let Some(sort) = sort_criteria {
+      sort_documents(&mut matched, &sort);
+    }
+    let total = matched.len() as u64;
+    let rows = paginate(matched, limit, skip).into_iter().filter_map(|r| r.get_document("data").ok().cloned()).collect::<Vec<Document>>();
+    Some(RowSet::new(&dset, &rows, total, limit, skip))
+  }
+
+  async fn get_datasets(&self, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> (Option<u64>, Vec<Value>) {
+    let criteria = filter_options.unwrap_or_default();
+    let data = match self.data.read() {
+      Ok(data) => data,
+      Err(_) => return (None, vec![]),
+    };
+    let mut dsets = data.datasets.iter().filter(|d| matches_criteria(d, &criteria)).cloned().collect::<Vec<Document>>();
+    if let Some(sort) = sort_criteria {
+      sort_documents(&mut dsets, &sort);
+    }
+    let total = dsets.len() as u64;
+    let datasets = paginate(dsets, limit, skip).iter().map(|dset| {
+      let mut row = bson_to_json(&Bson::Document(dset.to_owned()));
+      let id = extra_id_from_doc(dset);
+      let count = data.rows.iter().filter(|r| r.get_object_id("dataset_id").ok() == id).count();
+      row["row_count"] = json!(count);
+      row
+    }).collect::<Vec<Value>>();
+    (Some(total), datasets)
+  }
+
+  async fn save_import_with_rows(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Option<(String, String, usize)> {
+    let (id, import_id, count) = self.save_import_sync(options, rows, schema, import_id_opt, append)?;
+    if let Some(index_cols) = options["index_cols"].as_array() {
+      let fields = index_cols.iter().filter_map(|f| f.as_str().map(|s| s.to_string())).collect::<Vec<String>>();
+      self.declare_indexes(&id.to_string(), &fields).await;
+    }
+    invalidate_dataset(&id.to_string()).await;
+    Some((id.to_string(), import_id.to_string(), count))
+  }
+
+  async fn delete_dataset(&self, dataset_id: &str) -> Option<u64> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let num_rows = {
+      let mut data = self.data.write().ok()?;
+      let num_datasets = data.datasets.len();
+      data.datasets.retain(|d| extra_id_from_doc(d) != Some(id));
+      if data.datasets.len() == num_datasets {
+        return None;
+      }
+      let num_rows = data.rows.len();
+      data.rows.retain(|r| r.get_object_id("dataset_id").ok() != Some(id));
+      data.snapshots.retain(|snap| snap.dataset_id != id);
+      (num_rows - data.rows.len()) as u64
+    };
+    invalidate_dataset(dataset_id).await;
+    Some(num_rows)
+  }
+
+  async fn record_refresh(&self, dataset_id: &str, refresh: &Value) -> bool {
+    let Ok(id) = ObjectId::from_str(dataset_id) else {
+      return false;
+    };
+    let refresh_bson = bson::to_bson(refresh).unwrap_or(Bson::Null);
+    self.update_dataset(id, |dset| {
+      dset.insert("refresh", refresh_bson);
+      // runs that imported rows are already listed with their import
+      if let Some(record) = skipped_run_record(refresh) {
+        let mut imports = import_records(dset);
+        imports.push(record);
+        for index in excess_skipped_runs(&imports, |r| r.get_str("status").ok()).into_iter().rev() {
+          imports.remove(index);
+        }
+        dset.insert("imports", imports);
+      }
+    })
+  }
+
+  async fn list_imports(&self, dataset_id: &str) -> Option<Vec<ImportRecord>> {
+    let dset = self.find_dataset(dataset_id)?;
+    let id = extra_id_from_doc(&dset)?;
+    let current = current_import_id(&bson_to_json(&Bson::Document(dset.clone())));
+    let data = self.data.read().ok()?;
+    Some(import_records(&dset).iter().map(|record| {
+      let import_id = extra_id_from_doc(record);
+      let restorable = data.snapshots.iter().any(|snap| snap.dataset_id == id && Some(snap.import_id) == import_id);
+      ImportRecord::from_json(&bson_to_json(&Bson::Document(record.clone())), current.as_deref(), restorable)
+    }).collect())
+  }
+
+  async fn restore_import(&self, dataset_id: &str, import_id: &str) -> Option<bool> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let imp_id = ObjectId::from_str(import_id).ok()?;
+    let dset = self.find_dataset(dataset_id)?;
+    let record = import_records(&dset).into_iter().find(|r| extra_id_from_doc(r) == Some(imp_id))?;
+    {
+      let mut data = self.data.write().ok()?;
+      let Some(rows) = data.snapshots.iter().find(|snap| snap.dataset_id == id && snap.import_id == imp_id).map(|snap| snap.rows.clone()) else {
+        return Some(false);
+      };
+      data.rows.retain(|r| r.get_object_id("dataset_id").ok() != Some(id));
+      data.rows.extend(rows);
+    }
+    self.update_dataset(id, |dset| {
+      if let Ok(schema) = record.get_array("schema") {
+        dset.insert("schema", schema.clone());
+      }
+      if let Ok(options) = record.get_document("options") {
+        dset.insert("options", options.clone());
+      }
+      // restoring a draft publishes it
+      if let Ok(imports) = dset.get_array_mut("imports") {
+        for item in imports.iter_mut() {
+          if let Some(rec) = item.as_document_mut().filter(|r| extra_id_from_doc(r) == Some(imp_id)) {
+            rec.remove("draft");
+            rec.remove("options");
+            rec.remove("base_import");
+          }
+        }
+      }
+      dset.insert("current_import", imp_id);
+      dset.insert("updated_at", chrono::Utc::now());
+    });
+    invalidate_dataset(dataset_id).await;
+    Some(true)
+  }
+
+  async fn fetch_import_rows(&self, dataset_id: &str, import_id: &str) -> Option<Vec<Value>> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let imp_id = ObjectId::from_str(import_id).ok()?;
+    let data = self.data.read().ok()?;
+    let snapshot = data.snapshots.iter().find(|snap| snap.dataset_id == id && snap.import_id == imp_id)?;
+    Some(snapshot.rows.iter().filter_map(|r| r.get_document("data").ok()).map(|d| bson_to_json(&Bson::Document(d.to_owned()))).collect())
+  }
+
+  async fn fetch_dataset_schema(&self, dataset_id: &str) -> Option<Vec<ColumnSchema>> {
+    let dset = self.find_dataset(dataset_id)?;
+    if let Ok(schema_items) = dset.get_array("schema") {
+      if let Ok(schema) = bson::from_bson::<Vec<ColumnSchema>>(Bson::Array(schema_items.to_owned())) {
+        return Some(schema);
+      }
+    }
+    let id = extra_id_from_doc(&dset)?;
+    let data = self.data.read().ok()?;
+    let rows = data.rows.iter()
+      .filter(|r| r.get_object_id("dataset_id").ok() == Some(id))
+      .take(100)
+      .filter_map(|r| r.get_document("data").ok())
+      .map(|d| bson_to_json(&Bson::Document(d.to_owned())))
+      .collect::<Vec<Value>>();
+    Some(build_schema(&json!({}), &rows, &[]))
+  }
+
+  async fn migrate_dataset(&self, dataset_id: &str, migration: &SchemaMigration, schema: &[ColumnSchema]) -> Option<u64> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    migration.to_op().ok()?;
+    let num_updated = {
+      let mut data = self.data.write().ok()?;
+      let mut num_updated: u64 = 0;
+      for row in data.rows.iter_mut().filter(|r| r.get_object_id("dataset_id").ok() == Some(id)) {
+        if row.get_document_mut("data").map(|row_data| migration.apply_to_row(row_data)).unwrap_or(false) {
+          num_updated += 1;
+        }
+      }
+      // kept versions are migrated too, so restoring one does not bring back the old column
+      for snapshot in data.snapshots.iter_mut().filter(|snap| snap.dataset_id == id) {
+        for row in snapshot.rows.iter_mut() {
+          if let Ok(row_data) = row.get_document_mut("data") {
+            migration.apply_to_row(row_data);
+          }
+        }
+      }
+      num_updated
+    };
+    let record = doc! {
+      "_id": ObjectId::new(),
+      "dt": chrono::Utc::now(),
+      "op": &migration.op,
+      "key": &migration.key,
+      "to": migration.to.clone(),
+      "data_type": migration.data_type.clone(),
+      "rows": num_updated as i64
+    };
+    self.update_dataset(id, |dset| {
+      dset.insert("schema", bson::to_bson(schema).unwrap_or(Bson::Array(vec![])));
+      if let Ok(imports) = dset.get_array_mut("imports") {
+        for rec in imports.iter_mut().filter_map(|item| item.as_document_mut()) {
+          let migrated = rec.get("schema").and_then(|s| migration.apply_to_import(&bson_to_json(s)));
+          if let Some(import_schema) = migrated.and_then(|s| bson::to_bson(&s).ok()) {
+            rec.insert("schema", import_schema);
+          }
+        }
+      }
+      dset.insert("updated_at", chrono::Utc::now());
+      match dset.get_array_mut("migrations") {
+        Ok(items) => items.push(Bson::Document(record)),
+        Err(_) => {
+          dset.insert("migrations", vec![record]);
+        }
+      }
+    });
+    invalidate_dataset(dataset_id).await;
+    Some(num_updated)
+  }
+
+  async fn fetch_dataset_modified(&self, dataset_id: &str) -> Option<DateTime<Utc>> {
+    let dset = self.find_dataset(dataset_id)?;
+    dset.get_datetime("updated_at").or(dset.get_datetime("created_at")).ok().map(|dt| dt.to_chrono())
+  }
+
+  async fn datasets_modified(&self, filter_options: Option<Document>) -> (Option<DateTime<Utc>>, u64) {
+    let criteria = filter_options.unwrap_or_default();
+    let data = match self.data.read() {
+      Ok(data) => data,
+      Err(_) => return (None, 0),
+    };
+    let matched = data.datasets.iter().filter(|d| matches_criteria(d, &criteria)).collect::<Vec<&Document>>();
+    let modified = matched.iter()
+      .filter_map(|d| d.get_datetime("updated_at").or(d.get_datetime("created_at")).ok())
+      .max()
+      .map(|dt| dt.to_chrono());
+    (modified, matched.len() as u64)
+  }
+
+  async fn declare_indexes(&self, dataset_id: &str, fields: &[String]) -> Vec<String> {
+    let id = match ObjectId::from_str(dataset_id) {
+      Ok(id) => id,
+      Err(_) => return vec![],
+    };
+    let valid_fields = fields.iter().map(|f| f.trim().to_string()).filter(|f| is_valid_field(f)).collect::<Vec<String>>();
+    let declared = valid_fields.clone();
+    let updated = self.update_dataset(id, move |dset| {
+      let mut current = dset.get_array("indexes").cloned().unwrap_or_default();
+      for field in declared {
+        if !current.iter().any(|f| f.as_str() == Some(field.as_str())) {
+          current.push(Bson::String(field));
+        }
+      }
+      dset.insert("indexes", current);
+    });
+    if updated { valid_fields } else { vec![] }
+  }
+
+  async fn list_dataset_indexes(&self, dataset_id: &str) -> Option<Vec<ColumnIndex>> {
+    let dset = self.find_dataset(dataset_id)?;
+    let fields = dset.get_array("indexes").map(|items| items.iter().filter_map(|f| f.as_str().map(|s| s.to_string())).collect::<Vec<String>>()).unwrap_or_default();
+    // rows are scanned in memory, so declared indexes are always considered in place
+    Some(fields.into_iter().map(|field| ColumnIndex { name: column_index_name(&field), field, exists: true }).collect())
+  }
+
+  async fn drop_dataset_index(&self, dataset_id: &str, field: &str) -> Option<bool> {
+    let id = ObjectId::from_str(dataset_id).ok()?;
+    let dset = self.find_dataset(dataset_id)?;
+    let declared = dset.get_array("indexes").map(|items| items.iter().any(|f| f.as_str() == Some(field))).unwrap_or(false);
+    if !declared {
+      return None;
+    }
+    self.update_dataset(id, |dset| {
+      let remaining = dset.get_array("indexes").cloned().unwrap_or_default().into_iter().filter(|f| f.as_str() != Some(field)).collect::<Vec<Bson>>();
+      dset.insert("indexes", remaining);
+    });
+    Some(true)
+  }
+
+  async fn save_workbook(&self, workbook: &WorkbookRecord) -> bool {
+    let Ok(mut data) = self.data.write() else {
+      return false;
+    };
+    data.workbooks.retain(|record| record.id != workbook.id);
+    data.workbooks.push(workbook.clone());
+    true
+  }
+
+  async fn fetch_workbook(&self, workbook_id: &str) -> Option<WorkbookRecord> {
+    let data = self.data.read().ok()?;
+    data.workbooks.iter().find(|record| record.id == workbook_id).cloned()
+  }
+
+  async fn get_workbooks(&self, limit: u64, skip: u64) -> Vec<WorkbookRecord> {
+    let Ok(data) = self.data.read() else {
+      return vec![];
+    };
+    let mut records = data.workbooks.clone();
+    records.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+    records.into_iter().skip(skip as usize).take(limit as usize).collect()
+  }
+
+  async fn delete_workbook(&self, workbook_id: &str) -> bool {
+    let Ok(mut data) = self.data.write() else {
+      return false;
+    };
+    let num_workbooks = data.workbooks.len();
+    data.workbooks.retain(|record| record.id != workbook_id);
+    data.workbooks.len() < num_workbooks
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::options::QueryFilterParams;
+  use crate::imports::is_stale_draft;
+  use crate::documents::flatten_object;
+
+  fn filter_params(f: &str, v: &str, o: &str) -> QueryFilterParams {
+    QueryFilterParams {
+      f: Some(f.to_string()),
+      v: Some(v.to_string()),
+      o: Some(o.to_string()),
+      dt: None,
+      sort: None,
+      dir: None,
+      import: None,
+      start: None,
+      limit: None,
+      q: None,
+      u: None,
+      workbook: None,
+      format: None,
+    }
+  }
+
+  #[test]
+  fn test_matches_criteria() {
+    let row = doc! { "data": { "name": "Widget", "price": 12.5, "qty": 3 }, "tags": [{ "filename": "stock.xlsx" }] };
+    assert!(matches_criteria(&row, &doc! { "data.price": { "$gte": 12, "$lt": 13 } }));
+    assert!(matches_criteria(&row, &doc! { "data.qty": { "$in": [1, 3] } }));
+    assert!(matches_criteria(&row, &doc! { "data.name": { "$regex": "^wid", "$options": "i" } }));
+    assert!(!matches_criteria(&row, &doc! { "data.name": { "$regex": "^wid" } }));
+    assert!(matches_criteria(&row, &doc! { "$or": [{ "data.qty": 4 }, { "tags.filename": { "$regex": "stock" } }] }));
+    assert!(matches_criteria(&row, &doc! { "data.qty": { "$not": { "$gte": 4 } } }));
+    assert!(!matches_criteria(&row, &doc! { "data.colour": { "$exists": true } }));
+  }
+
+  #[tokio::test]
+  async fn test_memory_import_and_fetch() {
+    let storage = MemoryStorage::new();
+    let options = json!({ "filename": "stock.xlsx", "sheet_index": 0, "data_pk": "sku" });
+    let rows = vec![
+      json!({ "sku": "A1", "name": "Widget", "qty": 3 }),
+      json!({ "sku": "B2", "name": "Gadget", "qty": 7 }),
+    ];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, _, count) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    assert_eq!(count, 2);
+    let criteria = filter_params("qty", "5", "gt").to_criteria();
+    let result = storage.fetch_dataset(&id, None, criteria, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 1);
+    assert_eq!(result.rows[0]["sku"], json!("B2"));
+    // appending a row with an existing key replaces it
+    let update = vec![json!({ "sku": "A1", "name": "Widget", "qty": 9 })];
+    let (_, update_id, _) = storage.save_import_with_rows(&options, &update, &schema, None, true).await.unwrap();
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, Some(doc! { "data.qty": -1 })).await.unwrap();
+    assert_eq!(result.total, 2);
+    assert_eq!(result.rows[0]["qty"], json!(9));
+    // rows can be restricted to those written by one import
+    let result = storage.fetch_dataset(&id, Some(update_id), None, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 1);
+    let (total, datasets) = storage.get_datasets(None, 10, 0, None).await;
+    assert_eq!(total, Some(1));
+    assert_eq!(datasets[0]["row_count"], json!(2));
+    // the first version can be restored
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert_eq!(imports.len(), 2);
+    assert!(imports[1].current && imports[0].restorable);
+    assert_eq!(storage.restore_import(&id, &imports[0].import_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, Some(doc! { "data.sku": 1 })).await.unwrap();
+    assert_eq!(result.rows[0]["qty"], json!(3));
+    assert_eq!(storage.delete_dataset(&id).await, Some(2));
+  }
+
+  #[tokio::test]
+  async fn test_memory_draft_import() {
+    let storage = MemoryStorage::new();
+    let options = json!({ "filename": "prices.csv", "sheet_index": 0, "data_pk": "sku" });
+    let rows = vec![json!({ "sku": "A1", "price": 10 })];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, _, _) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    let draft_options = json!({ "filename": "prices.csv", "sheet_index": 0, "data_pk": "sku", "publish": false });
+    let update = vec![json!({ "sku": "A1", "price": 12 }), json!({ "sku": "B2", "price": 5 })];
+    let (_, draft_id, _) = storage.save_import_with_rows(&draft_options, &update, &schema, None, false).await.unwrap();
+    // the draft is kept as a version but the dataset is unchanged
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 1);
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert!(imports[0].current && imports[1].draft);
+    assert_eq!(storage.fetch_import_rows(&id, &draft_id).await.map(|rows| rows.len()), Some(2));
+    assert_eq!(storage.restore_import(&id, &draft_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 2);
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert!(imports[1].current && !imports[1].draft);
+  }
+
+  #[tokio::test]
+  async fn test_memory_stale_draft() {
+    let storage = MemoryStorage::new();
+    let options = json!({ "filename": "prices.csv", "sheet_index": 0, "data_pk": "sku" });
+    let rows = vec![json!({ "sku": "A1", "price": 10 })];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, first_id, _) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    let draft_options = json!({ "filename": "prices.csv", "sheet_index": 0, "data_pk": "sku", "publish": false });
+    let draft_rows = vec![json!({ "sku": "A1", "price": 12 })];
+    let (_, draft_id, _) = storage.save_import_with_rows(&draft_options, &draft_rows, &schema, None, false).await.unwrap();
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert_eq!(imports[1].base_import.as_deref(), Some(first_id.as_str()));
+    assert!(!is_stale_draft(&imports, &draft_id));
+    // publishing the draft now would roll back the import made after it
+    let update = vec![json!({ "sku": "A1", "price": 15 })];
+    storage.save_import_with_rows(&options, &update, &schema, None, false).await.unwrap();
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert!(imports[2].current);
+    assert!(is_stale_draft(&imports, &draft_id));
+    assert!(!is_stale_draft(&imports, &imports[2].import_id));
+  }
+
+  #[tokio::test]
+  async fn test_memory_migrate_and_restore() {
+    let storage = MemoryStorage::new();
+    let options = json!({ "filename": "staff.csv", "sheet_index": 0 });
+    let rows = vec![json!({ "id": 1, "name": "Alpha" })];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, first_id, _) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    let update = vec![json!({ "id": 2, "name": "Beta" })];
+    storage.save_import_with_rows(&options, &update, &schema, None, false).await.unwrap();
+    let migration = SchemaMigration { op: "rename".to_string(), key: "name".to_string(), to: Some("full_name".to_string()), data_type: None };
+    let migrated = migration.apply_to_schema(&schema).unwrap();
+    assert_eq!(storage.migrate_dataset(&id, &migration, &migrated).await, Some(1));
+    // the earlier version comes back with the renamed column
+    assert_eq!(storage.restore_import(&id, &first_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.rows[0], json!({ "id": 1, "full_name": "Alpha" }));
+    let stored = storage.fetch_dataset_schema(&id).await.unwrap();
+    assert_eq!(stored.iter().map(|c| c.key.as_str()).collect::<Vec<&str>>(), vec!["id", "full_name"]);
+  }
+
+  #[tokio::test]
+  async fn test_memory_nested_documents() {
+    let storage = MemoryStorage::new();
+    let options = json!({ "filename": "staff.json", "sheet_index": 0 });
+    let documents = vec![json!({ "id": 1, "address": { "city": "Leeds" }, "joined": { "on": "2024-12-25" } })];
+    let flat = documents.iter().map(flatten_object).collect::<Vec<Value>>();
+    let schema = build_schema(&json!({}), &flat, &[]);
+    assert_eq!(schema[2].key, "joined.on");
+    let (id, _, _) = storage.save_import_with_rows(&options, &documents, &schema, None, false).await.unwrap();
+    let migration = SchemaMigration { op: "rename".to_string(), key: "address.city".to_string(), to: Some("town".to_string()), data_type: None };
+    assert_eq!(storage.migrate_dataset(&id, &migration, &migration.apply_to_schema(&schema).unwrap()).await, Some(1));
+    let data = storage.data.read().unwrap();
+    let row_data = data.rows[0].get_document("data").unwrap();
+    assert_eq!(row_data.get_str("town"), Ok("Leeds"));
+    // date columns of nested documents are stored as dates
+    assert!(row_data.get_document("joined").unwrap().get_datetime("on").is_ok());
+  }
+}
@@ -10,6 +10,9 @@ use axum_typed_multipart::{FieldData, TryFromField, TryFromMultipart, TypedMulti
 use spreadsheet_to_json::{is_truthy::is_truthy_core, simple_string_patterns::{CharType, IsNumeric, SimpleMatch, StripCharacters, ToSegments}};
 use tempfile::NamedTempFile;
 
+use crate::dialects::DialectOptions;
+use crate::refresh::RefreshSchedule;
+
 const DEFAULT_MAX_UPLOAD_SIZE: usize = 50 * 1024 * 1024;
 
 const DEFAULT_MAX_OUTPUT_ROWS: usize = 1000;
@@ -45,7 +48,17 @@ pub struct UploadAssetRequest {
   pub lines: Option<usize>,
   pub cols: Option<String>,
   pub sheet_index: Option<usize>,
+  pub sheet_name: Option<String>,
   pub header_index: Option<usize>,
+  pub header_rows: Option<usize>,
+  pub no_header: Option<bool>,
+  pub delimiter: Option<String>,
+  pub quote: Option<String>,
+  pub escape: Option<String>,
+  pub encoding: Option<String>,
+  pub decimal_comma: Option<bool>,
+  pub thousands: Option<String>,
+  pub range: Option<String>,
 }
 
 impl UploadAssetRequest {
@@ -57,7 +70,12 @@ impl UploadAssetRequest {
     let mut lines: Option<usize> = None;
     let mut cols: Option<String> = None;
     let mut sheet_index: Option<usize> = None;
+    let mut sheet_name: Option<String> = None;
     let mut header_index: Option<usize> = None;
+    let mut header_rows: Option<usize> = None;
+    let mut no_header: Option<bool> = None;
+    let mut range: Option<String> = None;
+    let mut dialect = DialectOptions::default();
 
     while let Some(field) = multipart.next_field().await.unwrap() {
         let name = field.name().unwrap().to_string();
@@ -92,9 +110,39 @@ impl UploadAssetRequest {
             "sheet_index" => {
                 sheet_index = Some(field.text().await.unwrap().parse().unwrap());
             }
+            "sheet_name" => {
+                sheet_name = Some(field.text().await.unwrap());
+            }
             "header_index" => {
                 header_index = Some(field.text().await.unwrap().parse().unwrap());
             }
+            "header_rows" => {
+                header_rows = Some(field.text().await.unwrap().parse().unwrap());
+            }
+            "no_header" => {
+                no_header = is_truthy_core(&field.text().await.unwrap(), false);
+            }
+            "range" => {
+                range = Some(field.text().await.unwrap());
+            }
+            "delimiter" => {
+                dialect.delimiter = Some(field.text().await.unwrap());
+            }
+            "quote" => {
+                dialect.quote = Some(field.text().await.unwrap());
+            }
+            "escape" => {
+                dialect.escape = Some(field.text().await.unwrap());
+            }
+            "encoding" => {
+                dialect.encoding = Some(field.text().await.unwrap());
+            }
+            "decimal_comma" => {
+                dialect.decimal_comma = is_truthy_core(&field.text().await.unwrap(), false);
+            }
+            "thousands" => {
+                dialect.thousands = Some(field.text().await.unwrap());
+            }
             _ => {}
         }
     }
@@ -108,7 +156,17 @@ impl UploadAssetRequest {
           lines,
           cols,
           sheet_index,
+          sheet_name,
           header_index,
+          header_rows,
+          no_header,
+          delimiter: dialect.delimiter,
+          quote: dialect.quote,
+          escape: dialect.escape,
+          encoding: dialect.encoding,
+          decimal_comma: dialect.decimal_comma,
+          thousands: dialect.thousands,
+          range,
         }
       )
     } else {
@@ -119,7 +177,7 @@ impl UploadAssetRequest {
 
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CoreOptions {
   pub filename: Option<String>,
   pub title: Option<String>,
@@ -135,8 +193,25 @@ pub struct CoreOptions {
   // eg. id,name,height:float,width:float,
   pub cols: Option<String>,
   pub sheet_index: Option<usize>,
+  // alternative to sheet_index matched case-insensitively, so re-imports follow a sheet when tabs are reordered
+  pub sheet_name: Option<String>,
   pub header_index: Option<usize>,
-  // refer directly to a dataset, assumed to have the same schema
+  // number of header rows from header_index, joined per column as "Q1 / Revenue". Blank cells extend merged spans
+  pub header_rows: Option<usize>,
+  // the sheet has no header row. Columns are named by keys or generated in key_style: letters (col_a) or numbers (c01)
+  pub no_header: Option<bool>,
+  pub key_style: Option<String>,
+  // CSV and TSV dialect: delimiter, quote and escape characters, text encoding and number format.
+  // Unset values are detected in preview mode
+  pub delimiter: Option<String>,
+  pub quote: Option<String>,
+  pub escape: Option<String>,
+  pub encoding: Option<String>,
+  pub decimal_comma: Option<bool>,
+  pub thousands: Option<String>,
+  // rectangular cell region to read, e.g. B4:H200, with header_index relative to its first row
+  pub range: Option<String>,
+  // refer directly to a dataset, validated against its stored schema according to schema_policy
   pub dataset_id: Option<String>,
   // update within a specific import retaining the same id
   pub import_id: Option<String>,
@@ -144,6 +219,29 @@ pub struct CoreOptions {
   pub append: Option<bool>,
   // JSON lines reserved for future use when exporting data to large files
   pub lines: Option<bool>,
+  // how to treat schema differences when re-importing into an existing dataset: strict (default), additive or lenient
+  pub schema_policy: Option<String>,
+  // fixed offset such as +01:00 or IANA name such as Europe/London applied to datetimes without a timezone. Pure dates are never shifted
+  pub timezone: Option<String>,
+  // comma separated list of column keys to index for filtering and sorting
+  pub index_cols: Option<String>,
+  // shared or dedicated rows collection. By default datasets above DEDICATED_COLLECTION_THRESHOLD rows get their own
+  pub storage: Option<String>,
+  // column key identifying rows across imports. Rows sharing a key are replaced and imports can be compared
+  pub data_pk: Option<String>,
+  // false to store the import as a draft, published later via /datasets/:id/imports/:import_id/publish
+  pub publish: Option<bool>,
+  // URL the spreadsheet was downloaded from and cron-like schedule for re-importing it
+  pub source_url: Option<String>,
+  pub refresh_schedule: Option<String>,
+  // set by the scheduler for re-imports
+  #[serde(skip_deserializing)]
+  pub trigger: Option<String>,
+  // all or comma separated sheet indices and/or names to import as one dataset per sheet
+  #[serde(alias = "sheet_indices")]
+  pub sheets: Option<String>,
+  // groups the datasets imported from the sheets of one workbook. Pass it back to update them
+  pub workbook_id: Option<String>,
 }
 
 fn listing_limit() -> u64 {
@@ -179,15 +277,143 @@ impl CoreOptions {
         value["columns"] = json!(cols.to_parts(","));
       }
     }
+    if let Some(sheet_name) = self.sheet_name.clone() {
+      value["sheet_name"] = json!(sheet_name.trim());
+    }
+    if let Some(header_rows) = self.header_rows.filter(|rows| *rows > 1) {
+      value["header_rows"] = json!(header_rows);
+    }
+    if self.no_header.unwrap_or(false) {
+      value["no_header"] = json!(true);
+      if let Some(key_style) = self.key_style.clone() {
+        value["key_style"] = json!(key_style.to_lowercase());
+      }
+    }
+    for (key, setting) in [("delimiter", &self.delimiter), ("quote", &self.quote), ("escape", &self.escape), ("encoding", &self.encoding), ("thousands", &self.thousands)] {
+      if let Some(setting) = setting {
+        value[key] = json!(setting);
+      }
+    }
+    if let Some(decimal_comma) = self.decimal_comma {
+      value["decimal_comma"] = json!(decimal_comma);
+    }
+    if let Some(range) = self.range.clone() {
+      value["range"] = json!(range.trim().to_uppercase());
+    }
     if let Some(d_id) = self.dataset_id.clone() {
       value["dataset_id"] = json!(d_id);
     }
     if let Some(i_id) = self.import_id.clone() {
       value["import_id"] = json!(i_id);
     }
+    if let Some(policy) = self.schema_policy.clone() {
+      value["schema_policy"] = json!(SchemaPolicy::from_key(&policy).to_key());
+    }
+    if let Some(tz) = self.timezone.clone() {
+      value["timezone"] = json!(tz);
+    }
+    if let Some(storage) = self.storage.clone() {
+      value["storage"] = json!(storage.to_lowercase());
+    }
+    if let Some(pk) = self.data_pk.clone() {
+      if !pk.is_empty() {
+        value["data_pk"] = json!(pk);
+      }
+    }
+    if let Some(publish) = self.publish {
+      value["publish"] = json!(publish);
+    }
+    if let Some(max) = self.max {
+      value["max"] = json!(max);
+    }
+    if let Some(url) = self.source_url.clone() {
+      value["source_url"] = json!(url);
+    }
+    if let Some(schedule) = self.refresh_schedule.clone() {
+      value["refresh_schedule"] = json!(schedule.trim());
+    }
+    if let Some(trigger) = self.trigger.clone() {
+      value["trigger"] = json!(trigger);
+    }
+    if let Some(workbook_id) = self.workbook_id.clone() {
+      value["workbook_id"] = json!(workbook_id);
+    }
+    if let Some(index_cols) = self.index_cols.clone() {
+      if !index_cols.is_empty() {
+        value["index_cols"] = json!(index_cols.to_segments(","));
+      }
+    }
     value
   }
 
+  /// Options to re-import a dataset from its source with the settings stored by its last import.
+  /// Keyed datasets are upserted, others replaced.
+  pub fn from_dataset(dset: &Value, filename: &str) -> Self {
+    let options = &dset["options"];
+    let joined = |value: &Value| value.as_array().map(|items| items.iter().filter_map(|item| item.as_str()).collect::<Vec<&str>>().join(","));
+    let text = |value: &Value| value.as_str().map(|s| s.to_string());
+    CoreOptions {
+      filename: Some(filename.to_string()),
+      title: None,
+      description: None,
+      user_ref: None,
+      mode: Some("sync".to_string()),
+      max: options["max"].as_u64().map(|max| max as usize),
+      keys: joined(&options["keys"]),
+      cols: joined(&options["columns"]),
+      sheet_index: options["sheet_index"].as_u64().map(|index| index as usize),
+      sheet_name: text(&options["sheet_name"]),
+      header_index: options["header_index"].as_u64().map(|index| index as usize),
+      header_rows: options["header_rows"].as_u64().map(|rows| rows as usize),
+      no_header: options["no_header"].as_bool(),
+      key_style: text(&options["key_style"]),
+      delimiter: text(&options["delimiter"]),
+      quote: text(&options["quote"]),
+      escape: text(&options["escape"]),
+      encoding: text(&options["encoding"]),
+      decimal_comma: options["decimal_comma"].as_bool(),
+      thousands: text(&options["thousands"]),
+      range: text(&options["range"]),
+      dataset_id: text(&dset["_id"]),
+      import_id: None,
+      append: Some(options["data_pk"].is_string()),
+      lines: None,
+      schema_policy: text(&options["schema_policy"]),
+      timezone: text(&options["timezone"]),
+      index_cols: joined(&options["index_cols"]),
+      storage: text(&options["storage"]),
+      data_pk: text(&options["data_pk"]),
+      publish: None,
+      source_url: text(&options["source_url"]),
+      refresh_schedule: text(&options["refresh_schedule"]),
+      trigger: Some("schedule".to_string()),
+      sheets: None,
+      workbook_id: text(&options["workbook_id"]),
+    }
+  }
+
+  /// A refresh schedule must be valid and needs a source URL
+  pub fn validate_refresh(&self) -> Result<(), String> {
+    if let Some(spec) = &self.refresh_schedule {
+      RefreshSchedule::parse(spec).map_err(|e| format!("Invalid refresh_schedule: {}", e))?;
+      if self.source_url.is_none() {
+        return Err("A refresh_schedule requires a source_url".to_string());
+      }
+    }
+    Ok(())
+  }
+
+  pub fn dialect_options(&self) -> DialectOptions {
+    DialectOptions {
+      delimiter: self.delimiter.clone(),
+      quote: self.quote.clone(),
+      escape: self.escape.clone(),
+      encoding: self.encoding.clone(),
+      decimal_comma: self.decimal_comma,
+      thousands: self.thousands.clone(),
+    }
+  }
+
   pub fn append_mode(&self) -> bool {
     self.append.unwrap_or(false)
   }
@@ -206,14 +432,69 @@ impl UploadAssetRequest {
       lines: self.lines.map(|l| l > 0),
       cols: self.cols.clone(),
       sheet_index: self.sheet_index,
+      sheet_name: self.sheet_name.clone(),
       header_index: self.header_index,
+      header_rows: self.header_rows,
+      no_header: self.no_header,
+      key_style: None,
+      delimiter: self.delimiter.clone(),
+      quote: self.quote.clone(),
+      escape: self.escape.clone(),
+      encoding: self.encoding.clone(),
+      decimal_comma: self.decimal_comma,
+      thousands: self.thousands.clone(),
+      range: self.range.clone(),
       dataset_id: None,
       import_id: None,
       append: None,
+      schema_policy: None,
+      timezone: None,
+      index_cols: None,
+      storage: None,
+      data_pk: None,
+      publish: None,
+      source_url: None,
+      refresh_schedule: None,
+      trigger: None,
+      sheets: None,
+      workbook_id: None,
     }
   }
 }
 
+#[derive(Deserialize)]
+pub struct ImportUrlRequest {
+  // http(s) URL of a spreadsheet, e.g. a Google Sheets link published as CSV
+  pub url: String,
+  #[serde(flatten)]
+  pub options: CoreOptions,
+}
+
+#[derive(Deserialize)]
+pub struct IndexRequest {
+  // comma separated list of column keys
+  pub fields: String,
+}
+
+impl IndexRequest {
+  pub fn to_fields(&self) -> Vec<String> {
+    self.fields.to_segments(",")
+  }
+}
+
+#[derive(Deserialize)]
+pub struct PublishParams {
+  // publish a draft even if another import was made live after it was created
+  pub force: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DiffParams {
+  // import IDs, by default the import before `to` (or the current import for drafts) and the current import
+  pub from: Option<String>,
+  pub to: Option<String>,
+}
+
 pub enum DataSetMatcher {
   NameIndex(String, u32),
   Id(String),
@@ -261,6 +542,9 @@ pub struct QueryFilterParams {
     pub limit: Option<u64>,
     pub q: Option<String>,
     pub u: Option<String>, // user reference
+    pub workbook: Option<String>,
+    // json (default), parquet or arrow
+    pub format: Option<String>,
 }
 
 impl QueryFilterParams {
@@ -311,6 +595,9 @@ impl QueryFilterParams {
         let u_str = format!("^{}\\b", u.trim());
           criteria = doc! { "user_ref": { "$regex": &u_str, "$options": "i" } };
       }
+      if let Some(workbook_id) = self.workbook.clone() {
+          criteria.insert("options.workbook_id", workbook_id.trim());
+      }
       if criteria.is_empty() {
           None
       } else {
@@ -339,6 +626,38 @@ impl QueryFilterParams {
       Some(doc! { sort_field.to_string(): dir })
     }
 
+    /// Normalized representation of the dataset query used for response cache keys
+    pub fn to_cache_key(&self) -> String {
+        let (start, limit) = self.to_pagination();
+        let mut parts: Vec<String> = vec![];
+        if let (Some(field), Some(value)) = (self.f.clone(), self.v.clone()) {
+            let operator = self.o.clone().unwrap_or("eq".to_string()).to_lowercase().strip_non_alphanum();
+            let data_type = CastDataType::from_str(&self.dt.clone().unwrap_or_default());
+            parts.push(format!("f={}&v={}&o={}&dt={}", field, value, operator, data_type.to_key()));
+        }
+        if let Some(sort) = self.sort.clone() {
+            let dir = match_sort_direction(&self.dir.clone().unwrap_or("asc".to_string()));
+            parts.push(format!("sort={}&dir={}", sort, dir));
+        }
+        if let Some(import) = self.import.clone() {
+            parts.push(format!("import={}", import));
+        }
+        if let Some(q) = self.q.clone() {
+            parts.push(format!("q={}", q.trim()));
+        }
+        if let Some(u) = self.u.clone() {
+            parts.push(format!("u={}", u.trim()));
+        }
+        if let Some(workbook_id) = self.workbook.clone() {
+            parts.push(format!("workbook={}", workbook_id.trim()));
+        }
+        if let Some(format) = self.format.clone() {
+            parts.push(format!("format={}", format.trim().to_lowercase()));
+        }
+        parts.push(format!("start={}&limit={}", start, limit));
+        parts.join("&")
+    }
+
     pub fn to_pagination(&self) -> (u64, u64) {
         let start = self.start.unwrap_or(0);
         let mut limit = self.limit.unwrap_or(100);
@@ -351,7 +670,7 @@ impl QueryFilterParams {
     
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CastDataType {
   String,
   Float,
@@ -373,6 +692,17 @@ impl CastDataType {
     }
   }
 
+  pub fn to_key(&self) -> &'static str {
+    match self {
+      CastDataType::String => "string",
+      CastDataType::Float => "float",
+      CastDataType::Integer => "int",
+      CastDataType::Date => "date",
+      CastDataType::DateTime => "datetime",
+      CastDataType::Boolean => "bool",
+    }
+  }
+
   pub fn is_numeric(&self) -> bool {
     match self {
       CastDataType::Float | CastDataType::Integer => true,
@@ -428,7 +758,40 @@ impl ReplaceMode {
   }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaPolicy {
+  // any added, removed or retyped column rejects the import
+  Strict,
+  // new columns and widening type changes (int to float, date to datetime) are accepted
+  Additive,
+  // all differences are accepted and reported
+  Lenient,
+}
+
+impl SchemaPolicy {
+  pub fn from_key(key: &str) -> Self {
+    match key.to_lowercase().as_str() {
+      "additive" | "add" => SchemaPolicy::Additive,
+      "lenient" | "loose" | "none" => SchemaPolicy::Lenient,
+      _ => SchemaPolicy::Strict,
+    }
+  }
+
+  pub fn to_key(&self) -> &'static str {
+    match self {
+      SchemaPolicy::Strict => "strict",
+      SchemaPolicy::Additive => "additive",
+      SchemaPolicy::Lenient => "lenient",
+    }
+  }
+}
+
 fn cast_to_comparison(op: &str, value: &str, dt: &CastDataType) -> Document {
+  if dt.is_datelike() {
+    if let Some(criteria) = cast_to_date_comparison(op, value, dt) {
+      return criteria;
+    }
+  }
   if value.is_numeric() || dt.is_numeric() {
     if dt.is_integer() {
       if let Ok(num_val) = value.parse::<i64>() {
@@ -454,6 +817,30 @@ fn cast_to_comparison(op: &str, value: &str, dt: &CastDataType) -> Document {
   return doc! { op.to_string(): value.to_string() }
 }
 
+// Pure dates are stored as midnight UTC, so date comparisons match whole days
+fn cast_to_date_comparison(op: &str, value: &str, dt: &CastDataType) -> Option<Document> {
+  let naive = if let Ok(date) = chrono::NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d") {
+    date.and_hms_opt(0, 0, 0)?
+  } else {
+    iso_fuzzy_string_to_datetime(value).ok()?
+  };
+  if dt.is_datetime() {
+    let dt_val = chrono::Utc.from_utc_datetime(&naive);
+    return Some(doc! { op.to_string(): dt_val });
+  }
+  let start = chrono::Utc.from_utc_datetime(&naive.date().and_hms_opt(0, 0, 0)?);
+  let end = start + chrono::Duration::days(1);
+  let criteria = match op {
+    "$ne" => doc! { "$not": { "$gte": start, "$lt": end } },
+    "$gt" => doc! { "$gte": end },
+    "$gte" => doc! { "$gte": start },
+    "$lt" => doc! { "$lt": start },
+    "$lte" => doc! { "$lt": end },
+    _ => doc! { "$gte": start, "$lt": end },
+  };
+  Some(criteria)
+}
+
 fn match_sort_direction(key: &str) -> i32 {
   let dir_key = key.trim().to_lowercase();
   match dir_key.as_str() {
@@ -515,6 +902,25 @@ fn parse_upload_size(size: &str) -> Option<usize> {
 #[cfg(test)]
 mod test {
   use super::*;
+  use axum::{body::Body, extract::FromRequest, http::Request};
+
+  #[tokio::test]
+  async fn test_upload_request_range() {
+    let body = [
+      "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"report.csv\"\r\nContent-Type: text/csv\r\n\r\nsku,qty\nA1,3\r\n",
+      "--X\r\nContent-Disposition: form-data; name=\"range\"\r\n\r\nB4:H200\r\n",
+      "--X--\r\n",
+    ].concat();
+    let request = Request::builder()
+      .header("content-type", "multipart/form-data; boundary=X")
+      .body(Body::from(body))
+      .unwrap();
+    let multipart = Multipart::from_request(request, &()).await.unwrap();
+    let upload = UploadAssetRequest::from_multipart(multipart).await.unwrap();
+    let core_options = upload.to_core_options();
+    assert_eq!(core_options.range.as_deref(), Some("B4:H200"));
+    assert_eq!(core_options.filename.as_deref(), Some("report.csv"));
+  }
 
   #[test]
   fn test_cast_data_type_numeric() {
@@ -548,6 +954,24 @@ mod test {
     assert_eq!(string.is_bool(), false);
   }
 
+  #[test]
+  fn test_date_filter_matches_whole_day() {
+    let date = CastDataType::from_str("date");
+    let criteria = cast_to_comparison("$eq", "2024-03-01", &date);
+    assert_eq!(criteria.keys().collect::<Vec<&String>>(), vec!["$gte", "$lt"]);
+    let criteria = cast_to_comparison("$gt", "2024-03-01", &date);
+    let start = criteria.get_datetime("$gte").unwrap().to_chrono();
+    assert_eq!(start.format("%Y-%m-%d").to_string(), "2024-03-02");
+  }
+
+  #[test]
+  fn test_query_cache_key_normalization() {
+    let params: QueryFilterParams = serde_json::from_value(json!({ "f": "name", "v": "Ann", "o": "EQ", "dir": "descending", "sort": "age" })).unwrap();
+    let same: QueryFilterParams = serde_json::from_value(json!({ "f": "name", "v": "Ann", "sort": "age", "dir": "desc", "start": 0, "limit": 100 })).unwrap();
+    assert_eq!(params.to_cache_key(), same.to_cache_key());
+    assert_eq!(same.to_cache_key(), "f=name&v=Ann&o=eq&dt=string&sort=age&dir=-1&start=0&limit=100");
+  }
+
   #[test]
   fn test_filesize_conversion() {
     let size_str = "10k";
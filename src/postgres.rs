@@ -0,0 +1,950 @@
+use async_trait::async_trait;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use deadpool_postgres::{Config, Pool, Runtime};
+use serde_json::{json, Map, Value};
+use serde_with::chrono::{self, DateTime, Utc};
+use tokio_postgres::{types::ToSql, NoTls};
+
+use crate::cache::invalidate_dataset;
+use crate::db::{bson_to_json, build_row_doc, import_options_doc, import_timezone, RowSet, MIGRATION_BATCH_SIZE};
+use crate::imports::{
+  current_import_id, excess_skipped_runs, import_history_limit, import_source, is_draft, is_skipped_run, retained_import_ids, skipped_run_record, ImportRecord,
+};
+use crate::indexes::{is_valid_field, ColumnIndex};
+use crate::options::{DataSetMatcher, ReplaceMode};
+use crate::schema::{build_schema, ColumnSchema, MigrationOp, SchemaMigration};
+use crate::storage::Storage;
+use crate::workbooks::WorkbookRecord;
+
+const CREATE_TABLES: &str = "
+  CREATE TABLE IF NOT EXISTS datasets (
+    id TEXT PRIMARY KEY,
+    doc JSONB NOT NULL
+  );
+  CREATE TABLE IF NOT EXISTS data_rows (
+    id BIGSERIAL PRIMARY KEY,
+    dataset_id TEXT NOT NULL REFERENCES datasets (id) ON DELETE CASCADE,
+    import_id TEXT NOT NULL,
+    data JSONB NOT NULL
+  );
+  CREATE INDEX IF NOT EXISTS data_rows_dataset_id_idx ON data_rows (dataset_id);
+  CREATE INDEX IF NOT EXISTS data_rows_import_id_idx ON data_rows (import_id);
+  CREATE INDEX IF NOT EXISTS data_rows_data_gin_idx ON data_rows USING GIN (data);
+  CREATE TABLE IF NOT EXISTS data_row_snapshots (
+    id BIGSERIAL PRIMARY KEY,
+    dataset_id TEXT NOT NULL REFERENCES datasets (id) ON DELETE CASCADE,
+    snapshot_id TEXT NOT NULL,
+    import_id TEXT NOT NULL,
+    data JSONB NOT NULL
+  );
+  CREATE INDEX IF NOT EXISTS data_row_snapshots_idx ON data_row_snapshots (dataset_id, snapshot_id);
+  CREATE TABLE IF NOT EXISTS workbooks (
+    id TEXT PRIMARY KEY,
+    doc JSONB NOT NULL
+  );
+";
+
+fn get_postgres_uri() -> Result<String, String> {
+  dotenv::var("POSTGRES_URI").map_err(|_| "Failed to load `POSTGRES_URI` environment variable.".to_string())
+}
+
+/// Query parameter bound to a `$n` placeholder
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlParam {
+  Text(String),
+  TextList(Vec<String>),
+  Int(i64),
+  Json(Value),
+}
+
+impl SqlParam {
+  fn as_sql(&self) -> &(dyn ToSql + Sync) {
+    match self {
+      SqlParam::Text(text) => text,
+      SqlParam::TextList(items) => items,
+      SqlParam::Int(num) => num,
+      SqlParam::Json(value) => value,
+    }
+  }
+}
+
+fn param_refs(params: &[SqlParam]) -> Vec<&(dyn ToSql + Sync)> {
+  params.iter().map(|p| p.as_sql()).collect()
+}
+
+fn placeholder(params: &mut Vec<SqlParam>, param: SqlParam) -> String {
+  params.push(param);
+  format!("${}", params.len())
+}
+
+/// Segments of a criteria key relative to the JSONB column, e.g. `data.address.city` => `address`, `city` for row data
+fn key_segments(key: &str, prefix: Option<&str>) -> Vec<String> {
+  let relative = match prefix {
+    Some(pre) => key.strip_prefix(pre).and_then(|k| k.strip_prefix('.')).unwrap_or(key),
+    None => key,
+  };
+  relative.split('.').map(|s| s.to_string()).collect()
+}
+
+fn escape_jsonpath(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Lax-mode SQL/JSON path, which unwraps arrays along the way as MongoDB does for dotted keys
+fn to_jsonpath(segments: &[String]) -> String {
+  let accessors = segments.iter().map(|s| format!(".\"{}\"", escape_jsonpath(s))).collect::<String>();
+  format!("${}", accessors)
+}
+
+/// PostgreSQL regular expressions use `\y` rather than `\b` for word boundaries
+fn to_posix_regex(pattern: &str) -> String {
+  pattern.replace("\\b", "\\y")
+}
+
+fn path_exists(column: &str, path: &str, vars: Option<Value>, params: &mut Vec<SqlParam>) -> String {
+  let path_ref = placeholder(params, SqlParam::Text(path.to_string()));
+  match vars {
+    Some(vars) => {
+      let vars_ref = placeholder(params, SqlParam::Json(vars));
+      format!("jsonb_path_exists({}, {}::text::jsonpath, {}::jsonb)", column, path_ref, vars_ref)
+    },
+    None => format!("jsonb_path_exists({}, {}::text::jsonpath)", column, path_ref),
+  }
+}
+
+/// Equality is expressed as containment so it can use the GIN index
+fn eq_to_sql(column: &str, segments: &[String], value: &Bson, params: &mut Vec<SqlParam>) -> String {
+  let contained = segments.iter().rev().fold(bson_to_json(value), |inner, key| json!({ key: inner }));
+  format!("{} @> {}::jsonb", column, placeholder(params, SqlParam::Json(contained)))
+}
+
+fn condition_to_sql(column: &str, segments: &[String], condition: &Document, params: &mut Vec<SqlParam>) -> String {
+  let path = to_jsonpath(segments);
+  let mut clauses: Vec<String> = vec![];
+  for (op, arg) in condition.iter() {
+    let clause = match op.as_str() {
+      "$eq" => eq_to_sql(column, segments, arg, params),
+      "$ne" => format!("NOT {}", eq_to_sql(column, segments, arg, params)),
+      "$gt" | "$gte" | "$lt" | "$lte" => {
+        let symbol = match op.as_str() {
+          "$gt" => ">",
+          "$gte" => ">=",
+          "$lt" => "<",
+          _ => "<=",
+        };
+        // comparisons in SQL/JSON paths are strict, so numbers never match strings
+        path_exists(column, &format!("{} ? (@ {} $v)", path, symbol), Some(json!({ "v": bson_to_json(arg) })), params)
+      },
+      "$in" | "$nin" => {
+        let items = arg.as_array().cloned().unwrap_or_default();
+        let clause = if !items.is_empty() {
+          let mut vars = Map::new();
+          for (index, item) in items.iter().enumerate() {
+            vars.insert(format!("v{}", index), bson_to_json(item));
+          }
+          let tests = (0..items.len()).map(|index| format!("@ == $v{}", index)).collect::<Vec<String>>().join(" || ");
+          path_exists(column, &format!("{} ? ({})", path, tests), Some(Value::Object(vars)), params)
+        } else {
+          "FALSE".to_string()
+        };
+        if op == "$in" { clause } else { format!("NOT {}", clause) }
+      },
+      "$regex" => {
+        let pattern = to_posix_regex(arg.as_str().unwrap_or_default());
+        let flag = if condition.get_str("$options").unwrap_or_default().contains('i') { " flag \"i\"" } else { "" };
+        path_exists(column, &format!("{} ? (@ like_regex \"{}\"{})", path, escape_jsonpath(&pattern), flag), None, params)
+      },
+      "$exists" => {
+        let clause = path_exists(column, &path, None, params);
+        if arg.as_bool().unwrap_or(true) { clause } else { format!("NOT {}", clause) }
+      },
+      "$not" => match arg.as_document() {
+        Some(inner) => format!("NOT ({})", condition_to_sql(column, segments, inner, params)),
+        None => continue,
+      },
+      // $options is read with $regex
+      _ => continue,
+    };
+    clauses.push(clause);
+  }
+  join_clauses(clauses, " AND ")
+}
+
+fn join_clauses(clauses: Vec<String>, separator: &str) -> String {
+  match clauses.len() {
+    0 => "TRUE".to_string(),
+    1 => clauses[0].clone(),
+    _ => format!("({})", clauses.join(separator)),
+  }
+}
+
+/// Translate MongoDB-style criteria, as built by `QueryFilterParams`, into a SQL condition on a JSONB column.
+/// Keys are relative to the column once the optional prefix is removed.
+pub fn criteria_to_sql(column: &str, prefix: Option<&str>, criteria: &Document, params: &mut Vec<SqlParam>) -> String {
+  let mut clauses: Vec<String> = vec![];
+  for (key, expected) in criteria.iter() {
+    let clause = match key.as_str() {
+      "$or" | "$and" => {
+        let items = expected.as_array().cloned().unwrap_or_default();
+        let parts = items.iter().filter_map(|c| c.as_document()).map(|c| criteria_to_sql(column, prefix, c, params)).collect::<Vec<String>>();
+        match (key.as_str(), parts.len()) {
+          ("$or", 0) => "FALSE".to_string(),
+          ("$or", _) => join_clauses(parts, " OR "),
+          _ => join_clauses(parts, " AND "),
+        }
+      },
+      _ => {
+        let segments = key_segments(key, prefix);
+        match expected {
+          Bson::Document(condition) if condition.keys().all(|k| k.starts_with('$')) => condition_to_sql(column, &segments, condition, params),
+          _ => eq_to_sql(column, &segments, expected, params),
+        }
+      }
+    };
+    clauses.push(clause);
+  }
+  join_clauses(clauses, " AND ")
+}
+
+/// ORDER BY clause on JSONB values, with the row id as a tie-breaker for stable pagination
+pub fn sort_to_sql(column: &str, prefix: Option<&str>, sort_criteria: Option<&Document>) -> String {
+  let mut items: Vec<String> = vec![];
+  if let Some(sort) = sort_criteria {
+    for (key, dir) in sort.iter() {
+      let segments = key_segments(key, prefix);
+      if !segments.iter().all(|s| is_valid_field(s)) {
+        continue;
+      }
+      let descending = dir.as_i32().map(|d| d < 0).or(dir.as_i64().map(|d| d < 0)).unwrap_or(false);
+      items.push(format!("{} #> '{{{}}}' {}", column, segments.join(","), if descending { "DESC" } else { "ASC" }));
+    }
+  }
+  items.push("id ASC".to_string());
+  format!("ORDER BY {}", items.join(", "))
+}
+
+/// Expression index on a row column, used for sorting. Equality filters are served by the GIN index.
+pub fn row_index_name(field: &str) -> String {
+  format!("data_rows_{}_idx", field.replace('.', "_"))
+}
+
+fn to_timestamp(dt: DateTime<Utc>) -> Value {
+  bson_to_json(&Bson::DateTime(dt.into()))
+}
+
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+  value.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|dt| dt.with_timezone(&Utc))
+}
+
+fn dataset_modified(doc: &Value) -> Option<DateTime<Utc>> {
+  parse_timestamp(&doc["updated_at"]).or(parse_timestamp(&doc["created_at"]))
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+  value.as_array().map(|items| items.iter().filter_map(|f| f.as_str().map(|s| s.to_string())).collect()).unwrap_or_default()
+}
+
+/// Datasets and rows stored as JSONB documents in two tables. Rows of all datasets share one table,
+/// so the `storage` import option has no effect with this backend.
+pub struct PostgresStorage {
+  pool: Pool,
+}
+
+impl PostgresStorage {
+  pub fn new() -> Result<Self, String> {
+    Self::from_uri(&get_postgres_uri()?)
+  }
+
+  pub fn from_uri(uri: &str) -> Result<Self, String> {
+    let mut config = Config::new();
+    config.url = Some(uri.to_string());
+    let pool = config.create_pool(Some(Runtime::Tokio1), NoTls).map_err(|e| format!("Failed to create the PostgreSQL connection pool: {}", e))?;
+    Ok(Self { pool })
+  }
+
+  async fn query(&self, sql: &str, params: &[SqlParam]) -> Vec<tokio_postgres::Row> {
+    let client = match self.pool.get().await {
+      Ok(client) => client,
+      Err(e) => {
+        println!("PostgreSQL connection failed: {}", e);
+        return vec![];
+      }
+    };
+    match client.query(sql, &param_refs(params)).await {
+      Ok(rows) => rows,
+      Err(e) => {
+        println!("PostgreSQL query failed: {}", e);
+        vec![]
+      }
+    }
+  }
+
+  async fn execute(&self, sql: &str, params: &[SqlParam]) -> Option<u64> {
+    let client = self.pool.get().await.ok()?;
+    match client.execute(sql, &param_refs(params)).await {
+      Ok(num) => Some(num),
+      Err(e) => {
+        println!("PostgreSQL statement failed: {}", e);
+        None
+      }
+    }
+  }
+
+  async fn count(&self, sql: &str, params: &[SqlParam]) -> u64 {
+    self.query(sql, params).await.first().map(|row| row.get::<_, i64>(0) as u64).unwrap_or(0)
+  }
+
+  async fn fetch_dataset_doc(&self, dataset_id: &str) -> Option<Value> {
+    let rows = self.query("SELECT doc FROM datasets WHERE id = $1", &[SqlParam::Text(dataset_id.to_string())]).await;
+    rows.first().map(|row| row.get::<_, Value>(0))
+  }
+
+  async fn write_dataset_doc(&self, dataset_id: &str, doc: &Value) -> bool {
+    let params = [SqlParam::Text(dataset_id.to_string()), SqlParam::Json(doc.to_owned())];
+    self.execute("UPDATE datasets SET doc = $2 WHERE id = $1", &params).await.unwrap_or(0) > 0
+  }
+
+  /// Write the dataset record and its rows in a single transaction
+  async fn write_import(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Result<(String, String, usize), String> {
+    let mut client = self.pool.get().await.map_err(|e| e.to_string())?;
+    let tx = client.transaction().await.map_err(|e| e.to_string())?;
+    let fname = options["filename"].as_str().unwrap_or_default().to_owned();
+    let s_index = options["sheet_index"].as_u64().unwrap_or(0) as u32;
+    let matcher = match options["dataset_id"].as_str() {
+      Some(dataset_id) => DataSetMatcher::from_id(dataset_id),
+      None => DataSetMatcher::from_name_index(&fname, s_index),
+    };
+    let mut params: Vec<SqlParam> = vec![];
+    let where_sql = criteria_to_sql("doc", None, &matcher.to_criteria(), &mut params);
+    let sql = format!("SELECT id, doc FROM datasets WHERE {} LIMIT 1 FOR UPDATE", where_sql);
+    let existing = tx.query_opt(&sql, &param_refs(&params)).await.map_err(|e| e.to_string())?;
+    let now = to_timestamp(chrono::Utc::now());
+    let (dataset_id, mut dset) = match existing {
+      Some(row) => (row.get::<_, String>(0), row.get::<_, Value>(1)),
+      None => {
+        let dataset_id = ObjectId::new().to_hex();
+        let dset = json!({
+          "_id": &dataset_id,
+          "user_ref": options["user_ref"].as_str().unwrap_or_default(),
+          "name": &fname,
+          "title": options["title"].as_str().unwrap_or_default(),
+          "description": options["description"].as_str().unwrap_or_default(),
+          "sheet_index": s_index,
+          "imports": [],
+          "created_at": &now
+        });
+        (dataset_id, dset)
+      }
+    };
+    let mut imports = dset["imports"].as_array().cloned().unwrap_or_default();
+    let import_ref = import_id_opt.filter(|id| imports.iter().any(|r| r["_id"].as_str() == Some(id.as_str())));
+    let replace_mode = ReplaceMode::new(append, import_ref.is_some());
+    let import_id = import_ref.unwrap_or_else(|| ObjectId::new().to_hex());
+    let draft = is_draft(options);
+    let previous_current = current_import_id(&dset);
+    let options_value = bson_to_json(&Bson::Document(import_options_doc(options)));
+    let schema_value = serde_json::to_value(schema).unwrap_or(json!([]));
+    if draft {
+      // the live rows, schema and options change when the draft is published
+      if let Some(current) = &previous_current {
+        dset["current_import"] = json!(current);
+      }
+      if dset["options"].is_null() {
+        dset["options"] = options_value.clone();
+      }
+    } else {
+      dset["options"] = options_value.clone();
+      dset["schema"] = schema_value.clone();
+      dset["current_import"] = json!(&import_id);
+      dset["updated_at"] = now.clone();
+    }
+    let upsert = "INSERT INTO datasets (id, doc) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET doc = EXCLUDED.doc";
+    tx.execute(upsert, &[&dataset_id, &dset]).await.map_err(|e| e.to_string())?;
+    // drafts are applied to a copy of the live rows kept as their version
+    let (rows_table, scope_params): (&str, Vec<&(dyn ToSql + Sync)>) = match draft {
+      true => ("data_row_snapshots", vec![&import_id]),
+      false => ("data_rows", vec![]),
+    };
+    let scope = |num_params: usize| match draft {
+      true => format!("dataset_id = $1 AND snapshot_id = ${}", num_params + 1),
+      false => "dataset_id = $1".to_string(),
+    };
+    if draft {
+      tx.execute("DELETE FROM data_row_snapshots WHERE dataset_id = $1 AND snapshot_id = $2", &[&dataset_id, &import_id]).await.map_err(|e| e.to_string())?;
+      let copy = "INSERT INTO data_row_snapshots (dataset_id, snapshot_id, import_id, data) SELECT dataset_id, $2, import_id, data FROM data_rows WHERE dataset_id = $1 ORDER BY id";
+      tx.execute(copy, &[&dataset_id, &import_id]).await.map_err(|e| e.to_string())?;
+    }
+    match replace_mode {
+      ReplaceMode::ReplaceAll => {
+        let sql = format!("DELETE FROM {} WHERE {}", rows_table, scope(1));
+        tx.execute(&sql, &[&[&dataset_id as &(dyn ToSql + Sync)][..], &scope_params].concat()).await
+      },
+      ReplaceMode::ReplaceImport => {
+        let sql = format!("DELETE FROM {} WHERE {} AND import_id = $2", rows_table, scope(2));
+        tx.execute(&sql, &[&[&dataset_id as &(dyn ToSql + Sync), &import_id][..], &scope_params].concat()).await
+      },
+      ReplaceMode::Append => Ok(0),
+    }.map_err(|e| e.to_string())?;
+    let timezone = import_timezone(options);
+    let (dataset_oid, import_oid) = (ObjectId::parse_str(&dataset_id).map_err(|e| e.to_string())?, ObjectId::parse_str(&import_id).map_err(|e| e.to_string())?);
+    let row_values = rows.iter().map(|row| {
+      let row_doc = build_row_doc(dataset_oid, import_oid, row, schema, &timezone);
+      bson_to_json(row_doc.get("data").unwrap_or(&Bson::Document(doc! {})))
+    }).collect::<Vec<Value>>();
+    let row_array = Value::Array(row_values);
+    if let Some(pk) = options["data_pk"].as_str() {
+      // rows sharing a key with an incoming row are replaced
+      let sql = format!("DELETE FROM {} WHERE {} AND data -> $2 IN (SELECT item -> $2 FROM jsonb_array_elements($3::jsonb) AS item)", rows_table, scope(3));
+      tx.execute(&sql, &[&[&dataset_id as &(dyn ToSql + Sync), &pk, &row_array][..], &scope_params].concat()).await.map_err(|e| e.to_string())?;
+    }
+    let insert = match draft {
+      true => "INSERT INTO data_row_snapshots (dataset_id, snapshot_id, import_id, data) SELECT $1, $2, $2, item FROM jsonb_array_elements($3::jsonb) WITH ORDINALITY AS t(item, ord) ORDER BY ord",
+      false => "INSERT INTO data_rows (dataset_id, import_id, data) SELECT $1, $2, item FROM jsonb_array_elements($3::jsonb) WITH ORDINALITY AS t(item, ord) ORDER BY ord",
+    };
+    tx.execute(insert, &[&dataset_id, &import_id, &row_array]).await.map_err(|e| e.to_string())?;
+
+    // record the import and keep a copy of the rows as its version
+    let count_sql = format!("SELECT count(*) FROM {} WHERE {}", rows_table, scope(1));
+    let total_rows: i64 = tx.query_one(&count_sql, &[&[&dataset_id as &(dyn ToSql + Sync)][..], &scope_params].concat()).await.map_err(|e| e.to_string())?.get(0);
+    let mut import_record = json!({
+      "_id": &import_id,
+      "dt": &now,
+      "filename": &fname,
+      "sheet_index": s_index,
+      "rows": rows.len(),
+      "total_rows": total_rows,
+      "schema": &schema_value,
+    });
+    if let (Some(record), Value::Object(source)) = (import_record.as_object_mut(), bson_to_json(&Bson::Document(import_source(options)))) {
+      record.extend(source);
+    }
+    if draft {
+      import_record["draft"] = json!(true);
+      import_record["options"] = options_value;
+      if let Some(base) = &previous_current {
+        import_record["base_import"] = json!(base);
+      }
+    }
+    match imports.iter().position(|r| r["_id"].as_str() == Some(import_id.as_str())) {
+      Some(index) => imports[index] = import_record,
+      None => imports.push(import_record),
+    }
+    let current_import = match (draft, previous_current) {
+      (true, Some(id)) => id,
+      _ => import_id.clone(),
+    };
+    let import_ids = imports
+      .iter()
+      .filter(|r| !is_skipped_run(r["status"].as_str()))
+      .filter_map(|r| r["_id"].as_str().map(|id| id.to_string()))
+      .collect::<Vec<String>>();
+    let mut retained = retained_import_ids(&import_ids, &current_import, import_history_limit());
+    if !draft {
+      retained.retain(|id| id != &import_id);
+    }
+    dset["imports"] = Value::Array(imports);
+    tx.execute(upsert, &[&dataset_id, &dset]).await.map_err(|e| e.to_string())?;
+    tx.execute("DELETE FROM data_row_snapshots WHERE dataset_id = $1 AND NOT snapshot_id = ANY($2)", &[&dataset_id, &retained])
+      .await
+      .map_err(|e| e.to_string())?;
+    if import_history_limit() > 0 && !draft {
+      let snapshot = "INSERT INTO data_row_snapshots (dataset_id, snapshot_id, import_id, data) SELECT dataset_id, $2, import_id, data FROM data_rows WHERE dataset_id = $1 ORDER BY id";
+      tx.execute(snapshot, &[&dataset_id, &import_id]).await.map_err(|e| e.to_string())?;
+    }
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok((dataset_id, import_id, rows.len()))
+  }
+
+  /// Rewrite a column of the rows of a dataset in a table, in batches of consecutive row ids.
+  /// Keys of nested JSON documents are dotted paths, as with MongoDB's `$rename` and `$unset`.
+  async fn migrate_rows(&self, table: &str, dataset_id: &str, migration: &SchemaMigration) -> u64 {
+    let Ok(op) = migration.to_op() else {
+      return 0;
+    };
+    let key_path = SqlParam::TextList(key_segments(&migration.key, None));
+    let mut num_updated: u64 = 0;
+    let mut last_id: i64 = 0;
+    let select = format!("SELECT id, data #> $2 FROM {} WHERE dataset_id = $1 AND data #> $2 IS NOT NULL AND id > $3 ORDER BY id LIMIT {}", table, MIGRATION_BATCH_SIZE);
+    let id_range = "dataset_id = $1 AND data #> $2 IS NOT NULL AND id > $3 AND id <= $4";
+    loop {
+      let params = [SqlParam::Text(dataset_id.to_string()), key_path.clone(), SqlParam::Int(last_id)];
+      let batch = self.query(&select, &params).await;
+      let Some(batch_end) = batch.last().map(|row| row.get::<_, i64>(0)) else {
+        break;
+      };
+      let mut params = vec![SqlParam::Text(dataset_id.to_string()), key_path.clone()];
+      let update = match &op {
+        MigrationOp::Rename(to) => {
+          params.extend([SqlParam::Int(last_id), SqlParam::Int(batch_end)]);
+          let to_segments = key_segments(to, None);
+          // parent documents of a nested key are added first, as jsonb_set only creates the last key
+          let mut target = "(data #- $2)".to_string();
+          for depth in 1..to_segments.len() {
+            let parent = placeholder(&mut params, SqlParam::TextList(to_segments[..depth].to_vec()));
+            target = format!("jsonb_set({}, {}, COALESCE((data #- $2) #> {}, '{{}}'::jsonb))", target, parent, parent);
+          }
+          let to_ref = placeholder(&mut params, SqlParam::TextList(to_segments));
+          format!("UPDATE {} SET data = jsonb_set({}, {}, data #> $2) WHERE {}", table, target, to_ref, id_range)
+        },
+        MigrationOp::Drop => {
+          params.extend([SqlParam::Int(last_id), SqlParam::Int(batch_end)]);
+          format!("UPDATE {} SET data = data #- $2 WHERE {}", table, id_range)
+        },
+        MigrationOp::Retype(_) => {
+          let mut changes = Map::new();
+          for row in batch.iter() {
+            let value: Value = row.get(1);
+            let converted = bson::to_bson(&value).map(|b| bson_to_json(&migration.convert_value(&b))).unwrap_or(value.clone());
+            if converted != value {
+              changes.insert(row.get::<_, i64>(0).to_string(), converted);
+            }
+          }
+          params.push(SqlParam::Json(Value::Object(changes)));
+          format!("UPDATE {} SET data = jsonb_set(data, $2, $3::jsonb -> id::text) WHERE dataset_id = $1 AND $3::jsonb ? id::text", table)
+        },
+      };
+      num_updated += self.execute(&update, &params).await.unwrap_or(0);
+      last_id = batch_end;
+      if (batch.len() as i64) < MIGRATION_BATCH_SIZE {
+        break;
+      }
+    }
+    num_updated
+  }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+  async fn init(&self) -> bool {
+    match self.pool.get().await {
+      Ok(client) => match client.batch_execute(CREATE_TABLES).await {
+        Ok(_) => true,
+        Err(e) => {
+          println!("Failed to create PostgreSQL tables: {}", e);
+          false
+        }
+      },
+      Err(e) => {
+        println!("PostgreSQL connection failed: {}", e);
+        false
+      }
+    }
+  }
+
+  async fn fetch_dataset(&self, dataset_id: &str, import_id_opt: Option<String>, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> Option<RowSet> {
+    let dset = self.fetch_dataset_doc(dataset_id).await?;
+    let mut params = vec![SqlParam::Text(dataset_id.to_string())];
+    let mut where_sql = "dataset_id = $1".to_string();
+    if let Some(import_id) = import_id_opt {
+      where_sql.push_str(&format!(" AND import_id = {}", placeholder(&mut params, SqlParam::Text(import_id))));
+    }
+    if let Some(filter) = filter_options {
+      where_sql.push_str(&format!(" AND {}", criteria_to_sql("data", Some("data"), &filter, &mut params)));
+    }
+    let total = self.count(&format!("SELECT count(*) FROM data_rows WHERE {}", where_sql), &params).await;
+    let order_sql = sort_to_sql("data", Some("data"), sort_criteria.as_ref());
+    let sql = format!("SELECT data FROM data_rows WHERE {} {} LIMIT {} OFFSET {}", where_sql, order_sql, limit, skip);
+    let rows = self.query(&sql, &params).await.iter().map(|row| row.get::<_, Value>(0)).collect::<Vec<Value>>();
+    Some(RowSet {
+      dataset: dset,
+      rows: Value::Array(rows),
+      total,
+      limit,
+      skip,
+    })
+  }
+
+  async fn get_datasets(&self, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> (Option<u64>, Vec<Value>) {
+    let mut params: Vec<SqlParam> = vec![];
+    let where_sql = criteria_to_sql("doc", None, &filter_options.unwrap_or_default(), &mut params);
+    let total = self.count(&format!("SELECT count(*) FROM datasets WHERE {}", where_sql), &params).await;
+    let order_sql = sort_to_sql("doc", None, sort_criteria.as_ref());
+    let sql = format!("SELECT id, doc FROM datasets WHERE {} {} LIMIT {} OFFSET {}", where_sql, order_sql, limit, skip);
+    let dsets = self.query(&sql, &params).await.iter().map(|row| (row.get::<_, String>(0), row.get::<_, Value>(1))).collect::<Vec<(String, Value)>>();
+    let ids = dsets.iter().map(|(id, _)| id.to_owned()).collect::<Vec<String>>();
+    let counts = self.query("SELECT dataset_id, count(*) FROM data_rows WHERE dataset_id = ANY($1) GROUP BY dataset_id", &[SqlParam::TextList(ids)]).await;
+    let datasets = dsets.into_iter().map(|(id, mut dset)| {
+      if let Some(row) = counts.iter().find(|row| row.get::<_, String>(0) == id) {
+        dset["row_count"] = json!(row.get::<_, i64>(1));
+      }
+      dset
+    }).collect::<Vec<Value>>();
+    (Some(total), datasets)
+  }
+
+  async fn save_import_with_rows(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Option<(String, String, usize)> {
+    match self.write_import(options, rows, schema, import_id_opt, append).await {
+      Ok((id, import_id, count)) => {
+        let fields = string_list(&options["index_cols"]);
+        if !fields.is_empty() {
+          self.declare_indexes(&id, &fields).await;
+        }
+        invalidate_dataset(&id).await;
+        Some((id, import_id, count))
+      },
+      Err(e) => {
+        println!("Failed to save import: {}", e);
+        None
+      }
+    }
+  }
+
+  async fn delete_dataset(&self, dataset_id: &str) -> Option<u64> {
+    let params = [SqlParam::Text(dataset_id.to_string())];
+    let num_rows = self.count("SELECT count(*) FROM data_rows WHERE dataset_id = $1", &params).await;
+    // rows are removed via ON DELETE CASCADE
+    if self.execute("DELETE FROM datasets WHERE id = $1", &params).await? < 1 {
+      return None;
+    }
+    invalidate_dataset(dataset_id).await;
+    Some(num_rows)
+  }
+
+  async fn record_refresh(&self, dataset_id: &str, refresh: &Value) -> bool {
+    let params = [SqlParam::Text(dataset_id.to_string()), SqlParam::Json(refresh.to_owned())];
+    let Some(record) = skipped_run_record(refresh) else {
+      // runs that imported rows are already listed with their import
+      return self.execute("UPDATE datasets SET doc = jsonb_set(doc, '{refresh}', $2::jsonb) WHERE id = $1", &params).await.unwrap_or(0) > 0;
+    };
+    let Some(dset) = self.fetch_dataset_doc(dataset_id).await else {
+      return false;
+    };
+    let mut imports = dset["imports"].as_array().cloned().unwrap_or_default();
+    imports.push(bson_to_json(&Bson::Document(record)));
+    for index in excess_skipped_runs(&imports, |r| r["status"].as_str()).into_iter().rev() {
+      imports.remove(index);
+    }
+    let params = [SqlParam::Text(dataset_id.to_string()), SqlParam::Json(refresh.to_owned()), SqlParam::Json(json!(imports))];
+    let update = "UPDATE datasets SET doc = jsonb_set(jsonb_set(doc, '{refresh}', $2::jsonb), '{imports}', $3::jsonb) WHERE id = $1";
+    self.execute(update, &params).await.unwrap_or(0) > 0
+  }
+
+  async fn list_imports(&self, dataset_id: &str) -> Option<Vec<ImportRecord>> {
+    let dset = self.fetch_dataset_doc(dataset_id).await?;
+    let current = current_import_id(&dset);
+    let snapshots = self.query("SELECT DISTINCT snapshot_id FROM data_row_snapshots WHERE dataset_id = $1", &[SqlParam::Text(dataset_id.to_string())]).await;
+    let snapshot_ids = snapshots.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<String>>();
+    let records = dset["imports"].as_array().cloned().unwrap_or_default();
+    Some(records.iter().map(|record| {
+      let restorable = record["_id"].as_str().map(|id| snapshot_ids.iter().any(|s| s == id)).unwrap_or(false);
+      ImportRecord::from_json(record, current.as_deref(), restorable)
+    }).collect())
+  }
+
+  async fn restore_import(&self, dataset_id: &str, import_id: &str) -> Option<bool> {
+    let mut dset = self.fetch_dataset_doc(dataset_id).await?;
+    let record = dset["imports"].as_array()?.iter().find(|r| r["_id"].as_str() == Some(import_id)).cloned()?;
+    let mut client = self.pool.get().await.ok()?;
+    let result: Result<bool, tokio_postgres::Error> = async {
+      let tx = client.transaction().await?;
+      let snapshot: i64 = tx.query_one("SELECT count(*) FROM data_row_snapshots WHERE dataset_id = $1 AND snapshot_id = $2", &[&dataset_id, &import_id]).await?.get(0);
+      if snapshot < 1 {
+        return Ok(false);
+      }
+      tx.execute("DELETE FROM data_rows WHERE dataset_id = $1", &[&dataset_id]).await?;
+      let insert = "INSERT INTO data_rows (dataset_id, import_id, data) SELECT dataset_id, import_id, data FROM data_row_snapshots WHERE dataset_id = $1 AND snapshot_id = $2 ORDER BY id";
+      tx.execute(insert, &[&dataset_id, &import_id]).await?;
+      if record["schema"].is_array() {
+        dset["schema"] = record["schema"].clone();
+      }
+      if record["options"].is_object() {
+        dset["options"] = record["options"].clone();
+      }
+      // restoring a draft publishes it
+      if let Some(imports) = dset["imports"].as_array_mut() {
+        for item in imports.iter_mut().filter(|r| r["_id"].as_str() == Some(import_id)) {
+          if let Some(rec) = item.as_object_mut() {
+            rec.remove("draft");
+            rec.remove("options");
+            rec.remove("base_import");
+          }
+        }
+      }
+      dset["current_import"] = json!(import_id);
+      dset["updated_at"] = to_timestamp(chrono::Utc::now());
+      tx.execute("UPDATE datasets SET doc = $2 WHERE id = $1", &[&dataset_id, &dset]).await?;
+      tx.commit().await?;
+      Ok(true)
+    }.await;
+    match result {
+      Ok(restored) => {
+        if restored {
+          invalidate_dataset(dataset_id).await;
+        }
+        Some(restored)
+      },
+      Err(e) => {
+        println!("Failed to restore import: {}", e);
+        None
+      }
+    }
+  }
+
+  async fn fetch_import_rows(&self, dataset_id: &str, import_id: &str) -> Option<Vec<Value>> {
+    let params = [SqlParam::Text(dataset_id.to_string()), SqlParam::Text(import_id.to_string())];
+    if self.count("SELECT count(*) FROM data_row_snapshots WHERE dataset_id = $1 AND snapshot_id = $2", &params).await < 1 {
+      return None;
+    }
+    let rows = self.query("SELECT data FROM data_row_snapshots WHERE dataset_id = $1 AND snapshot_id = $2 ORDER BY id", &params).await;
+    Some(rows.iter().map(|row| row.get::<_, Value>(0)).collect())
+  }
+
+  async fn fetch_dataset_schema(&self, dataset_id: &str) -> Option<Vec<ColumnSchema>> {
+    let dset = self.fetch_dataset_doc(dataset_id).await?;
+    if let Ok(schema) = serde_json::from_value::<Vec<ColumnSchema>>(dset["schema"].clone()) {
+      return Some(schema);
+    }
+    let sql = "SELECT data FROM data_rows WHERE dataset_id = $1 ORDER BY id LIMIT 100";
+    let rows = self.query(sql, &[SqlParam::Text(dataset_id.to_string())]).await.iter().map(|row| row.get::<_, Value>(0)).collect::<Vec<Value>>();
+    Some(build_schema(&json!({}), &rows, &[]))
+  }
+
+  async fn migrate_dataset(&self, dataset_id: &str, migration: &SchemaMigration, schema: &[ColumnSchema]) -> Option<u64> {
+    migration.to_op().ok()?;
+    let mut dset = self.fetch_dataset_doc(dataset_id).await?;
+    let num_updated = self.migrate_rows("data_rows", dataset_id, migration).await;
+    // kept versions are migrated too, so restoring one does not bring back the old column
+    self.migrate_rows("data_row_snapshots", dataset_id, migration).await;
+    let record = json!({
+      "_id": ObjectId::new().to_hex(),
+      "dt": to_timestamp(chrono::Utc::now()),
+      "op": &migration.op,
+      "key": &migration.key,
+      "to": migration.to.clone(),
+      "data_type": migration.data_type.clone(),
+      "rows": num_updated
+    });
+    match dset["migrations"].as_array_mut() {
+      Some(items) => items.push(record),
+      None => dset["migrations"] = json!([record]),
+    }
+    dset["schema"] = serde_json::to_value(schema).unwrap_or(json!([]));
+    if let Some(imports) = dset["imports"].as_array_mut() {
+      for rec in imports.iter_mut() {
+        if let Some(import_schema) = migration.apply_to_import(&rec["schema"]) {
+          rec["schema"] = import_schema;
+        }
+      }
+    }
+    dset["updated_at"] = to_timestamp(chrono::Utc::now());
+    self.write_dataset_doc(dataset_id, &dset).await;
+    invalidate_dataset(dataset_id).await;
+    Some(num_updated)
+  }
+
+  async fn fetch_dataset_modified(&self, dataset_id: &str) -> Option<DateTime<Utc>> {
+    let dset = self.fetch_dataset_doc(dataset_id).await?;
+    dataset_modified(&dset)
+  }
+
+  async fn datasets_modified(&self, filter_options: Option<Document>) -> (Option<DateTime<Utc>>, u64) {
+    let mut params: Vec<SqlParam> = vec![];
+    let where_sql = criteria_to_sql("doc", None, &filter_options.unwrap_or_default(), &mut params);
+    // timestamps are stored as ISO 8601 strings in UTC, which sort chronologically
+    let sql = format!("SELECT max(COALESCE(doc ->> 'updated_at', doc ->> 'created_at')), count(*) FROM datasets WHERE {}", where_sql);
+    match self.query(&sql, &params).await.first() {
+      Some(row) => {
+        let modified = row.get::<_, Option<String>>(0).map(Value::String).and_then(|v| parse_timestamp(&v));
+        (modified, row.get::<_, i64>(1) as u64)
+      },
+      None => (None, 0),
+    }
+  }
+
+  async fn declare_indexes(&self, dataset_id: &str, fields: &[String]) -> Vec<String> {
+    let Some(mut dset) = self.fetch_dataset_doc(dataset_id).await else {
+      return vec![];
+    };
+    let mut created: Vec<String> = vec![];
+    for field in fields.iter().map(|f| f.trim().to_string()).filter(|f| is_valid_field(f)) {
+      let sql = format!("CREATE INDEX IF NOT EXISTS \"{}\" ON data_rows (dataset_id, (data #> '{{{}}}'))", row_index_name(&field), field.replace('.', ","));
+      if self.execute(&sql, &[]).await.is_some() {
+        created.push(field);
+      }
+    }
+    let mut declared = string_list(&dset["indexes"]);
+    for field in created.iter() {
+      if !declared.contains(field) {
+        declared.push(field.to_owned());
+      }
+    }
+    dset["indexes"] = json!(declared);
+    self.write_dataset_doc(dataset_id, &dset).await;
+    created
+  }
+
+  async fn list_dataset_indexes(&self, dataset_id: &str) -> Option<Vec<ColumnIndex>> {
+    let dset = self.fetch_dataset_doc(dataset_id).await?;
+    let names = self.query("SELECT indexname::text FROM pg_indexes WHERE tablename = 'data_rows'", &[]).await.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<String>>();
+    Some(string_list(&dset["indexes"]).into_iter().map(|field| {
+      let name = row_index_name(&field);
+      ColumnIndex {
+        field,
+        exists: names.contains(&name),
+        name,
+      }
+    }).collect())
+  }
+
+  async fn drop_dataset_index(&self, dataset_id: &str, field: &str) -> Option<bool> {
+    let mut dset = self.fetch_dataset_doc(dataset_id).await?;
+    let declared = string_list(&dset["indexes"]);
+    if !declared.iter().any(|f| f == field) {
+      return None;
+    }
+    dset["indexes"] = json!(declared.into_iter().filter(|f| f != field).collect::<Vec<String>>());
+    self.write_dataset_doc(dataset_id, &dset).await;
+    // all rows share one table, so the index is only dropped when no other dataset declares it
+    if self.count("SELECT count(*) FROM datasets WHERE doc -> 'indexes' ? $1", &[SqlParam::Text(field.to_string())]).await > 0 {
+      return Some(false);
+    }
+    Some(self.execute(&format!("DROP INDEX IF EXISTS \"{}\"", row_index_name(field)), &[]).await.is_some())
+  }
+
+  async fn save_workbook(&self, workbook: &WorkbookRecord) -> bool {
+    let params = [SqlParam::Text(workbook.id.clone()), SqlParam::Json(json!(workbook))];
+    let upsert = "INSERT INTO workbooks (id, doc) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET doc = EXCLUDED.doc";
+    self.execute(upsert, &params).await.is_some()
+  }
+
+  async fn fetch_workbook(&self, workbook_id: &str) -> Option<WorkbookRecord> {
+    let rows = self.query("SELECT doc FROM workbooks WHERE id = $1", &[SqlParam::Text(workbook_id.to_string())]).await;
+    rows.first().and_then(|row| WorkbookRecord::from_json(&row.get::<_, Value>(0)))
+  }
+
+  async fn get_workbooks(&self, limit: u64, skip: u64) -> Vec<WorkbookRecord> {
+    let sql = format!("SELECT doc FROM workbooks ORDER BY doc ->> 'updated_at' DESC LIMIT {} OFFSET {}", limit, skip);
+    self.query(&sql, &[]).await.iter().filter_map(|row| WorkbookRecord::from_json(&row.get::<_, Value>(0))).collect()
+  }
+
+  async fn delete_workbook(&self, workbook_id: &str) -> bool {
+    self.execute("DELETE FROM workbooks WHERE id = $1", &[SqlParam::Text(workbook_id.to_string())]).await.unwrap_or(0) > 0
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::imports::is_stale_draft;
+  use crate::options::QueryFilterParams;
+
+  #[test]
+  fn test_row_criteria_to_sql() {
+    let mut params: Vec<SqlParam> = vec![SqlParam::Text("dataset".to_string())];
+    let sql = criteria_to_sql("data", Some("data"), &doc! { "data.price": { "$gte": 10, "$lt": 20 } }, &mut params);
+    assert_eq!(sql, "(jsonb_path_exists(data, $2::text::jsonpath, $3::jsonb) AND jsonb_path_exists(data, $4::text::jsonpath, $5::jsonb))");
+    assert_eq!(params[1], SqlParam::Text("$.\"price\" ? (@ >= $v)".to_string()));
+    assert_eq!(params[2], SqlParam::Json(json!({ "v": 10 })));
+    let mut params: Vec<SqlParam> = vec![];
+    let sql = criteria_to_sql("data", Some("data"), &doc! { "data.address.city": "Leeds" }, &mut params);
+    assert_eq!(sql, "data @> $1::jsonb");
+    assert_eq!(params[0], SqlParam::Json(json!({ "address": { "city": "Leeds" } })));
+    let mut params: Vec<SqlParam> = vec![];
+    let sql = criteria_to_sql("data", Some("data"), &doc! { "data.sku": { "$nin": ["a", "b"] } }, &mut params);
+    assert_eq!(sql, "NOT jsonb_path_exists(data, $1::text::jsonpath, $2::jsonb)");
+    assert_eq!(params[0], SqlParam::Text("$.\"sku\" ? (@ == $v0 || @ == $v1)".to_string()));
+  }
+
+  #[test]
+  fn test_dataset_search_to_sql() {
+    let criteria = doc! { "$or": [
+      { "name": { "$regex": "\\b\\s*sales", "$options": "i" } },
+      { "imports.filename": { "$regex": "\\b\\s*sales", "$options": "i" } },
+    ] };
+    let mut params: Vec<SqlParam> = vec![];
+    let sql = criteria_to_sql("doc", None, &criteria, &mut params);
+    assert_eq!(sql, "(jsonb_path_exists(doc, $1::text::jsonpath) OR jsonb_path_exists(doc, $2::text::jsonpath))");
+    assert_eq!(params[1], SqlParam::Text("$.\"imports\".\"filename\" ? (@ like_regex \"\\\\y\\\\s*sales\" flag \"i\")".to_string()));
+    assert_eq!(sort_to_sql("doc", None, Some(&doc! { "updated_at": -1 })), "ORDER BY doc #> '{updated_at}' DESC, id ASC");
+    assert_eq!(sort_to_sql("data", Some("data"), Some(&doc! { "data.name; DROP": 1 })), "ORDER BY id ASC");
+  }
+
+  /// Runs against the database at POSTGRES_TEST_URL with `cargo test -- --ignored`
+  #[tokio::test]
+  #[ignore]
+  async fn test_postgres_imports() {
+    let uri = dotenv::var("POSTGRES_TEST_URL").expect("POSTGRES_TEST_URL must be set to run the PostgreSQL tests");
+    let storage = PostgresStorage::from_uri(&uri).unwrap();
+    assert!(storage.init().await);
+    // a unique file name so earlier runs are not matched
+    let filename = format!("stock-{}.csv", ObjectId::new());
+    let options = json!({ "filename": &filename, "sheet_index": 0, "data_pk": "sku" });
+    let rows = vec![
+      json!({ "sku": "A1", "name": "Widget", "qty": 3 }),
+      json!({ "sku": "B2", "name": "Gadget", "qty": 7 }),
+    ];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, first_id, count) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    assert_eq!(count, 2);
+    let params: QueryFilterParams = serde_json::from_value(json!({ "f": "qty", "v": "5", "o": "gt" })).unwrap();
+    let result = storage.fetch_dataset(&id, None, params.to_criteria(), 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 1);
+    assert_eq!(result.rows[0]["sku"], json!("B2"));
+    // appending a row with an existing key replaces it
+    let update = vec![json!({ "sku": "A1", "name": "Widget", "qty": 9 })];
+    let (_, second_id, _) = storage.save_import_with_rows(&options, &update, &schema, None, true).await.unwrap();
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, Some(doc! { "data.qty": -1 })).await.unwrap();
+    assert_eq!(result.total, 2);
+    assert_eq!(result.rows[0]["qty"], json!(9));
+
+    // a draft leaves the live rows unchanged until it is published
+    let draft_options = json!({ "filename": &filename, "sheet_index": 0, "data_pk": "sku", "publish": false });
+    let draft_rows = vec![json!({ "sku": "C3", "name": "Gizmo", "qty": 1 })];
+    let (_, draft_id, _) = storage.save_import_with_rows(&draft_options, &draft_rows, &schema, None, true).await.unwrap();
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 2);
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert_eq!(imports.len(), 3);
+    assert!(imports[1].current && imports[2].draft);
+    assert_eq!(imports[2].base_import.as_deref(), Some(second_id.as_str()));
+    assert!(!is_stale_draft(&imports, &draft_id));
+    assert_eq!(storage.fetch_import_rows(&id, &draft_id).await.map(|rows| rows.len()), Some(3));
+    assert_eq!(storage.restore_import(&id, &draft_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.total, 3);
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert!(imports[2].current && !imports[2].draft);
+
+    // the first version can be restored
+    assert!(imports[0].restorable);
+    assert_eq!(storage.restore_import(&id, &first_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, Some(doc! { "data.sku": 1 })).await.unwrap();
+    assert_eq!(result.total, 2);
+    assert_eq!(result.rows[0]["qty"], json!(3));
+    // a migration also applies to kept versions and their schemas
+    let migration = SchemaMigration { op: "rename".to_string(), key: "qty".to_string(), to: Some("stock".to_string()), data_type: None };
+    let migrated = migration.apply_to_schema(&schema).unwrap();
+    assert_eq!(storage.migrate_dataset(&id, &migration, &migrated).await, Some(2));
+    assert_eq!(storage.restore_import(&id, &second_id).await, Some(true));
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, Some(doc! { "data.sku": 1 })).await.unwrap();
+    assert_eq!(result.rows[0], json!({ "sku": "A1", "name": "Widget", "stock": 9 }));
+    let stored = storage.fetch_dataset_schema(&id).await.unwrap();
+    assert!(stored.iter().any(|c| c.key == "stock") && !stored.iter().any(|c| c.key == "qty"));
+    assert_eq!(storage.restore_import(&id, &first_id).await, Some(true));
+    // scheduled runs that import nothing are listed without changing the current import
+    assert!(storage.record_refresh(&id, &json!({ "status": "unchanged", "source_url": "https://example.com/stock.csv" })).await);
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert_eq!(imports.len(), 4);
+    assert!(imports[3].is_skipped_run() && imports[0].current);
+    assert_eq!(storage.delete_dataset(&id).await, Some(2));
+
+    // keys of nested documents are dotted paths
+    let options = json!({ "filename": format!("staff-{}.json", ObjectId::new()), "sheet_index": 0 });
+    let documents = vec![json!({ "id": 1, "address": { "city": "Leeds", "zip": "12345" }, "joined": "2024-12-25" })];
+    let flat = documents.iter().map(crate::documents::flatten_object).collect::<Vec<Value>>();
+    let schema = build_schema(&json!({}), &flat, &[]);
+    let (id, _, _) = storage.save_import_with_rows(&options, &documents, &schema, None, false).await.unwrap();
+    let migration = SchemaMigration { op: "rename".to_string(), key: "address.city".to_string(), to: Some("location.town".to_string()), data_type: None };
+    assert_eq!(storage.migrate_dataset(&id, &migration, &migration.apply_to_schema(&schema).unwrap()).await, Some(1));
+    let migration = SchemaMigration { op: "retype".to_string(), key: "address.zip".to_string(), to: None, data_type: Some("int".to_string()) };
+    storage.migrate_dataset(&id, &migration, &schema).await.unwrap();
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.rows[0]["location"], json!({ "town": "Leeds" }));
+    assert_eq!(result.rows[0]["address"], json!({ "zip": 12345 }));
+    let migration = SchemaMigration { op: "drop".to_string(), key: "address.zip".to_string(), to: None, data_type: None };
+    storage.migrate_dataset(&id, &migration, &schema).await.unwrap();
+    let result = storage.fetch_dataset(&id, None, None, 100, 0, None).await.unwrap();
+    assert_eq!(result.rows[0]["address"], json!({}));
+    assert_eq!(storage.delete_dataset(&id).await, Some(1));
+  }
+}
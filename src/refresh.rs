@@ -0,0 +1,271 @@
+use bson::doc;
+use serde_json::{json, Value};
+use serde_with::chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
+
+use crate::files::{download_remote_file, DownloadError};
+use crate::options::{get_max_upload_size, CoreOptions};
+use crate::routes::process_asset_common;
+use crate::storage::get_storage_instance;
+
+const DEFAULT_SCHEDULER_INTERVAL_SECONDS: u64 = 60;
+const SCHEDULER_PAGE_SIZE: u64 = 100;
+
+/// Seconds between checks for datasets due a refresh. 0 disables the scheduler.
+pub fn scheduler_interval() -> u64 {
+  dotenv::var("SCHEDULER_INTERVAL")
+    .ok()
+    .and_then(|secs| secs.parse::<u64>().ok())
+    .unwrap_or(DEFAULT_SCHEDULER_INTERVAL_SECONDS)
+}
+
+/// Cron-like schedule with five fields (minute, hour, day of month, month, day of week) in UTC.
+/// Fields accept `*`, numbers, ranges, lists and steps, e.g. `*/15 6-18 * * 1-5`.
+/// `@hourly`, `@daily`, `@weekly` and `@monthly` are shorthands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshSchedule {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+fn parse_field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+  let mut mask: u64 = 0;
+  for part in spec.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or(format!("invalid step in {}", part))?),
+      None => (part, 1),
+    };
+    let (start, end) = match range {
+      "*" => (min, max),
+      _ => match range.split_once('-') {
+        Some((start, end)) => (start.parse::<u32>().map_err(|_| format!("invalid range {}", range))?, end.parse::<u32>().map_err(|_| format!("invalid range {}", range))?),
+        None => {
+          let value = range.parse::<u32>().map_err(|_| format!("invalid value {}", range))?;
+          // 5/10 means every 10 starting at 5
+          (value, if part.contains('/') { max } else { value })
+        }
+      },
+    };
+    if start < min || end > max || start > end {
+      return Err(format!("{} is outside {}-{}", part, min, max));
+    }
+    for value in (start..=end).step_by(step as usize) {
+      mask |= 1 << value;
+    }
+  }
+  Ok(mask)
+}
+
+impl RefreshSchedule {
+  pub fn parse(spec: &str) -> Result<Self, String> {
+    let expanded = match spec.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      other => other,
+    };
+    let fields = expanded.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 5 {
+      return Err("a refresh schedule needs five fields: minute hour day month weekday".to_string());
+    }
+    let mut weekdays = parse_field(fields[4], 0, 7)?;
+    // 0 and 7 are both Sunday
+    if weekdays & (1 << 7) != 0 {
+      weekdays |= 1;
+    }
+    Ok(RefreshSchedule {
+      minutes: parse_field(fields[0], 0, 59)?,
+      hours: parse_field(fields[1], 0, 23)?,
+      days: parse_field(fields[2], 1, 31)?,
+      months: parse_field(fields[3], 1, 12)?,
+      weekdays,
+      any_day: fields[2] == "*",
+      any_weekday: fields[4] == "*",
+    })
+  }
+
+  pub fn matches(&self, dt: &DateTime<Utc>) -> bool {
+    let bit = |mask: u64, value: u32| mask & (1 << value) != 0;
+    let day_match = bit(self.days, dt.day());
+    let weekday_match = bit(self.weekdays, dt.weekday().num_days_from_sunday());
+    // as with cron, a restricted day of month and day of week match either
+    let day_ok = match (self.any_day, self.any_weekday) {
+      (false, false) => day_match || weekday_match,
+      _ => day_match && weekday_match,
+    };
+    bit(self.minutes, dt.minute()) && bit(self.hours, dt.hour()) && bit(self.months, dt.month()) && day_ok
+  }
+
+  /// First scheduled minute after `dt`, looking ahead up to a year
+  pub fn next_after(&self, dt: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let mut next = dt.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+    let limit = *dt + Duration::days(366);
+    while next <= limit {
+      if self.matches(&next) {
+        return Some(next);
+      }
+      next += Duration::minutes(1);
+    }
+    None
+  }
+
+  pub fn is_due(&self, last_run: &DateTime<Utc>, now: &DateTime<Utc>) -> bool {
+    self.next_after(last_run).map(|next| next <= *now).unwrap_or(false)
+  }
+}
+
+fn parse_dt(value: &Value) -> Option<DateTime<Utc>> {
+  value.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Re-fetch the source of a dataset and re-import it when it has changed. Returns the refresh record.
+pub async fn refresh_dataset(dset: &Value) -> Value {
+  let source_url = dset["options"]["source_url"].as_str().unwrap_or_default();
+  let previous = &dset["refresh"];
+  let now = Utc::now().to_rfc3339();
+  let mut record = json!({ "last_run": now, "source_url": source_url });
+  let download = download_remote_file(source_url, get_max_upload_size(), previous["etag"].as_str(), previous["last_modified"].as_str()).await;
+  match download {
+    Ok(file) => {
+      let core_options = CoreOptions::from_dataset(dset, &file.name);
+      match process_asset_common(file.path, &core_options, true).await {
+        Ok(response) => {
+          record["status"] = json!("imported");
+          record["import_id"] = response.0["dataset"]["import_id"].clone();
+          record["rows"] = response.0["dataset"]["rows"].clone();
+          record["size"] = json!(file.size);
+          record["etag"] = json!(file.etag);
+          record["last_modified"] = json!(file.last_modified);
+        },
+        Err((status, message)) => {
+          record["status"] = json!("failed");
+          record["message"] = json!(format!("{}: {}", status, message.0["message"].as_str().unwrap_or_default()));
+        }
+      }
+    },
+    Err(DownloadError::NotModified) => {
+      record["status"] = json!("unchanged");
+      record["etag"] = previous["etag"].clone();
+      record["last_modified"] = previous["last_modified"].clone();
+    },
+    Err(error) => {
+      record["status"] = json!("failed");
+      record["message"] = json!(error.message());
+      // keep the validators of the last successful download
+      record["etag"] = previous["etag"].clone();
+      record["last_modified"] = previous["last_modified"].clone();
+    }
+  }
+  record
+}
+
+async fn refresh_due_datasets() {
+  let db = get_storage_instance().await;
+  let criteria = doc! { "options.source_url": { "$exists": true }, "options.refresh_schedule": { "$exists": true } };
+  let now = Utc::now();
+  let mut skip: u64 = 0;
+  loop {
+    let (_, datasets) = db.get_datasets(Some(criteria.clone()), SCHEDULER_PAGE_SIZE, skip, None).await;
+    for dset in datasets.iter() {
+      let (Some(id), Some(spec)) = (dset["_id"].as_str(), dset["options"]["refresh_schedule"].as_str()) else {
+        continue;
+      };
+      let Ok(schedule) = RefreshSchedule::parse(spec) else {
+        continue;
+      };
+      let last_run = parse_dt(&dset["refresh"]["last_run"]).or(parse_dt(&dset["updated_at"])).or(parse_dt(&dset["created_at"])).unwrap_or(now);
+      if schedule.is_due(&last_run, &now) {
+        let record = refresh_dataset(dset).await;
+        println!("Refreshed dataset {}: {}", id, record["status"].as_str().unwrap_or_default());
+        db.record_refresh(id, &record).await;
+      }
+    }
+    if (datasets.len() as u64) < SCHEDULER_PAGE_SIZE {
+      break;
+    }
+    skip += SCHEDULER_PAGE_SIZE;
+  }
+}
+
+/// Background task re-importing datasets with a `source_url` and `refresh_schedule` when they are due
+pub async fn run_scheduler() {
+  let interval = scheduler_interval();
+  if interval == 0 {
+    return;
+  }
+  let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+  loop {
+    ticker.tick().await;
+    refresh_due_datasets().await;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::memory::MemoryStorage;
+  use crate::schema::build_schema;
+  use crate::storage::Storage;
+  use axum::{http::{header, HeaderMap, StatusCode}, response::IntoResponse, routing::get, Router};
+  use serde_with::chrono::TimeZone;
+
+  #[test]
+  fn test_refresh_schedule() {
+    let schedule = RefreshSchedule::parse("*/15 6-18 * * 1-5").unwrap();
+    // Monday 2024-06-03
+    let monday = Utc.with_ymd_and_hms(2024, 6, 3, 6, 15, 0).unwrap();
+    assert!(schedule.matches(&monday));
+    assert!(!schedule.matches(&Utc.with_ymd_and_hms(2024, 6, 3, 6, 20, 0).unwrap()));
+    assert!(!schedule.matches(&Utc.with_ymd_and_hms(2024, 6, 2, 6, 15, 0).unwrap()));
+    assert_eq!(schedule.next_after(&Utc.with_ymd_and_hms(2024, 6, 3, 18, 50, 0).unwrap()), Some(Utc.with_ymd_and_hms(2024, 6, 4, 6, 0, 0).unwrap()));
+    let daily = RefreshSchedule::parse("@daily").unwrap();
+    assert!(daily.is_due(&Utc.with_ymd_and_hms(2024, 6, 3, 9, 0, 0).unwrap(), &Utc.with_ymd_and_hms(2024, 6, 4, 0, 1, 0).unwrap()));
+    assert!(!daily.is_due(&Utc.with_ymd_and_hms(2024, 6, 4, 0, 0, 0).unwrap(), &Utc.with_ymd_and_hms(2024, 6, 4, 23, 59, 0).unwrap()));
+    assert_eq!(RefreshSchedule::parse("0 0 * * 7").unwrap(), RefreshSchedule::parse("0 0 * * 0,7").unwrap());
+    assert!(RefreshSchedule::parse("61 * * * *").is_err());
+    assert!(RefreshSchedule::parse("0 12 * *").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_unchanged_and_failed_runs() {
+    let app = Router::new().route("/prices.csv", get(|headers: HeaderMap| async move {
+      match headers.get(header::IF_NONE_MATCH).is_some_and(|tag| tag == "\"v1\"") {
+        true => StatusCode::NOT_MODIFIED.into_response(),
+        false => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+      }
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      axum::serve(listener, app).await.unwrap();
+    });
+    let storage = MemoryStorage::new();
+    let source_url = format!("http://{}/prices.csv", addr);
+    let options = json!({ "filename": "prices.csv", "sheet_index": 0, "source_url": &source_url, "refresh_schedule": "@daily" });
+    let rows = vec![json!({ "sku": "A1", "price": 10 })];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let (id, import_id, _) = storage.save_import_with_rows(&options, &rows, &schema, None, false).await.unwrap();
+    let (_, datasets) = storage.get_datasets(None, 10, 0, None).await;
+    let mut dset = datasets[0].clone();
+    dset["refresh"] = json!({ "etag": "\"v1\"" });
+    let unchanged = refresh_dataset(&dset).await;
+    assert_eq!(unchanged["status"], json!("unchanged"));
+    assert!(storage.record_refresh(&id, &unchanged).await);
+    dset["refresh"] = json!({});
+    let failed = refresh_dataset(&dset).await;
+    assert_eq!(failed["status"], json!("failed"));
+    assert!(storage.record_refresh(&id, &failed).await);
+    // each run is listed in the history, while the imported rows stay current
+    let imports = storage.list_imports(&id).await.unwrap();
+    assert_eq!(imports.len(), 3);
+    assert!(imports[0].current && imports[0].import_id == import_id);
+    assert_eq!((imports[1].status.as_deref(), imports[1].trigger.as_deref()), (Some("unchanged"), Some("schedule")));
+    assert_eq!(imports[2].status.as_deref(), Some("failed"));
+    assert!(imports[2].message.is_some() && !imports[2].restorable && !imports[2].current);
+  }
+}
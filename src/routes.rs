@@ -1,14 +1,19 @@
 use axum::{
     extract::{Json, Multipart, Path as PathParam, Query},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
 };
-use crate::{db::get_db_instance, files::*, options::*};
+use crate::{columnar::{export_rows, is_columnar_file, read_columnar_file, ExportFormat}, cache::{build_etag, dataset_cache_key, get_cached, is_not_modified, set_cached, validator_headers}, dates::ImportTimezone, dialects::{is_delimited_file, normalize_delimited_file}, diffs::diff_rows, documents::{is_json_file, read_json_file}, files::*, headers::{combine_header_rows, restore_header_row, MAX_HEADER_ROW}, imports::{import_history_limit, is_stale_draft}, options::*, schema::{build_schema, infer_columns, merge_schema, SchemaDiff, SchemaMigration}, storage::get_storage_instance, workbooks::{find_sheet, CellRange, inspect_workbook, read_sheet_names, select_sheets, WorkbookRecord}};
+use bson::{doc, oid::ObjectId};
 use serde_json::{json, Value};
 use spreadsheet_to_json::{
     process_spreadsheet_immediate, simple_string_patterns::ToSegments, OptionSet, ReadMode,
 };
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Upper bound of datasets tagged with one workbook ID, removed with the workbook
+const MAX_WORKBOOK_DATASETS: u64 = 1000;
 
 #[axum::debug_handler]
 pub async fn upload_asset(multipart: Multipart) -> impl IntoResponse {
@@ -50,6 +55,15 @@ pub async fn process_asset(Json(core_options): Json<CoreOptions>) -> impl IntoRe
     let file_path = Path::new(tmp_directory.as_str())
         .join(sub_directory.as_str())
         .join(&file_name);
+    if let Err(message) = core_options.validate_refresh() {
+        return (StatusCode::BAD_REQUEST, json_error_response(&message)).into_response();
+    }
+    if core_options.sheets.is_some() {
+        return match process_workbook(file_path, &core_options).await {
+            Ok(response) => response.into_response(),
+            Err((status, message)) => (status, message).into_response(),
+        };
+    }
 
     match process_asset_common(file_path, &core_options, true).await {
         Ok(response) => response.into_response(),
@@ -57,6 +71,31 @@ pub async fn process_asset(Json(core_options): Json<CoreOptions>) -> impl IntoRe
     }
 }
 
+pub async fn import_url(Json(request): Json<ImportUrlRequest>) -> impl IntoResponse {
+    match download_remote_file(&request.url, get_max_upload_size(), None, None).await {
+        Ok(file) => {
+            let mut core_options = request.options;
+            // the stored name lets the file be processed with /process, as after an upload
+            core_options.filename = Some(file.file_name.clone());
+            match process_asset_common(file.path, &core_options, false).await {
+                Ok(Json(mut response)) => {
+                    response["filename"] = json!(file.file_name);
+                    Json(response).into_response()
+                }
+                Err((status, message)) => (status, message).into_response(),
+            }
+        }
+        Err(error) => {
+            let status = match error {
+                DownloadError::InvalidUrl => StatusCode::BAD_REQUEST,
+                DownloadError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                DownloadError::NotModified | DownloadError::Failed(_) => StatusCode::BAD_GATEWAY,
+            };
+            (status, json_error_response(&error.message())).into_response()
+        }
+    }
+}
+
 pub async fn check_file(PathParam(file_name): PathParam<String>) -> impl IntoResponse {
     match match_available_path_name(&file_name).await {
         Some(info) => {
@@ -75,24 +114,330 @@ pub async fn check_file(PathParam(file_name): PathParam<String>) -> impl IntoRes
     }
 }
 
-pub async fn get_dataset(PathParam(id): PathParam<String>, Query(params): Query<QueryFilterParams>) -> impl IntoResponse {
-    let db = get_db_instance().await;
+pub async fn inspect_file(PathParam(file_name): PathParam<String>) -> impl IntoResponse {
+    let Some(info) = match_available_path_name(&file_name).await else {
+        return (StatusCode::NOT_FOUND, json_error_response("File not found"));
+    };
+    let (tmp_directory, sub_directory) = get_tmp_and_sub_directories();
+    let file_path = Path::new(tmp_directory.as_str()).join(sub_directory.as_str()).join(&file_name);
+    match inspect_workbook(&file_path).await {
+        Some(sheets) => (StatusCode::OK, Json(json!({
+            "valid": true,
+            "info": info,
+            "sheets": sheets
+        }))),
+        None => (StatusCode::NOT_ACCEPTABLE, json_error_response("Failed to process file")),
+    }
+}
+
+pub async fn get_dataset(PathParam(id): PathParam<String>, Query(params): Query<QueryFilterParams>, headers: HeaderMap) -> impl IntoResponse {
+    let export_format = match params.format.as_deref().map(|key| key.trim().to_lowercase()) {
+        None => None,
+        Some(key) if key == "json" || key.is_empty() => None,
+        Some(key) => match ExportFormat::from_key(&key) {
+            Some(format) => Some(format),
+            None => return (StatusCode::BAD_REQUEST, json_error_response("Unsupported format, use json, parquet or arrow")).into_response(),
+        },
+    };
+    let db = get_storage_instance().await;
+    let params_key = params.to_cache_key();
+    let mut validators = HeaderMap::new();
+    if let Some(modified) = db.fetch_dataset_modified(&id).await {
+        let etag = build_etag(&id, &modified, &params_key);
+        validators = validator_headers(&etag, &modified);
+        if is_not_modified(&headers, &etag, &modified) {
+            return (StatusCode::NOT_MODIFIED, validators).into_response();
+        }
+    }
+    let cache_key = dataset_cache_key(&id, &params_key).await;
+    if let Some(key) = cache_key.clone() {
+        if let Some(body) = get_cached(&key).await {
+            return (StatusCode::OK, validators, [(header::CONTENT_TYPE, "application/json")], body).into_response();
+        }
+    }
     let criteria = params.to_criteria();
     let (start, limit) = params.to_pagination();
     let sort_criteria = params.to_sort_criteria();
-    let data_opt = db.fetch_dataset(&id, None, criteria, limit, start, sort_criteria).await;
+    let data_opt = db.fetch_dataset(&id, params.import.clone(), criteria, limit, start, sort_criteria).await;
+    if let (Some(format), Some(data)) = (export_format, data_opt.as_ref()) {
+        let rows = data.rows.as_array().cloned().unwrap_or_default();
+        let schema = match db.fetch_dataset_schema(&id).await {
+            Some(schema) if !schema.is_empty() => schema,
+            _ => build_schema(&json!({}), &rows, &[]),
+        };
+        return match export_rows(format, &schema, &rows) {
+            Ok(bytes) => {
+                let disposition = format!("attachment; filename=\"{}.{}\"", id, format.extension());
+                (StatusCode::OK, validators, [(header::CONTENT_TYPE, format.content_type().to_string()), (header::CONTENT_DISPOSITION, disposition)], bytes).into_response()
+            }
+            Err(message) => (StatusCode::INTERNAL_SERVER_ERROR, json_error_response(&message)).into_response(),
+        };
+    }
     if let Some(data) = data_opt {
-        (StatusCode::OK, Json(json!(data)))
+        let response = json!(data);
+        if let Some(key) = cache_key {
+            set_cached(&key, &response.to_string()).await;
+        }
+        (StatusCode::OK, validators, Json(response)).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found.")).into_response()
+    }
+}
+
+pub async fn delete_dataset(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(num_rows) = db.delete_dataset(&id).await {
+        (StatusCode::OK, Json(json!({
+            "valid": true,
+            "id": id,
+            "deleted_rows": num_rows
+        })))
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."))
+    }
+}
+
+pub async fn list_workbooks(Query(params): Query<QueryFilterParams>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    let (start, limit) = params.to_pagination();
+    let workbooks = db.get_workbooks(limit, start).await;
+    (StatusCode::OK, Json(json!({
+        "start": start,
+        "limit": limit,
+        "rows": workbooks
+    })))
+}
+
+pub async fn get_workbook(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(workbook) = db.fetch_workbook(&id).await {
+        (StatusCode::OK, Json(json!(workbook)))
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The requested workbook was not found."))
+    }
+}
+
+/// Delete a workbook record with the datasets of all its sheets
+pub async fn delete_workbook(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    let Some(workbook) = db.fetch_workbook(&id).await else {
+        return (StatusCode::NOT_FOUND, json_error_response("The requested workbook was not found."));
+    };
+    // include datasets of sheets that have since been removed from the workbook
+    let (_, tagged) = db.get_datasets(Some(doc! { "options.workbook_id": &id }), MAX_WORKBOOK_DATASETS, 0, None).await;
+    let mut dataset_ids = workbook.dataset_ids();
+    for dataset_id in tagged.iter().filter_map(|dset| dset["_id"].as_str()) {
+        if !dataset_ids.iter().any(|existing| existing == dataset_id) {
+            dataset_ids.push(dataset_id.to_string());
+        }
+    }
+    let mut deleted: Vec<String> = vec![];
+    let mut num_rows = 0;
+    for dataset_id in dataset_ids {
+        if let Some(count) = db.delete_dataset(&dataset_id).await {
+            num_rows += count;
+            deleted.push(dataset_id);
+        }
+    }
+    db.delete_workbook(&id).await;
+    (StatusCode::OK, Json(json!({
+        "valid": true,
+        "id": id,
+        "deleted_datasets": deleted,
+        "deleted_rows": num_rows
+    })))
+}
+
+pub async fn get_dataset_schema(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(schema) = db.fetch_dataset_schema(&id).await {
+        (StatusCode::OK, Json(json!({
+            "id": id,
+            "schema": schema
+        })))
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."))
+    }
+}
+
+pub async fn migrate_dataset_schema(PathParam(id): PathParam<String>, Json(migration): Json<SchemaMigration>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    let Some(stored_schema) = db.fetch_dataset_schema(&id).await else {
+        return (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."));
+    };
+    match migration.apply_to_schema(&stored_schema) {
+        Ok(schema) => {
+            if let Some(num_rows) = db.migrate_dataset(&id, &migration, &schema).await {
+                (StatusCode::OK, Json(json!({
+                    "valid": true,
+                    "id": id,
+                    "migration": migration,
+                    "rows": num_rows,
+                    "schema": schema
+                })))
+            } else {
+                (StatusCode::INTERNAL_SERVER_ERROR, json_error_response("Failed to migrate the dataset rows."))
+            }
+        }
+        Err(message) => (StatusCode::BAD_REQUEST, json_error_response(&message)),
+    }
+}
+
+pub async fn list_dataset_indexes(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(indexes) = db.list_dataset_indexes(&id).await {
+        (StatusCode::OK, Json(json!({
+            "id": id,
+            "indexes": indexes
+        })))
     } else {
         (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."))
     }
 }
 
-pub async fn list_datasets(Query(params): Query<QueryFilterParams>) -> impl IntoResponse {
-    let db = get_db_instance().await;
+pub async fn add_dataset_indexes(PathParam(id): PathParam<String>, Json(request): Json<IndexRequest>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if db.fetch_dataset_modified(&id).await.is_none() {
+        return (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."));
+    }
+    let created = db.declare_indexes(&id, &request.to_fields()).await;
+    (StatusCode::OK, Json(json!({
+        "valid": !created.is_empty(),
+        "id": id,
+        "created": created
+    })))
+}
+
+pub async fn drop_dataset_index(PathParam((id, field)): PathParam<(String, String)>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(dropped) = db.drop_dataset_index(&id, &field).await {
+        (StatusCode::OK, Json(json!({
+            "valid": true,
+            "id": id,
+            "field": field,
+            // the index is kept while other datasets still declare the same field
+            "dropped": dropped
+        })))
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The dataset or indexed column was not found."))
+    }
+}
+
+pub async fn list_dataset_imports(PathParam(id): PathParam<String>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    if let Some(imports) = db.list_imports(&id).await {
+        let current_import = imports.iter().find(|imp| imp.current).map(|imp| imp.import_id.clone());
+        (StatusCode::OK, Json(json!({
+            "id": id,
+            "current_import": current_import,
+            "history_limit": import_history_limit(),
+            "imports": imports
+        })))
+    } else {
+        (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."))
+    }
+}
+
+pub async fn restore_dataset_import(PathParam((id, import_id)): PathParam<(String, String)>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    match db.restore_import(&id, &import_id).await {
+        Some(true) => (StatusCode::OK, Json(json!({
+            "valid": true,
+            "id": id,
+            "import_id": import_id,
+            "restored": true
+        }))),
+        Some(false) => (StatusCode::CONFLICT, json_error_response("The rows of this import are no longer kept and cannot be restored.")),
+        None => (StatusCode::NOT_FOUND, json_error_response("The dataset or import was not found.")),
+    }
+}
+
+pub async fn publish_dataset_import(PathParam((id, import_id)): PathParam<(String, String)>, Query(params): Query<PublishParams>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    let imports = db.list_imports(&id).await.unwrap_or_default();
+    match imports.iter().find(|imp| imp.import_id == import_id).map(|imp| imp.draft) {
+        None => return (StatusCode::NOT_FOUND, json_error_response("The dataset or import was not found.")),
+        Some(false) => return (StatusCode::CONFLICT, json_error_response("This import has already been published.")),
+        Some(true) => {}
+    }
+    if is_stale_draft(&imports, &import_id) && params.force != Some(true) {
+        return (StatusCode::CONFLICT, json_error_response("Another import was published after this draft was created. Publish with force=true to replace it."));
+    }
+    match db.restore_import(&id, &import_id).await {
+        Some(true) => (StatusCode::OK, Json(json!({
+            "valid": true,
+            "id": id,
+            "import_id": import_id,
+            "published": true
+        }))),
+        Some(false) => (StatusCode::CONFLICT, json_error_response("The draft could not be published as the dataset was updated at the same time.")),
+        None => (StatusCode::NOT_FOUND, json_error_response("The dataset or import was not found.")),
+    }
+}
+
+pub async fn diff_dataset_imports(PathParam(id): PathParam<String>, Query(params): Query<DiffParams>) -> impl IntoResponse {
+    let db = get_storage_instance().await;
+    let Some(imports) = db.list_imports(&id).await else {
+        return (StatusCode::NOT_FOUND, json_error_response("The requested dataset was not found."));
+    };
+    let to_index = match &params.to {
+        Some(import_id) => imports.iter().position(|imp| &imp.import_id == import_id),
+        None => imports.iter().position(|imp| imp.current),
+    };
+    let from_index = match &params.from {
+        Some(import_id) => imports.iter().position(|imp| &imp.import_id == import_id),
+        // drafts are compared with the current import
+        None => match to_index {
+            Some(index) if imports[index].draft => imports.iter().position(|imp| imp.current),
+            // scheduled runs that imported nothing have no version to compare
+            _ => to_index.and_then(|index| imports[..index].iter().rposition(|imp| !imp.is_skipped_run())),
+        },
+    };
+    let (Some(from), Some(to)) = (from_index.map(|i| &imports[i]), to_index.map(|i| &imports[i])) else {
+        return (StatusCode::NOT_FOUND, json_error_response("The imports to compare were not found."));
+    };
+    let data_pk = match db.fetch_dataset(&id, None, None, 1, 0, None).await {
+        Some(row_set) => row_set.dataset["options"]["data_pk"].as_str().map(|pk| pk.to_string()),
+        None => None,
+    };
+    let Some(pk) = data_pk else {
+        return (StatusCode::BAD_REQUEST, json_error_response("Only datasets imported with a primary key column (data_pk) can be compared."));
+    };
+    let (Some(from_rows), Some(to_rows)) = (db.fetch_import_rows(&id, &from.import_id).await, db.fetch_import_rows(&id, &to.import_id).await) else {
+        return (StatusCode::CONFLICT, json_error_response("The rows of one of these imports are no longer kept."));
+    };
+    let diff = diff_rows(&from_rows, &to_rows, &pk);
+    (StatusCode::OK, Json(json!({
+        "id": id,
+        "key": pk,
+        "from": from,
+        "to": to,
+        "summary": {
+            "added": diff.added.len(),
+            "removed": diff.removed.len(),
+            "changed": diff.changed.len(),
+            "unchanged": diff.unchanged
+        },
+        "added": diff.added,
+        "removed": diff.removed,
+        "changed": diff.changed
+    })))
+}
+
+pub async fn list_datasets(Query(params): Query<QueryFilterParams>, headers: HeaderMap) -> impl IntoResponse {
+    let db = get_storage_instance().await;
     let criteria = params.to_search_criteria();
     let sort_criteria = params.to_list_sort_criteria();
     let (start, limit) = params.to_pagination();
+    let mut validators = HeaderMap::new();
+    if let (Some(modified), count) = db.datasets_modified(criteria.clone()).await {
+        // the count changes the ETag when matching datasets are added or removed
+        let etag = build_etag(&format!("datasets:{}", count), &modified, &params.to_cache_key());
+        validators = validator_headers(&etag, &modified);
+        if is_not_modified(&headers, &etag, &modified) {
+            return (StatusCode::NOT_MODIFIED, validators).into_response();
+        }
+    }
     let (total, rows) = db.get_datasets(criteria, limit, start, sort_criteria).await;
     let response = json!({
         "total": total.unwrap_or(0),
@@ -100,13 +445,13 @@ pub async fn list_datasets(Query(params): Query<QueryFilterParams>) -> impl Into
         "limit": limit,
         "rows": rows
     });
-    (StatusCode::OK, Json(response))
+    (StatusCode::OK, validators, Json(response)).into_response()
 }
 
 pub async fn welcome() -> impl IntoResponse {
     let response = json!({
         "title": "Spreadsheet to JSON API",
-        "description": "Upload spreadsheets (Excel: xlsx, xlsb, xls, LibreOffice: odt, CSV and TSV), convert them to JSON and create a Web endpoint",
+        "description": "Upload spreadsheets (Excel: xlsx, xlsb, xls, LibreOffice: odt, CSV and TSV) JSON, JSON Lines, Parquet or Arrow files, convert them to JSON and create a Web endpoint",
         "version": "0.1.0",
         "max_upate_size": get_max_upload_size(),
         "max_body_size": get_max_body_size(),
@@ -124,9 +469,39 @@ pub async fn welcome() -> impl IntoResponse {
                   "lines": "The number of lines to read",
                   "cols": "Column settings",
                   "sheet_index": "The index of the sheet to read",
-                  "header_index": "The index of the header row"
+                  "sheet_name": "The name of the sheet to read, instead of sheet_index",
+                  "header_index": "The index of the header row",
+                  "header_rows": "Number of header rows to join per column, e.g. Q1 / Revenue",
+                  "no_header": "true if the first row holds data. Columns are named by keys or col_a, col_b ...",
+                  "delimiter": "CSV/TSV delimiter such as ; | or tab, detected in preview mode",
+                  "quote": "CSV quote character, default double quote",
+                  "escape": "CSV escape character, e.g. backslash, by default quotes are doubled",
+                  "encoding": "utf-8, utf-16 or windows-1252, detected from the byte order mark or content",
+                  "decimal_comma": "true if numbers use a decimal comma, detected in preview mode",
+                  "thousands": "Thousands separator in numbers, e.g. . or space",
+                  "range": "Cell region to read, e.g. B4:H200, with the headers in its first row"
                 },
-                "description": "Upload a spreadsheet file"
+                "description": "Upload a spreadsheet file. In preview mode the response includes a per-column type inference report"
+            },
+            "import-url": {
+                "method": "POST",
+                "path": "/import-url",
+                "type": "application/json",
+                "params": {
+                  "url": "http(s) URL of a spreadsheet, including Google Sheets links published as CSV",
+                  "mode": "The read mode to use (sync or preview)",
+                  "sheet_index": "The index of the sheet to read",
+                  "header_index": "The index of the header row",
+                  "header_rows": "Number of header rows to join per column, e.g. Q1 / Revenue",
+                  "no_header": "true if the first row holds data. Columns are named by keys or col_a, col_b ...",
+                  "delimiter": "CSV/TSV delimiter such as ; | or tab, detected in preview mode",
+                  "quote": "CSV quote character, default double quote",
+                  "escape": "CSV escape character, e.g. backslash, by default quotes are doubled",
+                  "encoding": "utf-8, utf-16 or windows-1252, detected from the byte order mark or content",
+                  "decimal_comma": "true if numbers use a decimal comma, detected in preview mode",
+                  "thousands": "Thousands separator in numbers, e.g. . or space"
+                },
+                "description": "Download a remote spreadsheet within the maximum upload size and process it like an upload"
             },
             "process": {
                 "method": "PUT",
@@ -139,7 +514,29 @@ pub async fn welcome() -> impl IntoResponse {
                   "lines": "The number of lines to read",
                   "cols": "Column settings",
                   "sheet_index": "The index of the sheet to read",
-                  "header_index": "The index of the header row"
+                  "sheet_name": "The name of the sheet to read, instead of sheet_index",
+                  "header_index": "The index of the header row",
+                  "header_rows": "Number of header rows to join per column, e.g. Q1 / Revenue",
+                  "no_header": "true if the first row holds data. Columns are named by keys or col_a, col_b ...",
+                  "delimiter": "CSV/TSV delimiter such as ; | or tab, detected in preview mode",
+                  "quote": "CSV quote character, default double quote",
+                  "escape": "CSV escape character, e.g. backslash, by default quotes are doubled",
+                  "encoding": "utf-8, utf-16 or windows-1252, detected from the byte order mark or content",
+                  "decimal_comma": "true if numbers use a decimal comma, detected in preview mode",
+                  "thousands": "Thousands separator in numbers, e.g. . or space",
+                  "key_style": "Generated keys without a header row: letters (col_a, default) or numbers (c01)",
+                  "range": "Cell region to read, e.g. B4:H200, with the headers in its first row",
+                  "dataset_id": "Re-import into an existing dataset",
+                  "timezone": "Fixed offset (e.g. +01:00) or IANA name (e.g. Europe/London) for datetimes without a timezone",
+                  "index_cols": "Comma separated column keys to index for filtering",
+                  "storage": "shared or dedicated rows collection (large datasets are moved to their own collection automatically)",
+                  "data_pk": "Column key identifying rows across imports. Incoming rows replace those with the same key",
+                  "publish": "false to store the import as a draft that does not change the dataset until published",
+                  "source_url": "URL the file was imported from, re-fetched according to refresh_schedule",
+                  "refresh_schedule": "Cron-like UTC schedule (minute hour day month weekday or @hourly, @daily, @weekly, @monthly) for re-imports from source_url",
+                  "schema_policy": "How to treat schema differences with an existing dataset: strict (default), additive or lenient",
+                  "sheets": "all or comma separated sheet indices and/or names, imported as one dataset per sheet",
+                  "workbook_id": "Update the datasets of a previous multi-sheet import"
                 },
                 "description": "Re-process an uploaded spreadsheet file with new criteria"
             },
@@ -153,19 +550,78 @@ pub async fn welcome() -> impl IntoResponse {
                   "f": "Field name (snake_cased)",
                   "v": "Field value",
                   "o": "Comparison operator (eq, ne, gt, gte, lt, lte, in, nin, regex, starts, ends)",
+                  "dt": "Data type of the comparison value (string, int, float, date, datetime, bool)",
                   "sort": "Sort field",
                   "dir": "Sort direction (asc or desc)",
+                  "import": "Restrict rows to those written by one import ID listed in the dataset's imports",
                   "start": "Start offset for pagination",
-                  "limit": "Number of rows per page"
+                  "limit": "Number of rows per page",
+                  "format": "json (default), parquet or arrow to download the rows as a file typed by the dataset schema"
                 },
                 "description": "Re-process an uploaded spreadsheet file with new criteria"
             },
+            "schema": {
+                "method": "GET",
+                "path": "/datasets/:dataset_id/schema",
+                "path_params": {
+                  ":dataset_id": "The ID of the dataset"
+                },
+                "description": "Column schema of a dataset (key, label, data type, nullable, format and original header)"
+            },
+            "migrations": {
+                "method": "POST",
+                "path": "/datasets/:dataset_id/schema/migrations",
+                "type": "application/json",
+                "params": {
+                  "op": "rename, retype or drop",
+                  "key": "The column key to migrate",
+                  "to": "The new column key (rename only)",
+                  "data_type": "The target data type: string, int, float, date, datetime or bool (retype only)"
+                },
+                "description": "Rename, retype or drop a column across all rows of a dataset"
+            },
+            "indexes": {
+                "methods": ["GET", "POST", "DELETE"],
+                "path": "/datasets/:dataset_id/indexes",
+                "params": {
+                  "fields": "Comma separated column keys to index (POST)"
+                },
+                "description": "List, declare or drop (DELETE /datasets/:dataset_id/indexes/:field) indexed columns of a dataset"
+            },
+            "imports": {
+                "method": "GET",
+                "path": "/datasets/:dataset_id/imports",
+                "description": "Import history of a dataset, flagging the current import and versions that can be restored"
+            },
+            "restore": {
+                "method": "POST",
+                "path": "/datasets/:dataset_id/imports/:import_id/restore",
+                "description": "Roll a dataset back to the rows and schema of an earlier import"
+            },
+            "publish": {
+                "method": "POST",
+                "path": "/datasets/:dataset_id/imports/:import_id/publish",
+                "description": "Make a draft import current",
+                "query_params": {
+                  "force": "true to publish a draft even if another import was published after it was created"
+                }
+            },
+            "diff": {
+                "method": "GET",
+                "path": "/datasets/:dataset_id/diff",
+                "query_params": {
+                  "from": "Import ID of the earlier version, by default the import before `to` or the current import when `to` is a draft",
+                  "to": "Import ID of the later version, by default the current import"
+                },
+                "description": "Rows added, removed and changed (with per-field before and after values) between two imports of a keyed dataset"
+            },
             "datasets": {
                 "method": "GET",
                 "path": "/datasets",
                 "query_params": {
                   "q": "Search query within file name, titles, or descriptions",
                   "u": "user reference or ID",
+                  "workbook": "Workbook ID of a multi-sheet import",
                   "sort": "Sort field (created or updated), default by newest first",
                   "dir": "Sort direction (asc or desc), default desc for created",
                   "start": "Start offset for pagination",
@@ -173,6 +629,11 @@ pub async fn welcome() -> impl IntoResponse {
                 },
                 "description": "List imported datasets by user"
             },
+            "inspect": {
+                "method": "GET",
+                "path": "/inspect/:file_name",
+                "description": "List the sheets of an uploaded workbook with their dimensions, column keys and header labels"
+            },
             "check-file": {
                 "method": "GET",
                 "path": "/check-file/:file_name",
@@ -188,11 +649,11 @@ pub async fn not_found() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, json_error_response("The requested resource was not found."))
 }
 
-async fn process_asset_common(
+pub(crate) async fn process_asset_common(
     file_path: PathBuf,
     core_options: &CoreOptions,
     save_rows: bool,
-) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
     let default_limit: usize = dotenv::var("DEFAULT_LIMIT")
         .unwrap_or(String::from("1000"))
         .parse()
@@ -245,25 +706,78 @@ async fn process_asset_common(
         if let Some(cols_str) = core_options.cols.clone() {
             col_values = serde_json::from_str(&cols_str).unwrap_or_else(|_| vec![]);
         }
-        if col_values.len() < 1 && head_keys.len() > 0 {
+        let no_header = core_options.no_header.unwrap_or(false);
+        // without a header row keys name the columns once the first row is restored
+        if col_values.len() < 1 && head_keys.len() > 0 && !no_header {
             col_values = head_keys.iter().map(|k| json!({ "key": k })).collect();
         }
-        let top_index = core_options.header_index.unwrap_or(0);
-        let h_index = if top_index < 256 {
-            top_index as u8
+        let range = match core_options.range.clone() {
+            Some(spec) => Some(CellRange::parse(&spec).map_err(|message| (StatusCode::BAD_REQUEST, json_error_response(&message)))?),
+            None => None,
+        };
+        if let Some(tz) = core_options.timezone.as_deref() {
+            if ImportTimezone::parse(tz).is_none() {
+                let message = format!("Unknown timezone `{}`, use a fixed offset such as +01:00 or an IANA name such as Europe/London", tz);
+                return Err((StatusCode::BAD_REQUEST, json_error_response(&message)));
+            }
+        }
+        let header_index = core_options.header_index.unwrap_or(0);
+        let header_rows = core_options.header_rows.unwrap_or(1).max(1);
+        // rows below the first header row are read as data and merged into the headers afterwards
+        let read_limit = if no_header {
+            // the first row is read as the header row
+            limit.saturating_sub(1).max(1)
         } else {
-            0u8
+            limit + header_rows - 1
+        };
+        let (top_index, read_limit) = match &range {
+            Some(cell_range) => (cell_range.header_row(header_index), read_limit.min(cell_range.data_rows(header_index))),
+            None => (header_index, read_limit),
         };
+        if top_index > MAX_HEADER_ROW {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                json_error_response(&format!("The header row {} is beyond the supported maximum of {}", top_index, MAX_HEADER_ROW)),
+            ));
+        }
+        let h_index = top_index as u8;
         let import_id_opt = core_options.import_id.clone();
-        let s_index = core_options.sheet_index.unwrap_or(0);
-        let opts = OptionSet::new(&file_path.to_string_lossy().to_string())
+        let s_index = match core_options.sheet_name.clone() {
+            Some(sheet_name) => resolve_sheet_name(&file_path, &sheet_name).await?,
+            None => core_options.sheet_index.unwrap_or(0),
+        };
+        // CSV and TSV files in other dialects are rewritten as UTF-8 CSV for the parser
+        let dialect_options = core_options.dialect_options();
+        let mut read_path = file_path.clone();
+        let mut dialect: Option<Value> = None;
+        if is_delimited_file(&file_path) && (is_preview || !dialect_options.is_empty()) {
+            match normalize_delimited_file(&file_path, &dialect_options, is_preview) {
+                Ok((normalized_path, csv_dialect)) => {
+                    read_path = normalized_path;
+                    dialect = Some(json!(csv_dialect));
+                }
+                Err(message) => return Err((StatusCode::BAD_REQUEST, json_error_response(&message))),
+            }
+        }
+        let opts = OptionSet::new(&read_path.to_string_lossy().to_string())
             .set_read_mode(&mode_key)
-            .max_row_count(limit as u32)
+            .max_row_count(read_limit as u32)
             .sheet_index(s_index as u32)
             .header_row(h_index)
             .override_columns(&col_values);
-        match process_spreadsheet_immediate(&opts).await {
-            Ok(result) => {
+        // JSON, Parquet and Arrow files skip the spreadsheet parser and keep their nested documents for storage
+        let parsed = if is_json_file(&file_path) {
+            read_json_file(&file_path, limit).map(|file| (file.result, file.rows, Some(file.documents)))
+        } else if is_columnar_file(&file_path) {
+            read_columnar_file(&file_path, limit).map(|file| (file.result, file.rows, Some(file.documents)))
+        } else {
+            process_spreadsheet_immediate(&opts)
+                .await
+                .map(|result| (result.to_json(), result.to_vec().into_iter().map(|r| json!(r)).collect::<Vec<Value>>(), None))
+                .map_err(|_| "Failed to process file".to_string())
+        };
+        match parsed {
+            Ok((mut response, mut rows, documents)) => {
                 let file_name_clone = file_name.clone();
                 tokio::spawn(async move {
                     let file_name = file_name_clone;
@@ -273,16 +787,54 @@ async fn process_asset_common(
                     }
                 });
                 
+                if let Some(csv_dialect) = dialect {
+                    response["dialect"] = csv_dialect;
+                }
+                if let Some(cell_range) = range.as_ref().filter(|_| documents.is_none()) {
+                    cell_range.select_columns(&mut response, &mut rows);
+                }
+                if documents.is_some() {
+                    // spreadsheet layout options do not apply to JSON
+                } else if no_header {
+                    let first_col = range.as_ref().map(|cell_range| cell_range.start_col).unwrap_or(0);
+                    let key_style = core_options.key_style.clone().unwrap_or_default().to_lowercase();
+                    restore_header_row(&mut response, &mut rows, &head_keys, first_col, &key_style);
+                    response["data"] = json!(rows);
+                } else if range.is_some() || header_rows > 1 {
+                    combine_header_rows(&mut response, &mut rows, header_rows);
+                    response["data"] = json!(rows);
+                }
                 if save_rows {
-                    let mut response = result.to_json();
-                    let db = get_db_instance().await;
-                    let core_options_json = core_options.to_json_value();
-                    let rows = result
-                        .to_vec()
-                        .into_iter()
-                        .map(|r| json!(r))
-                        .collect::<Vec<Value>>();
-                    let import_info = db.save_import_with_rows(&core_options_json, &rows, import_id_opt, append).await;
+                    let db = get_storage_instance().await;
+                    let mut core_options_json = core_options.to_json_value();
+                    core_options_json["sheet_index"] = json!(s_index);
+                    let mut schema = build_schema(&response, &rows, &col_values);
+                    let mut schema_diff: Option<SchemaDiff> = None;
+                    if let Some(dataset_id) = core_options.dataset_id.clone() {
+                        if let Some(stored_schema) = db.fetch_dataset_schema(&dataset_id).await {
+                            let policy = SchemaPolicy::from_key(&core_options.schema_policy.clone().unwrap_or_default());
+                            let diff = SchemaDiff::new(&stored_schema, &schema);
+                            if !diff.is_accepted(&policy) {
+                                return Err((
+                                    StatusCode::CONFLICT,
+                                    Json(json!({
+                                        "valid": false,
+                                        "message": "The file does not match the schema of the dataset",
+                                        "schema_policy": policy.to_key(),
+                                        "diff": diff
+                                    }))
+                                ));
+                            }
+                            if append || policy == SchemaPolicy::Additive {
+                                schema = merge_schema(&stored_schema, &schema);
+                            }
+                            if !diff.is_empty() {
+                                schema_diff = Some(diff);
+                            }
+                        }
+                    }
+                    let saved_rows = documents.as_ref().unwrap_or(&rows);
+                    let import_info = db.save_import_with_rows(&core_options_json, saved_rows, &schema, import_id_opt, append).await;
                     
                     if let Some((dataset_id, import_id, num_rows)) = import_info {
                         let max_output_rows = get_max_output_rows();
@@ -292,28 +844,36 @@ async fn process_asset_common(
                             (false, num_rows)
                         };
                         if limit_rows {
-                            response["data"] = json!(rows[..max_output_rows]);
+                            response["data"] = json!(saved_rows[..max_output_rows]);
                         } else {
-                            response["data"] = json!(rows);
+                            response["data"] = json!(saved_rows);
                         }
                         response["dataset"] = json!({
                             "id": json!(dataset_id),
                             "import_id": json!(import_id),
+                            "draft": core_options.publish == Some(false),
                             "rows": num_rows,
-                            "showing": num_showing
+                            "showing": num_showing,
+                            "schema": schema
                         });
+                        if let Some(diff) = schema_diff {
+                            response["dataset"]["schema_diff"] = json!(diff);
+                        }
                     }
-                    Ok(Json(response).into_response()) 
+                    Ok(Json(response)) 
                 } else {
-                    Ok(Json(result.to_json()).into_response())
+                    if is_preview {
+                        response["inference"] = json!(infer_columns(&response, &rows));
+                    }
+                    Ok(Json(response))
                 }
                 // Return success response
             }
-            Err(_) => {
+            Err(message) => {
                 remove_uploaded_file(&file_path);
                 Err((
                     StatusCode::NOT_ACCEPTABLE,
-                    json_error_response("Failed to process file")
+                    json_error_response(&message)
                 )) // Return error response
             }
         }
@@ -322,6 +882,93 @@ async fn process_asset_common(
     }
 }
 
+async fn resolve_sheet_name(file_path: &PathBuf, sheet_name: &str) -> Result<usize, (StatusCode, Json<Value>)> {
+    let Some(names) = read_sheet_names(file_path).await else {
+        remove_uploaded_file(file_path);
+        return Err((StatusCode::NOT_ACCEPTABLE, json_error_response("Failed to process file")));
+    };
+    find_sheet(&names, sheet_name).ok_or((
+        StatusCode::BAD_REQUEST,
+        json_error_response(&format!("The workbook has no sheet named {}", sheet_name.trim())),
+    ))
+}
+
+/// Import the selected sheets of a workbook as one dataset per sheet, grouped by a workbook record.
+/// Datasets previously imported with the same workbook ID are updated sheet by sheet.
+async fn process_workbook(
+    file_path: PathBuf,
+    core_options: &CoreOptions,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let spec = core_options.sheets.clone().unwrap_or_default();
+    let Some(names) = read_sheet_names(&file_path).await else {
+        remove_uploaded_file(&file_path);
+        return Err((StatusCode::NOT_ACCEPTABLE, json_error_response("Failed to process file")));
+    };
+    let indices = select_sheets(&spec, &names).map_err(|message| (StatusCode::BAD_REQUEST, json_error_response(&message)))?;
+    let workbook_id = core_options.workbook_id.clone().unwrap_or(ObjectId::new().to_hex());
+    if ObjectId::from_str(&workbook_id).is_err() {
+        return Err((StatusCode::BAD_REQUEST, json_error_response("Invalid workbook ID")));
+    }
+    let db = get_storage_instance().await;
+    let (_, existing) = db.get_datasets(Some(doc! { "options.workbook_id": &workbook_id }), names.len() as u64, 0, None).await;
+    let mut datasets: Vec<Value> = vec![];
+    let mut imported: Vec<(usize, String)> = vec![];
+    let mut first_error: Option<(StatusCode, Json<Value>)> = None;
+    for index in indices {
+        let sheet_name = names[index].clone();
+        let mut sheet_options = core_options.clone();
+        sheet_options.sheets = None;
+        sheet_options.sheet_index = Some(index);
+        sheet_options.sheet_name = Some(sheet_name.clone());
+        sheet_options.workbook_id = Some(workbook_id.clone());
+        // match sheets by name first as tabs may have been reordered
+        let same_name = existing.iter().find(|dset| dset["options"]["sheet_name"].as_str().map(|name| name.eq_ignore_ascii_case(&sheet_name)).unwrap_or(false));
+        let same_index = existing.iter().find(|dset| dset["options"]["sheet_name"].is_null() && dset["options"]["sheet_index"].as_u64() == Some(index as u64));
+        sheet_options.dataset_id = same_name.or(same_index).and_then(|dset| dset["_id"].as_str().map(|id| id.to_string()));
+        if let Some(title) = core_options.title.clone() {
+            sheet_options.title = Some(format!("{}: {}", title, sheet_name));
+        }
+        match process_asset_common(file_path.clone(), &sheet_options, true).await {
+            Ok(response) => {
+                if let Some(dataset_id) = response.0["dataset"]["id"].as_str() {
+                    imported.push((index, dataset_id.to_string()));
+                }
+                datasets.push(json!({
+                    "sheet_index": index,
+                    "sheet_name": sheet_name,
+                    "valid": true,
+                    "dataset": response.0["dataset"]
+                }));
+            }
+            Err((status, message)) => {
+                datasets.push(json!({
+                    "sheet_index": index,
+                    "sheet_name": sheet_name,
+                    "valid": false,
+                    "status": status.as_u16(),
+                    "message": message.0["message"]
+                }));
+                first_error.get_or_insert((status, message));
+            }
+        }
+    }
+    if imported.is_empty() {
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+    }
+    let previous = db.fetch_workbook(&workbook_id).await;
+    let workbook = WorkbookRecord::update(previous.as_ref(), &workbook_id, &core_options.filename.clone().unwrap_or_default(), &names, &imported);
+    if !db.save_workbook(&workbook).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, json_error_response("Failed to save the workbook record")));
+    }
+    Ok(Json(json!({
+        "valid": true,
+        "workbook": workbook,
+        "datasets": datasets
+    })))
+}
+
 fn json_error_response(message: &str) -> Json<serde_json::Value> {
     Json(json!({
         "valid": false,
@@ -0,0 +1,533 @@
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::chrono::{NaiveDate, NaiveDateTime};
+use spreadsheet_to_json::{heck::ToTitleCase, indexmap::IndexMap};
+use std::cmp::Reverse;
+
+use crate::dates::{normalize_date_format, parse_date_value, ImportTimezone};
+use crate::documents::{insert_path, take_path};
+use crate::options::{CastDataType, SchemaPolicy};
+
+/// Persisted description of a single dataset column
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnSchema {
+  pub key: String,
+  pub label: String,
+  pub data_type: String,
+  pub nullable: bool,
+  pub format: Option<String>,
+  pub header: Option<String>,
+  // chrono or friendly pattern (e.g. dd/mm/yyyy) or `excel` for serial dates
+  pub date_format: Option<String>,
+}
+
+impl ColumnSchema {
+  pub fn new(key: &str, data_type: &CastDataType) -> Self {
+    ColumnSchema {
+      key: key.to_string(),
+      label: key.to_title_case(),
+      data_type: data_type.to_key().to_string(),
+      nullable: false,
+      format: None,
+      header: None,
+      date_format: None,
+    }
+  }
+
+  pub fn cast_type(&self) -> CastDataType {
+    CastDataType::from_str(&self.data_type)
+  }
+}
+
+/// Per-column type inference returned with previews so users can confirm or override column types
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnInference {
+  pub key: String,
+  pub data_type: String,
+  pub confidence: f64,
+  pub null_ratio: f64,
+  pub samples: Vec<Value>,
+  pub warnings: Vec<String>,
+}
+
+/// Type change of a column between the stored schema and an incoming import
+#[derive(Debug, Clone, Serialize)]
+pub struct TypeChange {
+  pub key: String,
+  pub from: String,
+  pub to: String,
+}
+
+impl TypeChange {
+  /// int to float and date to datetime can hold all previous values
+  pub fn is_widening(&self) -> bool {
+    let from = CastDataType::from_str(&self.from);
+    let to = CastDataType::from_str(&self.to);
+    from != to && merge_types(&from, &to) == to
+  }
+}
+
+/// Differences between a stored dataset schema and the schema of an incoming import
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaDiff {
+  pub added: Vec<String>,
+  pub removed: Vec<String>,
+  pub changed: Vec<TypeChange>,
+}
+
+impl SchemaDiff {
+  pub fn new(stored: &[ColumnSchema], incoming: &[ColumnSchema]) -> Self {
+    let added = incoming.iter().filter(|c| !stored.iter().any(|s| s.key == c.key)).map(|c| c.key.clone()).collect();
+    let removed = stored.iter().filter(|s| !incoming.iter().any(|c| c.key == s.key)).map(|s| s.key.clone()).collect();
+    let mut changed: Vec<TypeChange> = vec![];
+    for column in incoming {
+      if let Some(prev) = stored.iter().find(|s| s.key == column.key) {
+        // columns without any values in the new file carry no type information
+        if prev.data_type != column.data_type && !(column.nullable && column.cast_type() == CastDataType::String) {
+          changed.push(TypeChange {
+            key: column.key.clone(),
+            from: prev.data_type.clone(),
+            to: column.data_type.clone(),
+          });
+        }
+      }
+    }
+    SchemaDiff { added, removed, changed }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.added.len() < 1 && self.removed.len() < 1 && self.changed.len() < 1
+  }
+
+  pub fn is_accepted(&self, policy: &SchemaPolicy) -> bool {
+    match policy {
+      SchemaPolicy::Strict => self.is_empty(),
+      SchemaPolicy::Additive => self.removed.is_empty() && self.changed.iter().all(|c| c.is_widening()),
+      SchemaPolicy::Lenient => true,
+    }
+  }
+}
+
+/// Keep stored columns that are absent from the incoming schema, e.g. when appending rows
+pub fn merge_schema(stored: &[ColumnSchema], incoming: &[ColumnSchema]) -> Vec<ColumnSchema> {
+  let mut columns = incoming.to_vec();
+  for column in stored {
+    if !columns.iter().any(|c| c.key == column.key) {
+      let mut prev = column.clone();
+      prev.nullable = true;
+      columns.push(prev);
+    }
+  }
+  columns
+}
+
+/// Column migration applied to the stored schema and all rows of a dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaMigration {
+  // rename, retype or drop
+  pub op: String,
+  pub key: String,
+  // new key when renaming
+  pub to: Option<String>,
+  // target data type when retyping
+  pub data_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationOp {
+  Rename(String),
+  Retype(CastDataType),
+  Drop,
+}
+
+impl SchemaMigration {
+  pub fn to_op(&self) -> Result<MigrationOp, String> {
+    match self.op.to_lowercase().as_str() {
+      "rename" => match self.to.clone() {
+        Some(to) if !to.trim().is_empty() => Ok(MigrationOp::Rename(to.trim().to_string())),
+        _ => Err("A rename migration requires a new key in `to`".to_string()),
+      },
+      "retype" | "cast" => match self.data_type.clone() {
+        Some(dt) => Ok(MigrationOp::Retype(CastDataType::from_str(&dt))),
+        None => Err("A retype migration requires a `data_type`".to_string()),
+      },
+      "drop" | "remove" | "delete" => Ok(MigrationOp::Drop),
+      _ => Err(format!("Unknown migration operation `{}`", self.op)),
+    }
+  }
+
+  /// Apply the migration to a schema, validating that the column exists and the new key is free
+  pub fn apply_to_schema(&self, schema: &[ColumnSchema]) -> Result<Vec<ColumnSchema>, String> {
+    let op = self.to_op()?;
+    let Some(index) = schema.iter().position(|c| c.key == self.key) else {
+      return Err(format!("The column `{}` does not exist", self.key));
+    };
+    let mut columns = schema.to_vec();
+    match op {
+      MigrationOp::Rename(to) => {
+        if columns.iter().any(|c| c.key == to) {
+          return Err(format!("The column `{}` already exists", to));
+        }
+        columns[index].key = to;
+      },
+      MigrationOp::Retype(dt) => {
+        columns[index].data_type = dt.to_key().to_string();
+      },
+      MigrationOp::Drop => {
+        columns.remove(index);
+      },
+    }
+    Ok(columns)
+  }
+
+  /// Apply the migration to the data of one row. Returns false when the row has no value for the column.
+  pub fn apply_to_row(&self, row_data: &mut Document) -> bool {
+    let Ok(op) = self.to_op() else {
+      return false;
+    };
+    // keys of nested JSON documents are dotted paths
+    let Some(value) = take_path(row_data, &self.key) else {
+      return false;
+    };
+    match op {
+      MigrationOp::Rename(to) => insert_path(row_data, &to, value),
+      MigrationOp::Retype(_) => insert_path(row_data, &self.key, self.convert_value(&value)),
+      MigrationOp::Drop => {}
+    }
+    true
+  }
+
+  /// Schema kept with an import record with the migration applied, so restoring or publishing the import
+  /// matches its migrated rows. None when the import has no such column.
+  pub fn apply_to_import(&self, record_schema: &Value) -> Option<Value> {
+    let schema = serde_json::from_value::<Vec<ColumnSchema>>(record_schema.clone()).ok()?;
+    self.apply_to_schema(&schema).ok().and_then(|columns| serde_json::to_value(columns).ok())
+  }
+
+  /// Convert a stored value for a retype migration. Values that cannot be converted are left unchanged,
+  /// as with MongoDB's `$convert` and `onError`.
+  pub fn convert_value(&self, value: &Bson) -> Bson {
+    let Ok(MigrationOp::Retype(dt)) = self.to_op() else {
+      return value.clone();
+    };
+    let number = match value {
+      Bson::Double(n) => Some(*n),
+      Bson::Int32(n) => Some(*n as f64),
+      Bson::Int64(n) => Some(*n as f64),
+      _ => None,
+    };
+    let text = value.as_str().map(|s| s.trim().to_string());
+    let converted = match dt.to_key() {
+      "int" => number.map(|n| n as i64).or(text.as_ref().and_then(|s| s.parse::<i64>().ok())).map(Bson::Int64),
+      "float" => number.or(text.as_ref().and_then(|s| s.parse::<f64>().ok())).map(Bson::Double),
+      "bool" => match value {
+        Bson::Boolean(b) => Some(Bson::Boolean(*b)),
+        _ => number.map(|n| n != 0.0).or(text.as_ref().and_then(|s| s.to_lowercase().parse::<bool>().ok())).map(Bson::Boolean),
+      },
+      "date" | "datetime" => parse_date_value(value, !dt.is_datetime(), None, &ImportTimezone::default()),
+      _ => match value {
+        Bson::Null | Bson::String(_) => None,
+        Bson::Boolean(b) => Some(Bson::String(b.to_string())),
+        _ => number.map(|n| Bson::String(n.to_string())),
+      },
+    };
+    converted.unwrap_or(value.clone())
+  }
+}
+
+const MAX_INFERENCE_SAMPLES: usize = 5;
+
+/// Detect the data type of a single cell value. Nulls and empty strings have no type.
+pub fn detect_value_type(value: &Value) -> Option<CastDataType> {
+  match value {
+    Value::Null => None,
+    Value::Bool(_) => Some(CastDataType::Boolean),
+    Value::Number(num) => {
+      if num.is_i64() || num.is_u64() {
+        Some(CastDataType::Integer)
+      } else {
+        Some(CastDataType::Float)
+      }
+    },
+    Value::String(text) => detect_string_type(text),
+    _ => Some(CastDataType::String),
+  }
+}
+
+fn detect_string_type(text: &str) -> Option<CastDataType> {
+  let trimmed = text.trim();
+  if trimmed.is_empty() {
+    return None;
+  }
+  if NaiveDateTime::parse_from_str(trimmed, "%Y-%m-%dT%H:%M:%S%.fZ").is_ok()
+    || NaiveDateTime::parse_from_str(trimmed, "%Y-%m-%dT%H:%M:%S%.f").is_ok()
+    || NaiveDateTime::parse_from_str(trimmed, "%Y-%m-%d %H:%M:%S").is_ok() {
+    return Some(CastDataType::DateTime);
+  }
+  if NaiveDate::parse_from_str(trimmed, "%Y-%m-%d").is_ok() {
+    return Some(CastDataType::Date);
+  }
+  if trimmed.parse::<i64>().is_ok() {
+    return Some(CastDataType::Integer);
+  }
+  if trimmed.parse::<f64>().is_ok() {
+    return Some(CastDataType::Float);
+  }
+  match trimmed.to_lowercase().as_str() {
+    "true" | "false" => Some(CastDataType::Boolean),
+    _ => Some(CastDataType::String),
+  }
+}
+
+/// Merge two detected types into the narrowest type that can hold both
+pub fn merge_types(current: &CastDataType, next: &CastDataType) -> CastDataType {
+  if current == next {
+    return current.clone();
+  }
+  match (current, next) {
+    (CastDataType::Integer, CastDataType::Float) | (CastDataType::Float, CastDataType::Integer) => CastDataType::Float,
+    (CastDataType::Date, CastDataType::DateTime) | (CastDataType::DateTime, CastDataType::Date) => CastDataType::DateTime,
+    _ => CastDataType::String,
+  }
+}
+
+/// Column keys in the order returned by the parser, falling back to the order of first appearance in rows
+pub fn extract_keys(result: &Value, rows: &[Value]) -> Vec<String> {
+  for field in ["keys", "fields"] {
+    if let Some(items) = result.get(field).and_then(|v| v.as_array()) {
+      let keys = items.iter().filter_map(|k| k.as_str().map(|s| s.to_string())).collect::<Vec<String>>();
+      if !keys.is_empty() {
+        return keys;
+      }
+    }
+  }
+  let mut keys: Vec<String> = vec![];
+  for row in rows {
+    if let Some(obj) = row.as_object() {
+      for key in obj.keys() {
+        if !keys.contains(key) {
+          keys.push(key.to_owned());
+        }
+      }
+    }
+  }
+  keys
+}
+
+/// Original header labels keyed by column key, if the parser exposes them
+fn extract_headers(result: &Value, keys: &[String]) -> IndexMap<String, String> {
+  let mut headers = IndexMap::new();
+  if let Some(items) = result.get("headers").and_then(|v| v.as_array()) {
+    for (index, item) in items.iter().enumerate() {
+      if let (Some(key), Some(header)) = (keys.get(index), item.as_str()) {
+        headers.insert(key.to_owned(), header.to_string());
+      }
+    }
+  }
+  headers
+}
+
+/// Build the column schema for an import from the parser result, its rows and any column overrides
+pub fn build_schema(result: &Value, rows: &[Value], col_overrides: &[Value]) -> Vec<ColumnSchema> {
+  let keys = extract_keys(result, rows);
+  let headers = extract_headers(result, &keys);
+  let mut columns: Vec<ColumnSchema> = vec![];
+  for key in keys.iter() {
+    let mut detected: Option<CastDataType> = None;
+    let mut nullable = false;
+    for row in rows {
+      match row.get(key).and_then(detect_value_type) {
+        Some(dt) => {
+          detected = Some(match detected {
+            Some(current) => merge_types(&current, &dt),
+            None => dt,
+          });
+        },
+        None => {
+          nullable = true;
+        }
+      }
+    }
+    let mut column = ColumnSchema::new(key, &detected.unwrap_or(CastDataType::String));
+    column.nullable = nullable;
+    column.header = headers.get(key).cloned();
+    if let Some(col_def) = col_overrides.iter().find(|c| c.get("key").and_then(|k| k.as_str()) == Some(key.as_str())) {
+      if let Some(label) = col_def.get("label").and_then(|v| v.as_str()) {
+        column.label = label.to_string();
+      }
+      if let Some(format) = col_def.get("format").and_then(|v| v.as_str()) {
+        column.format = Some(format.to_string());
+        let dt = CastDataType::from_str(format);
+        if dt != CastDataType::String || format.eq_ignore_ascii_case("string") {
+          column.data_type = dt.to_key().to_string();
+        }
+      }
+      if let Some(date_format) = col_def.get("date_format").and_then(|v| v.as_str()) {
+        let fmt = normalize_date_format(date_format);
+        if !column.cast_type().is_datelike() {
+          let has_time = fmt.contains("%H") || fmt.contains("%M") || fmt.contains("%S");
+          column.data_type = if has_time { "datetime" } else { "date" }.to_string();
+        }
+        column.date_format = Some(fmt);
+      }
+    }
+    columns.push(column);
+  }
+  columns
+}
+
+/// Infer the most likely type of one column, with the share of values matching it and any mixed-type warnings
+pub fn infer_column(key: &str, rows: &[Value]) -> ColumnInference {
+  let mut type_counts: IndexMap<&'static str, usize> = IndexMap::new();
+  let mut merged: Option<CastDataType> = None;
+  let mut num_nulls = 0;
+  let mut samples: Vec<Value> = vec![];
+  for row in rows {
+    let value = row.get(key).unwrap_or(&Value::Null);
+    match detect_value_type(value) {
+      Some(dt) => {
+        *type_counts.entry(dt.to_key()).or_insert(0) += 1;
+        merged = Some(match merged {
+          Some(current) => merge_types(&current, &dt),
+          None => dt,
+        });
+        if samples.len() < MAX_INFERENCE_SAMPLES && !samples.contains(value) {
+          samples.push(value.to_owned());
+        }
+      },
+      None => {
+        num_nulls += 1;
+      }
+    }
+  }
+  let num_values = rows.len() - num_nulls;
+  let mut warnings: Vec<String> = vec![];
+  let mut data_type = merged.unwrap_or(CastDataType::String);
+  let mut num_matched = num_values;
+  // int/float and date/datetime widen cleanly, any other mix suggests the dominant type
+  if type_counts.len() > 1 && data_type == CastDataType::String {
+    let mut counts = type_counts.iter().map(|(k, c)| (*k, *c)).collect::<Vec<(&str, usize)>>();
+    counts.sort_by_key(|(_, c)| Reverse(*c));
+    let summary = counts.iter().map(|(k, c)| format!("{} ({})", k, c)).collect::<Vec<String>>().join(", ");
+    warnings.push(format!("mixed types: {}", summary));
+    data_type = CastDataType::from_str(counts[0].0);
+    num_matched = counts[0].1;
+  }
+  if num_values < 1 {
+    warnings.push("no values".to_string());
+  }
+  let confidence = if num_values > 0 { num_matched as f64 / num_values as f64 } else { 0.0 };
+  let null_ratio = if !rows.is_empty() { num_nulls as f64 / rows.len() as f64 } else { 0.0 };
+  ColumnInference {
+    key: key.to_string(),
+    data_type: data_type.to_key().to_string(),
+    confidence,
+    null_ratio,
+    samples,
+    warnings,
+  }
+}
+
+/// Build the inference report for all columns in a preview
+pub fn infer_columns(result: &Value, rows: &[Value]) -> Vec<ColumnInference> {
+  extract_keys(result, rows).iter().map(|key| infer_column(key, rows)).collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_build_schema_types() {
+    let rows = vec![
+      json!({ "id": 1, "name": "Alpha", "height": 1.5, "dob": "1990-05-01", "active": true }),
+      json!({ "id": 2, "name": null, "height": 2, "dob": "1991-06-12", "active": false }),
+    ];
+    let schema = build_schema(&json!({}), &rows, &[]);
+    let types = schema.iter().map(|c| c.data_type.as_str()).collect::<Vec<&str>>();
+    assert_eq!(types, vec!["int", "string", "float", "date", "bool"]);
+    assert!(schema[1].nullable);
+    assert!(!schema[0].nullable);
+    assert_eq!(schema[2].label, "Height");
+  }
+
+  #[test]
+  fn test_build_schema_date_format() {
+    let rows = vec![json!({ "dob": "25/12/1990" })];
+    let overrides = vec![json!({ "key": "dob", "date_format": "dd/mm/yyyy" })];
+    let schema = build_schema(&json!({}), &rows, &overrides);
+    assert_eq!(schema[0].data_type, "date");
+    assert_eq!(schema[0].date_format, Some("%d/%m/%Y".to_string()));
+  }
+
+  #[test]
+  fn test_infer_mixed_column() {
+    let rows = vec![
+      json!({ "code": 12 }),
+      json!({ "code": 14 }),
+      json!({ "code": "n/a" }),
+      json!({ "code": null }),
+    ];
+    let report = infer_column("code", &rows);
+    assert_eq!(report.data_type, "int");
+    assert_eq!(report.null_ratio, 0.25);
+    assert!((report.confidence - 2.0 / 3.0).abs() < 0.001);
+    assert_eq!(report.warnings.len(), 1);
+    assert_eq!(report.samples.len(), 3);
+  }
+
+  #[test]
+  fn test_schema_diff_policies() {
+    let stored = vec![
+      ColumnSchema::new("id", &CastDataType::Integer),
+      ColumnSchema::new("name", &CastDataType::String),
+    ];
+    let widened = vec![
+      ColumnSchema::new("id", &CastDataType::Float),
+      ColumnSchema::new("name", &CastDataType::String),
+      ColumnSchema::new("email", &CastDataType::String),
+    ];
+    let diff = SchemaDiff::new(&stored, &widened);
+    assert_eq!(diff.added, vec!["email"]);
+    assert_eq!(diff.changed.len(), 1);
+    assert!(!diff.is_accepted(&SchemaPolicy::Strict));
+    assert!(diff.is_accepted(&SchemaPolicy::Additive));
+    let dropped = vec![ColumnSchema::new("id", &CastDataType::Integer)];
+    let diff = SchemaDiff::new(&stored, &dropped);
+    assert_eq!(diff.removed, vec!["name"]);
+    assert!(!diff.is_accepted(&SchemaPolicy::Additive));
+    assert!(diff.is_accepted(&SchemaPolicy::Lenient));
+  }
+
+  #[test]
+  fn test_migration_apply_to_schema() {
+    let schema = vec![
+      ColumnSchema::new("id", &CastDataType::Integer),
+      ColumnSchema::new("name", &CastDataType::String),
+    ];
+    let rename = SchemaMigration { op: "rename".to_string(), key: "name".to_string(), to: Some("full_name".to_string()), data_type: None };
+    let renamed = rename.apply_to_schema(&schema).unwrap();
+    assert_eq!(renamed[1].key, "full_name");
+    let clash = SchemaMigration { op: "rename".to_string(), key: "name".to_string(), to: Some("id".to_string()), data_type: None };
+    assert!(clash.apply_to_schema(&schema).is_err());
+    let retype = SchemaMigration { op: "retype".to_string(), key: "id".to_string(), to: None, data_type: Some("float".to_string()) };
+    assert_eq!(retype.apply_to_schema(&schema).unwrap()[0].data_type, "float");
+    assert_eq!(retype.convert_value(&Bson::String(" 12.5".to_string())), Bson::Double(12.5));
+    assert_eq!(retype.convert_value(&Bson::String("n/a".to_string())), Bson::String("n/a".to_string()));
+    let drop = SchemaMigration { op: "drop".to_string(), key: "missing".to_string(), to: None, data_type: None };
+    assert!(drop.apply_to_schema(&schema).is_err());
+    let mut row_data = bson::doc! { "id": 1, "name": "Alpha" };
+    assert!(rename.apply_to_row(&mut row_data));
+    assert_eq!(row_data, bson::doc! { "id": 1, "full_name": "Alpha" });
+    assert!(!drop.apply_to_row(&mut row_data));
+    let nested = SchemaMigration { op: "rename".to_string(), key: "address.city".to_string(), to: Some("town".to_string()), data_type: None };
+    let mut row_data = bson::doc! { "id": 1, "address": { "city": "Leeds" } };
+    assert!(nested.apply_to_row(&mut row_data));
+    assert_eq!(row_data, bson::doc! { "id": 1, "address": {}, "town": "Leeds" });
+    let stored = serde_json::to_value(&schema).unwrap();
+    assert_eq!(rename.apply_to_import(&stored).unwrap()[1]["key"], "full_name");
+    assert!(drop.apply_to_import(&stored).is_none());
+  }
+}
@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use bson::Document;
+use serde_json::Value;
+use serde_with::chrono::{DateTime, Utc};
+use tokio::sync::OnceCell;
+
+use crate::db::{RowSet, DB};
+use crate::imports::ImportRecord;
+use crate::indexes::ColumnIndex;
+use crate::memory::MemoryStorage;
+use crate::postgres::PostgresStorage;
+use crate::schema::{ColumnSchema, SchemaMigration};
+use crate::workbooks::WorkbookRecord;
+
+static STORAGE_INSTANCE: OnceCell<Box<dyn Storage>> = OnceCell::const_new();
+
+/// Create the storage backend selected via STORAGE_BACKEND: mongo (default), postgres or memory.
+/// Called on startup, so a misconfigured backend stops the server before it accepts requests.
+pub async fn load_storage_instance() -> Result<&'static dyn Storage, String> {
+  STORAGE_INSTANCE.get_or_try_init(init_storage).await.map(|storage| storage.as_ref())
+}
+
+/// Storage backend loaded on startup
+pub async fn get_storage_instance() -> &'static dyn Storage {
+  load_storage_instance().await.expect("The storage backend is loaded on startup")
+}
+
+async fn init_storage() -> Result<Box<dyn Storage>, String> {
+  let backend = dotenv::var("STORAGE_BACKEND").unwrap_or_default().to_lowercase();
+  Ok(match backend.as_str() {
+    "memory" | "mem" => Box::new(MemoryStorage::new()),
+    "postgres" | "postgresql" | "pg" => Box::new(PostgresStorage::new()?),
+    _ => Box::new(DB::new().await),
+  })
+}
+
+/// Dataset metadata, import records and row queries.
+/// Filter, search and sort criteria use the MongoDB-style documents built by `QueryFilterParams`,
+/// which other backends translate to their own query model.
+#[async_trait]
+pub trait Storage: Send + Sync {
+  /// Prepare indexes or tables the backend relies on
+  async fn init(&self) -> bool;
+
+  async fn fetch_dataset(&self, dataset_id: &str, import_id_opt: Option<String>, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> Option<RowSet>;
+
+  async fn get_datasets(&self, filter_options: Option<Document>, limit: u64, skip: u64, sort_criteria: Option<Document>) -> (Option<u64>, Vec<Value>);
+
+  async fn save_import_with_rows(&self, options: &Value, rows: &[Value], schema: &[ColumnSchema], import_id_opt: Option<String>, append: bool) -> Option<(String, String, usize)>;
+
+  async fn delete_dataset(&self, dataset_id: &str) -> Option<u64>;
+
+  /// Store the outcome of the latest scheduled refresh without changing the dataset's rows
+  async fn record_refresh(&self, dataset_id: &str, refresh: &Value) -> bool;
+
+  /// Imports of a dataset, flagging those whose rows are kept as restorable versions
+  async fn list_imports(&self, dataset_id: &str) -> Option<Vec<ImportRecord>>;
+
+  /// Roll the dataset back to the rows of an earlier import. None if the dataset or import does not exist,
+  /// false if that version is no longer kept.
+  async fn restore_import(&self, dataset_id: &str, import_id: &str) -> Option<bool>;
+
+  /// Rows of a dataset as they were after an import, if that version is kept
+  async fn fetch_import_rows(&self, dataset_id: &str, import_id: &str) -> Option<Vec<Value>>;
+
+  async fn fetch_dataset_schema(&self, dataset_id: &str) -> Option<Vec<ColumnSchema>>;
+
+  async fn migrate_dataset(&self, dataset_id: &str, migration: &SchemaMigration, schema: &[ColumnSchema]) -> Option<u64>;
+
+  async fn fetch_dataset_modified(&self, dataset_id: &str) -> Option<DateTime<Utc>>;
+
+  async fn datasets_modified(&self, filter_options: Option<Document>) -> (Option<DateTime<Utc>>, u64);
+
+  async fn declare_indexes(&self, dataset_id: &str, fields: &[String]) -> Vec<String>;
+
+  async fn list_dataset_indexes(&self, dataset_id: &str) -> Option<Vec<ColumnIndex>>;
+
+  async fn drop_dataset_index(&self, dataset_id: &str, field: &str) -> Option<bool>;
+
+  /// Insert or replace the record of a multi-sheet import
+  async fn save_workbook(&self, workbook: &WorkbookRecord) -> bool;
+
+  async fn fetch_workbook(&self, workbook_id: &str) -> Option<WorkbookRecord>;
+
+  /// Workbook records, most recently updated first
+  async fn get_workbooks(&self, limit: u64, skip: u64) -> Vec<WorkbookRecord>;
+
+  /// Remove a workbook record. Its datasets are deleted separately.
+  async fn delete_workbook(&self, workbook_id: &str) -> bool;
+}
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_with::chrono::Utc;
+use spreadsheet_to_json::{process_spreadsheet_immediate, simple_string_patterns::ToSegments, OptionSet};
+use std::path::Path;
+
+use crate::schema::extract_keys;
+
+const INSPECT_SAMPLE_ROWS: u32 = 10;
+
+/// Sheet of an uploaded workbook with its size and the column keys and header labels detected in the first row
+#[derive(Debug, Clone, Serialize)]
+pub struct SheetSummary {
+  pub index: usize,
+  pub name: String,
+  pub rows: usize,
+  pub columns: usize,
+  pub keys: Vec<String>,
+  pub headers: Vec<String>,
+}
+
+impl SheetSummary {
+  fn from_result(index: usize, name: &str, result: &Value, rows: &[Value]) -> Self {
+    let keys = extract_keys(result, rows);
+    let headers = match result["headers"].as_array() {
+      Some(items) if !items.is_empty() => items.iter().map(|item| item.as_str().unwrap_or_default().to_string()).collect(),
+      _ => keys.clone(),
+    };
+    SheetSummary {
+      index,
+      name: name.to_string(),
+      rows: result["num_rows"].as_u64().map(|n| n as usize).unwrap_or(rows.len()),
+      columns: keys.len(),
+      keys,
+      headers,
+    }
+  }
+}
+
+/// Sheet of an imported workbook and the dataset it was imported into
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkbookSheet {
+  pub index: usize,
+  pub name: String,
+  pub dataset_id: Option<String>,
+}
+
+/// Shared record of a multi-sheet import, linking each sheet of the workbook to its dataset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkbookRecord {
+  #[serde(rename = "_id")]
+  pub id: String,
+  pub filename: String,
+  pub sheets: Vec<WorkbookSheet>,
+  pub created_at: String,
+  pub updated_at: String,
+}
+
+impl WorkbookRecord {
+  /// Record after importing sheets of a workbook, given as sheet index and dataset ID.
+  /// Sheets not imported this time keep the dataset of a previous import with the same name.
+  pub fn update(previous: Option<&WorkbookRecord>, id: &str, filename: &str, names: &[String], imported: &[(usize, String)]) -> Self {
+    let now = Utc::now().to_rfc3339();
+    let sheets = names
+      .iter()
+      .enumerate()
+      .map(|(index, name)| {
+        let dataset_id = imported.iter().find(|(i, _)| *i == index).map(|(_, dataset_id)| dataset_id.to_owned()).or_else(|| {
+          previous
+            .and_then(|record| record.sheets.iter().find(|sheet| sheet.name.eq_ignore_ascii_case(name)))
+            .and_then(|sheet| sheet.dataset_id.clone())
+        });
+        WorkbookSheet { index, name: name.to_owned(), dataset_id }
+      })
+      .collect();
+    WorkbookRecord {
+      id: id.to_string(),
+      filename: filename.to_string(),
+      sheets,
+      created_at: previous.map(|record| record.created_at.clone()).unwrap_or(now.clone()),
+      updated_at: now,
+    }
+  }
+
+  pub fn dataset_ids(&self) -> Vec<String> {
+    self.sheets.iter().filter_map(|sheet| sheet.dataset_id.clone()).collect()
+  }
+
+  pub fn from_json(value: &Value) -> Option<Self> {
+    serde_json::from_value(value.to_owned()).ok()
+  }
+}
+
+/// Rectangular cell region such as `B4:H200`, with 0-based inclusive bounds.
+/// The first row of the range holds the headers unless `header_index` points further down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellRange {
+  pub start_col: usize,
+  pub start_row: usize,
+  pub end_col: usize,
+  pub end_row: usize,
+}
+
+fn parse_cell_ref(cell: &str) -> Option<(usize, usize)> {
+  let cell = cell.trim().to_uppercase();
+  let split = cell.find(|c: char| c.is_ascii_digit())?;
+  let (letters, digits) = cell.split_at(split);
+  if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+    return None;
+  }
+  let col = letters.chars().fold(0usize, |acc, c| acc * 26 + (c as usize - 'A' as usize + 1));
+  let row = digits.parse::<usize>().ok().filter(|r| *r > 0)?;
+  Some((col - 1, row - 1))
+}
+
+impl CellRange {
+  pub fn parse(spec: &str) -> Result<Self, String> {
+    let error = || format!("Invalid range {}, expected e.g. B4:H200", spec);
+    let (start, end) = spec.split_once(':').ok_or_else(error)?;
+    let (start_col, start_row) = parse_cell_ref(start).ok_or_else(error)?;
+    let (end_col, end_row) = parse_cell_ref(end).ok_or_else(error)?;
+    if end_col < start_col || end_row < start_row {
+      return Err(error());
+    }
+    Ok(CellRange { start_col, start_row, end_col, end_row })
+  }
+
+  /// Sheet row of the headers for a header index relative to the top of the range
+  pub fn header_row(&self, header_index: usize) -> usize {
+    self.start_row + header_index
+  }
+
+  /// Number of data rows below the header row within the range
+  pub fn data_rows(&self, header_index: usize) -> usize {
+    self.end_row.saturating_sub(self.header_row(header_index))
+  }
+
+  /// Keep only the columns inside the range, counted from the first column read, in rows and result metadata
+  pub fn select_columns(&self, result: &mut Value, rows: &mut [Value]) {
+    let keys = extract_keys(result, rows);
+    let selected = keys.iter().enumerate().filter(|(index, _)| *index >= self.start_col && *index <= self.end_col).map(|(_, key)| key.to_owned()).collect::<Vec<String>>();
+    for field in ["keys", "fields", "headers"] {
+      if let Some(items) = result.get_mut(field).and_then(|v| v.as_array_mut()) {
+        let kept = items.iter().enumerate().filter(|(index, _)| *index >= self.start_col && *index <= self.end_col).map(|(_, item)| item.clone()).collect::<Vec<Value>>();
+        *items = kept;
+      }
+    }
+    for row in rows.iter_mut() {
+      if let Some(obj) = row.as_object_mut() {
+        obj.retain(|key, _| selected.contains(key));
+      }
+    }
+  }
+}
+
+/// Names of the sheets in a workbook, read from a one-row preview of the first sheet.
+/// CSV and TSV files have a single sheet named after the file.
+pub async fn read_sheet_names(file_path: &Path) -> Option<Vec<String>> {
+  let opts = OptionSet::new(&file_path.to_string_lossy())
+    .set_read_mode("preview")
+    .max_row_count(1)
+    .sheet_index(0);
+  let result = process_spreadsheet_immediate(&opts).await.ok()?;
+  let names = sheet_names_from_result(&result.to_json());
+  if !names.is_empty() {
+    return Some(names);
+  }
+  let stem = file_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+  Some(vec![stem])
+}
+
+fn sheet_names_from_result(result: &Value) -> Vec<String> {
+  result["sheets"]
+    .as_array()
+    .map(|items| items.iter().filter_map(|item| item.as_str().map(|s| s.to_string())).collect())
+    .unwrap_or_default()
+}
+
+/// Summaries of every sheet in a workbook from a short preview of each
+pub async fn inspect_workbook(file_path: &Path) -> Option<Vec<SheetSummary>> {
+  let names = read_sheet_names(file_path).await?;
+  let mut sheets: Vec<SheetSummary> = vec![];
+  for (index, name) in names.iter().enumerate() {
+    let opts = OptionSet::new(&file_path.to_string_lossy())
+      .set_read_mode("preview")
+      .max_row_count(INSPECT_SAMPLE_ROWS)
+      .sheet_index(index as u32);
+    if let Ok(result) = process_spreadsheet_immediate(&opts).await {
+      let rows = result.to_vec().into_iter().map(|r| json!(r)).collect::<Vec<Value>>();
+      sheets.push(SheetSummary::from_result(index, name, &result.to_json(), &rows));
+    }
+  }
+  Some(sheets)
+}
+
+/// Index of a sheet by name, ignoring case and surrounding whitespace
+pub fn find_sheet(names: &[String], name: &str) -> Option<usize> {
+  names.iter().position(|item| item.trim().eq_ignore_ascii_case(name.trim()))
+}
+
+/// Resolve `all` or a comma separated list of sheet indices and names (case-insensitive) to sheet indices
+pub fn select_sheets(spec: &str, names: &[String]) -> Result<Vec<usize>, String> {
+  if spec.trim().eq_ignore_ascii_case("all") || spec.trim() == "*" {
+    return Ok((0..names.len()).collect());
+  }
+  let mut indices: Vec<usize> = vec![];
+  for part in spec.to_segments(",") {
+    let index = match part.parse::<usize>() {
+      Ok(index) if index < names.len() => index,
+      Ok(index) => return Err(format!("The workbook has no sheet {}", index)),
+      Err(_) => find_sheet(names, &part).ok_or(format!("The workbook has no sheet named {}", part))?,
+    };
+    if !indices.contains(&index) {
+      indices.push(index);
+    }
+  }
+  if indices.is_empty() {
+    return Err("No sheets selected".to_string());
+  }
+  Ok(indices)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_select_sheets() {
+    let names = vec!["Summary".to_string(), "Q1".to_string(), "Q2".to_string()];
+    assert_eq!(select_sheets("all", &names), Ok(vec![0, 1, 2]));
+    assert_eq!(select_sheets("2, summary,0", &names), Ok(vec![2, 0]));
+    assert!(select_sheets("3", &names).is_err());
+    assert!(select_sheets("Q3", &names).is_err());
+    assert_eq!(sheet_names_from_result(&json!({ "sheets": ["Summary", "Q1"] })), vec!["Summary", "Q1"]);
+    assert_eq!(find_sheet(&names, " q2 "), Some(2));
+  }
+
+  #[test]
+  fn test_workbook_record_update() {
+    let names = vec!["Summary".to_string(), "Q1".to_string()];
+    let first = WorkbookRecord::update(None, "wb1", "report.xlsx", &names, &[(0, "d1".to_string()), (1, "d2".to_string())]);
+    assert_eq!(first.dataset_ids(), vec!["d1", "d2"]);
+    // Q1 moved to the front and only Summary was re-imported
+    let names = vec!["Q1".to_string(), "Q2".to_string(), "Summary".to_string()];
+    let second = WorkbookRecord::update(Some(&first), "wb1", "report.xlsx", &names, &[(2, "d1".to_string())]);
+    assert_eq!(second.sheets[0], WorkbookSheet { index: 0, name: "Q1".to_string(), dataset_id: Some("d2".to_string()) });
+    assert_eq!(second.sheets[1].dataset_id, None);
+    assert_eq!(second.created_at, first.created_at);
+    let stored = json!(second);
+    assert_eq!(stored["_id"], json!("wb1"));
+    assert_eq!(WorkbookRecord::from_json(&stored).map(|record| record.dataset_ids()), Some(vec!["d2".to_string(), "d1".to_string()]));
+  }
+
+  #[test]
+  fn test_cell_range() {
+    let range = CellRange::parse("b4:H200").unwrap();
+    assert_eq!(range, CellRange { start_col: 1, start_row: 3, end_col: 7, end_row: 199 });
+    assert_eq!((range.header_row(0), range.data_rows(0)), (3, 196));
+    assert_eq!(range.header_row(1), 4);
+    assert_eq!(CellRange::parse("AA1:AB2").unwrap().start_col, 26);
+    assert!(CellRange::parse("H4:B200").is_err());
+    assert!(CellRange::parse("B0:H2").is_err());
+    assert!(CellRange::parse("B4").is_err());
+    let mut result = json!({ "fields": ["note", "sku", "qty", "comment"] });
+    let mut rows = vec![json!({ "note": null, "sku": "A1", "qty": 2, "comment": "x" })];
+    CellRange::parse("B1:C10").unwrap().select_columns(&mut result, &mut rows);
+    assert_eq!(result["fields"], json!(["sku", "qty"]));
+    assert_eq!(rows[0], json!({ "sku": "A1", "qty": 2 }));
+  }
+
+  #[test]
+  fn test_sheet_summary() {
+    let result = json!({ "fields": ["sku", "unit_price"], "headers": ["SKU", "Unit Price"], "num_rows": 120 });
+    let rows = vec![json!({ "sku": "A1", "unit_price": 2.5 })];
+    let summary = SheetSummary::from_result(1, "Prices", &result, &rows);
+    assert_eq!(summary.rows, 120);
+    assert_eq!(summary.columns, 2);
+    assert_eq!(summary.headers, vec!["SKU", "Unit Price"]);
+    // without parser metadata keys come from the sample rows
+    let summary = SheetSummary::from_result(0, "Sheet1", &json!({}), &rows);
+    assert_eq!((summary.rows, summary.keys.clone()), (1, vec!["sku".to_string(), "unit_price".to_string()]));
+    assert_eq!(summary.headers, summary.keys);
+  }
+}